@@ -0,0 +1,60 @@
+// Compares the key derivation done at login by the legacy path and the current one, both
+// through the functions the client ships
+// Run with: cargo bench --bench login_kdf
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rust_password_manager_client::encryption::{
+    generate_all_master_ciphers, generate_all_vault_ciphers, generate_kdf_params,
+    generate_master_keys, generate_vault_key,
+};
+use rust_password_manager_client::model::KdfParams;
+
+const ITERATIONS: u32 = 5;
+
+const EMAIL: &str = "bench@example.com";
+const PASSWORD: &str = "correct horse battery staple";
+
+// Without key derivation settings the crate takes the legacy path: separate Argon2 runs for
+// the keys and one more per content type
+fn login_legacy() {
+    black_box(generate_master_keys(EMAIL, PASSWORD, None).unwrap());
+    black_box(generate_all_master_ciphers(EMAIL, PASSWORD).unwrap());
+}
+
+// A single Argon2 run, everything else is expanded with HKDF
+fn login_current(kdf_params: &KdfParams, vault_key: &[u8]) {
+    black_box(generate_master_keys(EMAIL, PASSWORD, Some(kdf_params)).unwrap());
+    black_box(generate_all_vault_ciphers(vault_key).unwrap());
+}
+
+fn measure(name: &str, f: impl Fn()) -> Duration {
+    // Warm up the allocator before timing
+    f();
+
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        f();
+    }
+
+    let average = start.elapsed() / ITERATIONS;
+
+    println!("{}: {:?} per login", name, average);
+
+    average
+}
+
+fn main() {
+    let kdf_params = generate_kdf_params();
+    let vault_key = generate_vault_key();
+
+    let legacy = measure("legacy", login_legacy);
+    let current = measure("current", || login_current(&kdf_params, &vault_key[..]));
+
+    println!(
+        "speedup: {:.2}x",
+        legacy.as_secs_f64() / current.as_secs_f64()
+    );
+}
@@ -0,0 +1,349 @@
+use std::fs;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::{env, fmt::Write as _};
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::model::{EncryptedDataEntry, EncryptedDataEntryChanges, KdfParams};
+
+const CACHE_DIRECTORY: &str = "password-manager-client";
+const VAULT_CACHE_SUFFIX: &str = ".json";
+
+// The entries as last seen on the server, still encrypted, along with what is needed to derive
+// the master keys without asking the server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VaultCache {
+    pub email: String,
+    pub kdf_params: Option<KdfParams>,
+    pub entries: Vec<EncryptedDataEntry>,
+    pub synced_at: DateTime<Utc>,
+    // Where the next delta sync starts from these entries
+    #[serde(default)]
+    pub cursor: Option<u64>,
+}
+
+impl VaultCache {
+    pub fn new(
+        email: &str,
+        kdf_params: Option<KdfParams>,
+        entries: Vec<EncryptedDataEntry>,
+        cursor: Option<u64>,
+    ) -> VaultCache {
+        VaultCache {
+            email: email.to_string(),
+            kdf_params,
+            entries,
+            synced_at: Utc::now(),
+            cursor,
+        }
+    }
+
+    pub fn load(server_url: &str, email: &str) -> Result<VaultCache, Error> {
+        let vault_cache =
+            match read_cache_file::<VaultCache>(server_url, email, VAULT_CACHE_SUFFIX)? {
+                Some(vault_cache) => vault_cache,
+                None => return Err(Error::Storage("There is no offline copy".to_string())),
+            };
+
+        // A hash collision or a copied file should not open someone else's vault
+        if vault_cache.email != email {
+            return Err(Error::Storage(
+                "The offline copy belongs to another account".to_string(),
+            ));
+        }
+
+        Ok(vault_cache)
+    }
+
+    pub fn save(&self, server_url: &str) -> Result<(), Error> {
+        write_cache_file(server_url, &self.email, VAULT_CACHE_SUFFIX, self)
+    }
+
+    pub fn store_entry(&mut self, encrypted_entry: &EncryptedDataEntry) {
+        store_entry(&mut self.entries, encrypted_entry);
+    }
+
+    pub fn remove_entry(&mut self, key: &str) {
+        remove_entry(&mut self.entries, key);
+    }
+}
+
+// Replaces the entry with the same key, or adds it
+pub fn store_entry(entries: &mut Vec<EncryptedDataEntry>, encrypted_entry: &EncryptedDataEntry) {
+    remove_entry(entries, &encrypted_entry.key());
+    entries.push(encrypted_entry.clone());
+}
+
+pub fn remove_entry(entries: &mut Vec<EncryptedDataEntry>, key: &str) {
+    entries.retain(|entry| entry.key() != key);
+}
+
+// Brings entries synced up to some cursor up to the cursor of the changes
+pub fn apply_entry_changes(
+    entries: &mut Vec<EncryptedDataEntry>,
+    changes: &EncryptedDataEntryChanges,
+) {
+    for deleted_entry in changes.deleted.iter() {
+        remove_entry(entries, &deleted_entry.key());
+    }
+
+    for changed_entry in changes.changed.iter() {
+        store_entry(entries, changed_entry);
+    }
+}
+
+// Applies a change already accepted by the server, the offline copy is only a convenience
+// so failing to update it is not an error
+pub fn update_vault_cache(server_url: &str, email: &str, update: impl FnOnce(&mut VaultCache)) {
+    let mut vault_cache = match VaultCache::load(server_url, email) {
+        Ok(vault_cache) => vault_cache,
+        Err(e) => {
+            println!("Skipping offline copy update: {}", e);
+            return;
+        }
+    };
+
+    update(&mut vault_cache);
+    vault_cache.synced_at = Utc::now();
+
+    if let Err(e) = vault_cache.save(server_url) {
+        println!("Failed to update offline copy: {}", e);
+    }
+}
+
+// $XDG_CACHE_HOME, falling back to ~/.cache as the specification asks
+fn cache_directory() -> PathBuf {
+    xdg_directory("XDG_CACHE_HOME", &[".cache"])
+}
+
+// $XDG_STATE_HOME, falling back to ~/.local/state. Unlike the cache it is not meant to be wiped,
+// so it keeps what exists nowhere else, like the changes the server has not seen yet
+fn state_directory() -> PathBuf {
+    xdg_directory("XDG_STATE_HOME", &[".local", "state"])
+}
+
+fn xdg_directory(variable: &str, fallback: &[&str]) -> PathBuf {
+    let base = match env::var_os(variable) {
+        Some(directory) if !directory.is_empty() => PathBuf::from(directory),
+        _ => match env::var_os("HOME") {
+            Some(home) => fallback
+                .iter()
+                .fold(PathBuf::from(home), |path, component| path.join(component)),
+            None => PathBuf::from("."),
+        },
+    };
+
+    base.join(CACHE_DIRECTORY)
+}
+
+// Returns None when nothing has been saved yet for the account
+pub fn read_cache_file<T: DeserializeOwned>(
+    server_url: &str,
+    email: &str,
+    suffix: &str,
+) -> Result<Option<T>, Error> {
+    read_json_file(&cache_file_path(server_url, email, suffix))
+}
+
+// Files written before they moved out of the cache are still read until the next write
+pub fn read_state_file<T: DeserializeOwned>(
+    server_url: &str,
+    email: &str,
+    suffix: &str,
+) -> Result<Option<T>, Error> {
+    match read_json_file(&state_file_path(server_url, email, suffix))? {
+        Some(value) => Ok(Some(value)),
+        None => read_cache_file(server_url, email, suffix),
+    }
+}
+
+fn read_json_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(Error::Storage(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    match serde_json::from_str::<T>(&json) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => Err(Error::Storage(format!(
+            "Failed to parse {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+// Only readable by the user, even though every entry written here is encrypted
+pub fn write_cache_file<T: Serialize>(
+    server_url: &str,
+    email: &str,
+    suffix: &str,
+    value: &T,
+) -> Result<(), Error> {
+    write_json_file(&cache_file_path(server_url, email, suffix), value)
+}
+
+// Also removes the copy written to the cache before the file moved
+pub fn write_state_file<T: Serialize>(
+    server_url: &str,
+    email: &str,
+    suffix: &str,
+    value: &T,
+) -> Result<(), Error> {
+    write_json_file(&state_file_path(server_url, email, suffix), value)?;
+
+    let old_path = cache_file_path(server_url, email, suffix);
+    match fs::remove_file(&old_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => println!("Failed to remove {}: {}", old_path.display(), e),
+    }
+
+    Ok(())
+}
+
+fn write_json_file<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            return Err(Error::Storage(format!(
+                "Failed to create {}: {}",
+                parent.display(),
+                e
+            )));
+        }
+    }
+
+    let json = match serde_json::to_string(value) {
+        Ok(json) => json,
+        Err(e) => {
+            return Err(Error::Storage(format!(
+                "Failed to encode {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    // Written next to the file and renamed over it, so a crash never leaves half a file behind
+    let mut temporary_path = path.as_os_str().to_os_string();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    // A leftover from an earlier crash may have other permissions
+    match fs::remove_file(&temporary_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(Error::Storage(format!(
+                "Failed to remove {}: {}",
+                temporary_path.display(),
+                e
+            )))
+        }
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    // Elsewhere the file gets the default permissions of the user's profile
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let result = options
+        .open(&temporary_path)
+        .and_then(|mut file| {
+            file.write_all(json.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temporary_path, path));
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temporary_path);
+
+        return Err(Error::Storage(format!(
+            "Failed to write {}: {}",
+            path.display(),
+            e
+        )));
+    }
+
+    // Makes the rename itself survive a crash
+    if let Some(parent) = path.parent() {
+        if let Err(e) = fs::File::open(parent).and_then(|directory| directory.sync_all()) {
+            println!("Failed to sync {}: {}", parent.display(), e);
+        }
+    }
+
+    Ok(())
+}
+
+fn cache_file_path(server_url: &str, email: &str, suffix: &str) -> PathBuf {
+    cache_directory().join(account_file_name(server_url, email, suffix))
+}
+
+fn state_file_path(server_url: &str, email: &str, suffix: &str) -> PathBuf {
+    state_directory().join(account_file_name(server_url, email, suffix))
+}
+
+// One file per server, account and suffix, named by a hash so the email does not show
+fn account_file_name(server_url: &str, email: &str, suffix: &str) -> String {
+    let digest = Sha256::digest(format!("{}\n{}", server_url, email).as_bytes());
+
+    let mut file_name = String::new();
+    for byte in digest.iter() {
+        write!(file_name, "{:02x}", byte).unwrap();
+    }
+    file_name.push_str(suffix);
+
+    file_name
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn written_files_replace_the_old_one_and_stay_private() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("cache").join("vault.json");
+
+        write_json_file(&path, &vec!["first".to_string()]).unwrap();
+
+        // A leftover from a crash must not keep its permissions
+        fs::write(directory.path().join("cache").join("vault.json.tmp"), "").unwrap();
+
+        write_json_file(&path, &vec!["second".to_string()]).unwrap();
+
+        let stored: Vec<String> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(stored, vec!["second".to_string()]);
+
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let file_names: Vec<_> = fs::read_dir(directory.path().join("cache"))
+            .unwrap()
+            .map(|file| file.unwrap().file_name())
+            .collect();
+        assert_eq!(file_names, vec![std::ffi::OsString::from("vault.json")]);
+    }
+}
@@ -0,0 +1,612 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::tls::{is_local_url, CertificatePin};
+
+const CONFIG_DIRECTORY: &str = "password-manager-client";
+const CONFIG_FILE: &str = "config.json";
+const DEFAULT_PROFILE: &str = "Default";
+
+// Stored as JSON in the XDG config directory, missing fields fall back to the defaults
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub profiles: Vec<Profile>,
+    pub active_profile: String,
+    pub connect_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    // Zero disables the timer
+    pub auto_lock_minutes: u64,
+    pub clipboard_clear_secs: u64,
+    pub generator: GeneratorConfig,
+    // Refuses http:// servers other than this machine
+    pub require_https: bool,
+
+    // Files written before profiles existed only have the one server
+    #[serde(skip_serializing)]
+    server_url: Option<String>,
+}
+
+// A server and the account last used on it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub server_url: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    // PEM certificates trusted on top of the system ones, for servers behind an internal CA
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+    // Trusted instead of any certificate authority, see CertificatePin for the format
+    #[serde(default)]
+    pub certificate_pin: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeneratorConfig {
+    pub length: usize,
+    pub use_symbols: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            profiles: vec![Profile::default()],
+            active_profile: DEFAULT_PROFILE.to_string(),
+            connect_timeout_secs: 10,
+            request_timeout_secs: 30,
+            auto_lock_minutes: 15,
+            clipboard_clear_secs: 30,
+            generator: GeneratorConfig::default(),
+            require_https: false,
+            server_url: None,
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            server_url: "http://localhost:8080".to_string(),
+            email: None,
+            ca_bundle: None,
+            certificate_pin: None,
+        }
+    }
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            length: 16,
+            use_symbols: true,
+        }
+    }
+}
+
+impl Config {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    pub fn profile_mut(&mut self, name: &str) -> Option<&mut Profile> {
+        self.profiles
+            .iter_mut()
+            .find(|profile| profile.name == name)
+    }
+
+    // validate makes sure the active profile exists
+    pub fn active_profile(&self) -> &Profile {
+        match self.profile(&self.active_profile) {
+            Some(profile) => profile,
+            None => &self.profiles[0],
+        }
+    }
+
+    // Brings files from older versions and hand edited files back to a usable state
+    fn normalize(&mut self) {
+        if let Some(server_url) = self.server_url.take() {
+            if let Some(profile) = self.profile_mut(DEFAULT_PROFILE) {
+                profile.server_url = server_url;
+            }
+        }
+
+        if self.profiles.is_empty() {
+            self.profiles.push(Profile::default());
+        }
+
+        if self.profile(&self.active_profile).is_none() {
+            self.active_profile = self.profiles[0].name.clone();
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        for (index, profile) in self.profiles.iter().enumerate() {
+            profile.validate(self.require_https)?;
+
+            if self.profiles[..index]
+                .iter()
+                .any(|other_profile| other_profile.name == profile.name)
+            {
+                return Err(Error::Validation(format!(
+                    "There is already a profile named {}",
+                    profile.name
+                )));
+            }
+        }
+
+        if self.profile(&self.active_profile).is_none() {
+            return Err(Error::Validation(format!(
+                "There is no profile named {}",
+                self.active_profile
+            )));
+        }
+
+        if self.connect_timeout_secs == 0 || self.request_timeout_secs == 0 {
+            return Err(Error::Validation(
+                "Timeouts must be at least one second".to_string(),
+            ));
+        }
+
+        if !(8..=128).contains(&self.generator.length) {
+            return Err(Error::Validation(
+                "Generated passwords must be between 8 and 128 characters".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Profile {
+    pub fn validate(&self, require_https: bool) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error::Validation("Profile name is empty".to_string()));
+        }
+
+        let server_url = match reqwest::Url::parse(&self.server_url) {
+            Ok(server_url) => server_url,
+            Err(e) => return Err(Error::Validation(format!("Invalid server URL: {}", e))),
+        };
+
+        if server_url.scheme() != "http" && server_url.scheme() != "https" {
+            return Err(Error::Validation(
+                "Server URL must start with http:// or https://".to_string(),
+            ));
+        }
+
+        if require_https && server_url.scheme() == "http" && !is_local_url(&server_url) {
+            return Err(Error::Validation(format!(
+                "Profile {} uses plain http:// for a remote server, which is not allowed",
+                self.name
+            )));
+        }
+
+        if let Some(certificate_pin) = &self.certificate_pin {
+            CertificatePin::parse(certificate_pin)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Values given on the command line or in the environment win over the file for this run only
+#[derive(Clone, Debug, Default)]
+pub struct ConfigOverrides {
+    pub config_path: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub server_url: Option<String>,
+    pub connect_timeout_secs: Option<u64>,
+    pub request_timeout_secs: Option<u64>,
+    pub auto_lock_minutes: Option<u64>,
+}
+
+pub const USAGE: &str = "Usage: rust-password-manager-client [OPTIONS]
+
+Options:
+  --config <PATH>            Read and write the configuration at PATH
+  --profile <NAME>           Start with the profile NAME selected
+  --server-url <URL>         Connect to URL instead of the server of the profile
+  --connect-timeout <SECS>   Connection timeout in seconds
+  --request-timeout <SECS>   Request timeout in seconds
+  --auto-lock <MINUTES>      Lock after MINUTES of inactivity, 0 to disable
+  -h, --help                 Print this help
+
+Environment variables PASSWORD_MANAGER_CONFIG, PASSWORD_MANAGER_PROFILE,
+PASSWORD_MANAGER_SERVER_URL, PASSWORD_MANAGER_CONNECT_TIMEOUT,
+PASSWORD_MANAGER_REQUEST_TIMEOUT and PASSWORD_MANAGER_AUTO_LOCK set the same
+options, the flags take precedence.";
+
+impl ConfigOverrides {
+    // Returns None when only the help was asked for
+    pub fn from_env_and_args(args: &[String]) -> Result<Option<ConfigOverrides>, Error> {
+        let mut overrides = ConfigOverrides {
+            config_path: env::var_os("PASSWORD_MANAGER_CONFIG").map(PathBuf::from),
+            profile: env::var("PASSWORD_MANAGER_PROFILE").ok(),
+            server_url: env::var("PASSWORD_MANAGER_SERVER_URL").ok(),
+            connect_timeout_secs: parse_env_number("PASSWORD_MANAGER_CONNECT_TIMEOUT")?,
+            request_timeout_secs: parse_env_number("PASSWORD_MANAGER_REQUEST_TIMEOUT")?,
+            auto_lock_minutes: parse_env_number("PASSWORD_MANAGER_AUTO_LOCK")?,
+        };
+
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(Error::Validation(format!("Missing value for {}", arg))),
+            };
+
+            match arg.as_str() {
+                "--config" => overrides.config_path = Some(PathBuf::from(value)),
+                "--profile" => overrides.profile = Some(value.clone()),
+                "--server-url" => overrides.server_url = Some(value.clone()),
+                "--connect-timeout" => {
+                    overrides.connect_timeout_secs = Some(parse_number(arg, value)?)
+                }
+                "--request-timeout" => {
+                    overrides.request_timeout_secs = Some(parse_number(arg, value)?)
+                }
+                "--auto-lock" => overrides.auto_lock_minutes = Some(parse_number(arg, value)?),
+                _ => return Err(Error::Validation(format!("Unknown option {}", arg))),
+            }
+        }
+
+        Ok(Some(overrides))
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        // Only the profile the app started with connects elsewhere
+        if let (Some(server_url), Some(profile_name)) = (&self.server_url, &self.profile) {
+            if let Some(profile) = config.profile_mut(profile_name) {
+                profile.server_url = server_url.clone();
+            }
+        }
+
+        if let Some(connect_timeout_secs) = self.connect_timeout_secs {
+            config.connect_timeout_secs = connect_timeout_secs;
+        }
+
+        if let Some(request_timeout_secs) = self.request_timeout_secs {
+            config.request_timeout_secs = request_timeout_secs;
+        }
+
+        if let Some(auto_lock_minutes) = self.auto_lock_minutes {
+            config.auto_lock_minutes = auto_lock_minutes;
+        }
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u64, Error> {
+    match value.parse::<u64>() {
+        Ok(number) => Ok(number),
+        Err(_) => Err(Error::Validation(format!(
+            "Invalid value for {}: {}",
+            name, value
+        ))),
+    }
+}
+
+fn parse_env_number(name: &str) -> Result<Option<u64>, Error> {
+    match env::var(name) {
+        Ok(value) => parse_number(name, &value).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+// The file as saved by the preferences dialog, and what this run actually uses on top of it
+pub struct Settings {
+    pub path: PathBuf,
+    pub saved: Config,
+    pub overrides: ConfigOverrides,
+    // The profile in use, only written to the file when the user switches to it
+    pub active_profile: String,
+    // Set when the file could not be read nor backed up, so that saving does not destroy it
+    load_error: Option<String>,
+}
+
+impl Settings {
+    pub fn load(mut overrides: ConfigOverrides) -> Settings {
+        let path = match &overrides.config_path {
+            Some(config_path) => config_path.clone(),
+            None => default_config_path(),
+        };
+
+        let mut load_error = None;
+
+        // A broken file should not keep the user out of their vault, it is kept aside before
+        // the defaults can be saved over it
+        let mut saved = match read_config(&path) {
+            Ok(saved) => saved,
+            Err(e) => {
+                println!("Failed to read config, using defaults: {}", e);
+
+                match back_up_config(&path) {
+                    Ok(backup_path) => {
+                        println!("Kept the unreadable config as {}", backup_path.display())
+                    }
+                    Err(backup_error) => {
+                        println!("Failed to back up config: {}", backup_error);
+                        load_error = Some(e.to_string());
+                    }
+                }
+
+                Config::default()
+            }
+        };
+
+        saved.normalize();
+
+        let active_profile = match &overrides.profile {
+            Some(profile) if saved.profile(profile).is_some() => profile.clone(),
+            Some(profile) => {
+                println!(
+                    "There is no profile named {}, starting with {}",
+                    profile, saved.active_profile
+                );
+                saved.active_profile.clone()
+            }
+            None => saved.active_profile.clone(),
+        };
+
+        // The server URL given on the command line belongs to the profile the app starts with
+        overrides.profile = Some(active_profile.clone());
+
+        Settings {
+            path,
+            saved,
+            overrides,
+            active_profile,
+            load_error,
+        }
+    }
+
+    pub fn effective(&self) -> Config {
+        let mut config = self.saved.clone();
+        config.active_profile = self.active_profile.clone();
+        self.overrides.apply(&mut config);
+
+        config
+    }
+
+    // Picking a profile in the app is remembered, unlike the one given on the command line
+    pub fn select_profile(&mut self, name: &str) {
+        self.active_profile = name.to_string();
+        self.update_saved(|config| config.active_profile = name.to_string());
+    }
+
+    // For changes made as a side effect, like switching profiles, which still apply to this run
+    // when the file cannot be written
+    pub fn update_saved(&mut self, update: impl FnOnce(&mut Config)) {
+        let mut config = self.saved.clone();
+        update(&mut config);
+
+        if let Err(e) = self.save(config.clone()) {
+            println!("Failed to save config: {}", e);
+            self.saved = config;
+        }
+    }
+
+    pub fn save(&mut self, config: Config) -> Result<(), Error> {
+        if let Some(load_error) = &self.load_error {
+            return Err(Error::Storage(format!(
+                "Not overwriting {}, which could not be read: {}",
+                self.path.display(),
+                load_error
+            )));
+        }
+
+        config.validate()?;
+
+        if let Some(parent) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                return Err(Error::Storage(format!(
+                    "Failed to create {}: {}",
+                    parent.display(),
+                    e
+                )));
+            }
+        }
+
+        let json = match serde_json::to_string_pretty(&config) {
+            Ok(json) => json,
+            Err(e) => return Err(Error::Storage(format!("Failed to encode config: {}", e))),
+        };
+
+        if let Err(e) = fs::write(&self.path, json) {
+            return Err(Error::Storage(format!(
+                "Failed to write {}: {}",
+                self.path.display(),
+                e
+            )));
+        }
+
+        self.saved = config;
+
+        Ok(())
+    }
+}
+
+fn read_config(path: &PathBuf) -> Result<Config, Error> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        // Nothing has been saved yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => {
+            return Err(Error::Storage(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    match serde_json::from_str::<Config>(&json) {
+        Ok(config) => Ok(config),
+        Err(e) => Err(Error::Storage(format!(
+            "Failed to parse {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+// Copied rather than moved, the file is only replaced once the user saves
+fn back_up_config(path: &Path) -> Result<PathBuf, std::io::Error> {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(".bad");
+    let backup_path = PathBuf::from(backup_path);
+
+    fs::copy(path, &backup_path)?;
+
+    Ok(backup_path)
+}
+
+// $XDG_CONFIG_HOME, falling back to ~/.config as the specification asks
+pub fn config_directory() -> PathBuf {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) if !config_home.is_empty() => PathBuf::from(config_home),
+        _ => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => PathBuf::from("."),
+        },
+    };
+
+    base.join(CONFIG_DIRECTORY)
+}
+
+fn default_config_path() -> PathBuf {
+    config_directory().join(CONFIG_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(path: &Path) -> ConfigOverrides {
+        ConfigOverrides {
+            config_path: Some(path.to_path_buf()),
+            ..ConfigOverrides::default()
+        }
+    }
+
+    fn two_profiles() -> Config {
+        let mut config = Config::default();
+        config.profiles.push(Profile {
+            name: "Work".to_string(),
+            server_url: "https://vault.example.com".to_string(),
+            ..Profile::default()
+        });
+
+        config
+    }
+
+    #[test]
+    fn missing_file_loads_defaults() {
+        let directory = tempfile::tempdir().unwrap();
+        let settings = Settings::load(overrides(&directory.path().join(CONFIG_FILE)));
+
+        assert_eq!(settings.saved, Config::default());
+    }
+
+    #[test]
+    fn single_server_files_become_the_default_profile() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(CONFIG_FILE);
+        fs::write(
+            &path,
+            r#"{"server_url":"https://old.example.com","auto_lock_minutes":5}"#,
+        )
+        .unwrap();
+
+        let settings = Settings::load(overrides(&path));
+
+        assert_eq!(
+            settings.saved.active_profile().server_url,
+            "https://old.example.com"
+        );
+        assert_eq!(settings.saved.auto_lock_minutes, 5);
+    }
+
+    #[test]
+    fn broken_file_is_backed_up_before_saving() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(CONFIG_FILE);
+        fs::write(&path, "{ broken").unwrap();
+
+        let mut settings = Settings::load(overrides(&path));
+        assert_eq!(settings.saved, Config::default());
+
+        settings.save(Config::default()).unwrap();
+
+        let backup = fs::read_to_string(directory.path().join("config.json.bad")).unwrap();
+        assert_eq!(backup, "{ broken");
+    }
+
+    #[test]
+    fn unreadable_file_is_not_overwritten() {
+        let directory = tempfile::tempdir().unwrap();
+        // A directory where the file should be can neither be read nor copied
+        let path = directory.path().join(CONFIG_FILE);
+        fs::create_dir(&path).unwrap();
+
+        let mut settings = Settings::load(overrides(&path));
+
+        assert!(matches!(
+            settings.save(Config::default()),
+            Err(Error::Storage(_))
+        ));
+    }
+
+    #[test]
+    fn command_line_profile_is_not_saved() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(CONFIG_FILE);
+        fs::write(&path, serde_json::to_string(&two_profiles()).unwrap()).unwrap();
+
+        let mut settings = Settings::load(ConfigOverrides {
+            profile: Some("Work".to_string()),
+            server_url: Some("https://other.example.com".to_string()),
+            ..overrides(&path)
+        });
+
+        let effective = settings.effective();
+        assert_eq!(effective.active_profile, "Work");
+        assert_eq!(
+            effective.active_profile().server_url,
+            "https://other.example.com"
+        );
+
+        settings.update_saved(|config| config.auto_lock_minutes = 1);
+
+        let written = read_config(&path).unwrap();
+        assert_eq!(written.active_profile, DEFAULT_PROFILE);
+        assert_eq!(
+            written.profile("Work").unwrap().server_url,
+            "https://vault.example.com"
+        );
+        assert_eq!(written.auto_lock_minutes, 1);
+
+        settings.select_profile(DEFAULT_PROFILE);
+        settings.select_profile("Work");
+        assert_eq!(read_config(&path).unwrap().active_profile, "Work");
+    }
+}
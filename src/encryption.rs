@@ -1,76 +1,591 @@
 use aes_gcm_siv::{
-    aead::{generic_array::GenericArray, Aead, KeyInit},
-    Aes256GcmSiv, Nonce,
+    aead::{
+        generic_array::GenericArray, rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload,
+    },
+    Aes256GcmSiv,
 };
 use argon2::{
-    password_hash::{PasswordHasher, SaltString},
-    Argon2,
+    password_hash::{Output, PasswordHasher, SaltString},
+    Algorithm, Argon2, Params, Version,
 };
+use chacha20poly1305::XChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt::Write;
+use zeroize::Zeroizing;
 
-use crate::model::{Ciphers, EncryptedDataEntry};
+use crate::error::Error;
+use crate::model::{CipherSuite, Ciphers, EncryptedDataEntry, KdfParams, MasterKeys, VaultCipher};
+use crate::vault_entry::ENTRY_HANDLERS;
+
+// Ciphertext envelope layout: [version][algorithm][nonce][ciphertext]
+// Since version 2 the header, entry name and content type are authenticated as associated data,
+// since version 3 the entry id takes the place of the name so that renaming keeps it readable
+// and entries with the same name cannot be swapped
+pub const ENVELOPE_VERSION: u8 = 3;
+pub const ENVELOPE_VERSION_BOUND_TO_NAME: u8 = 2;
+pub const ENVELOPE_VERSION_WITHOUT_ASSOCIATED_DATA: u8 = 1;
+
+const ENVELOPE_HEADER_LEN: usize = 2;
+const AES_256_GCM_SIV_NONCE_LEN: usize = 12;
+const XCHACHA20_POLY1305_NONCE_LEN: usize = 24;
+
+// Suite used for everything written from now on; entries in other suites are re-encrypted on login
+pub const DEFAULT_CIPHER_SUITE: CipherSuite = CipherSuite::XChaCha20Poly1305;
+
+// Nonce shared by every entry written before the envelope was introduced
+pub const LEGACY_NONCE: &[u8; AES_256_GCM_SIV_NONCE_LEN] = b"unique nonce";
+
+pub const VAULT_KEY_LEN: usize = 32;
+
+// Key derivation settings for new accounts; existing accounts below them get upgraded on login
+pub const DEFAULT_KDF_ALGORITHM: &str = "argon2id";
+pub const DEFAULT_KDF_MEMORY_COST: u32 = 65536;
+pub const DEFAULT_KDF_ITERATIONS: u32 = 3;
+pub const DEFAULT_KDF_PARALLELISM: u32 = 4;
+
+const KDF_SALT_LEN: usize = 16;
+
+const RECOVERY_SECRET_LEN: usize = 16;
+
+// Every HKDF label starts with this prefix so the subkeys cannot collide with other applications
+const HKDF_INFO_PREFIX: &str = "rust-password-manager-client";
 
 pub fn encrypt_data_entry(
     data_entry_string: &str,
-    cipher: &Aes256GcmSiv,
-) -> Result<(Vec<u8>, Vec<u8>), String> {
-    let nonce = Nonce::from_slice(b"unique nonce");
-
-    match cipher.encrypt(nonce, data_entry_string.as_bytes()) {
-        Ok(ciphertext) => Ok((ciphertext, nonce.to_vec())),
-        Err(e) => Err(format!("Failed to encrypt data entry: {}", e)),
-    }
+    entry_id: &str,
+    content_type: &str,
+    cipher: &VaultCipher,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    seal_envelope(data_entry_string.as_bytes(), entry_id, content_type, cipher)
 }
 
 pub fn decrypt_data_entry(
     data_entry: &EncryptedDataEntry,
-    cipher: &Aes256GcmSiv,
-) -> Result<String, String> {
-    let ciphertext = &data_entry.content;
-    let nonce = Nonce::from_slice(data_entry.nonce.as_slice());
+    cipher: &VaultCipher,
+) -> Result<Zeroizing<String>, Error> {
+    let decrypted = open_data_entry(data_entry, cipher)?;
 
-    match cipher.decrypt(nonce, ciphertext.as_ref()) {
-        Ok(decrypted) => match String::from_utf8(decrypted) {
-            Ok(decrypted_string) => Ok(decrypted_string),
-            Err(e) => Err(format!("Failed to decode string: {}", e)),
-        },
-        Err(e) => Err(format!("Failed to decrypt data entry: {}", e)),
+    match std::str::from_utf8(&decrypted) {
+        Ok(decrypted_string) => Ok(Zeroizing::new(decrypted_string.to_string())),
+        Err(e) => Err(Error::Decode(format!("Failed to decode string: {}", e))),
     }
 }
 
-pub fn generate_all_master_ciphers(email: &str, password: &str) -> Result<Ciphers, String> {
-    let password_cipher = match generate_master_cipher(email, password, "password") {
-        Ok(cipher) => cipher,
-        Err(e) => return Err(e),
+pub fn generate_vault_key() -> Zeroizing<Vec<u8>> {
+    let mut vault_key = Zeroizing::new(vec![0u8; VAULT_KEY_LEN]);
+    OsRng.fill_bytes(&mut vault_key);
+
+    vault_key
+}
+
+// The vault key is stored on the server encrypted by the master key
+pub fn wrap_vault_key(
+    vault_key: &[u8],
+    entry_id: &str,
+    content_type: &str,
+    master_cipher: &VaultCipher,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    seal_envelope(vault_key, entry_id, content_type, master_cipher)
+}
+
+pub fn unwrap_vault_key(
+    protected_vault_key: &EncryptedDataEntry,
+    master_cipher: &VaultCipher,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let vault_key = match open_data_entry(protected_vault_key, master_cipher) {
+        Ok(vault_key) => vault_key,
+        Err(e) => return Err(e.context("Failed to unwrap vault key")),
     };
 
-    let note_cipher = match generate_master_cipher(email, password, "note") {
-        Ok(cipher) => cipher,
-        Err(e) => return Err(e),
+    if vault_key.len() != VAULT_KEY_LEN {
+        return Err(Error::Crypto(
+            "Failed to unwrap vault key: invalid key length".to_string(),
+        ));
+    }
+
+    Ok(vault_key)
+}
+
+// The key is used as is for AES-256-GCM-SIV so entries written before the suites existed still open
+pub fn create_vault_cipher(key: &[u8], suite: CipherSuite) -> Result<VaultCipher, Error> {
+    let xchacha20_poly1305_key = expand_key(key, "suite/xchacha20-poly1305")?;
+
+    Ok(VaultCipher {
+        suite,
+        aes_256_gcm_siv: Aes256GcmSiv::new(GenericArray::from_slice(key)),
+        xchacha20_poly1305: XChaCha20Poly1305::new(GenericArray::from_slice(
+            xchacha20_poly1305_key.as_slice(),
+        )),
+    })
+}
+
+fn cipher_suite_from_algorithm(algorithm: u8) -> Result<CipherSuite, Error> {
+    match algorithm {
+        1 => Ok(CipherSuite::Aes256GcmSiv),
+        2 => Ok(CipherSuite::XChaCha20Poly1305),
+        _ => Err(Error::Crypto(format!(
+            "Unsupported encryption algorithm: {}",
+            algorithm
+        ))),
+    }
+}
+
+fn nonce_len(suite: CipherSuite) -> usize {
+    match suite {
+        CipherSuite::Aes256GcmSiv => AES_256_GCM_SIV_NONCE_LEN,
+        CipherSuite::XChaCha20Poly1305 => XCHACHA20_POLY1305_NONCE_LEN,
+    }
+}
+
+fn seal_envelope(
+    plaintext: &[u8],
+    entry_id: &str,
+    content_type: &str,
+    cipher: &VaultCipher,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let header = [ENVELOPE_VERSION, cipher.suite as u8];
+    let associated_data = generate_associated_data(&header, entry_id, content_type);
+
+    let payload = Payload {
+        msg: plaintext,
+        aad: &associated_data,
     };
 
-    let card_cipher = match generate_master_cipher(email, password, "card") {
-        Ok(cipher) => cipher,
-        Err(e) => return Err(e),
+    let (nonce, ciphertext) = match cipher.suite {
+        CipherSuite::Aes256GcmSiv => {
+            let nonce = Aes256GcmSiv::generate_nonce(&mut OsRng);
+
+            (
+                nonce.to_vec(),
+                cipher.aes_256_gcm_siv.encrypt(&nonce, payload),
+            )
+        }
+        CipherSuite::XChaCha20Poly1305 => {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+            (
+                nonce.to_vec(),
+                cipher.xchacha20_poly1305.encrypt(&nonce, payload),
+            )
+        }
     };
 
-    let totp_entry_cipher = match generate_master_cipher(email, password, "totp_entry") {
-        Ok(cipher) => cipher,
-        Err(e) => return Err(e),
+    let ciphertext = match ciphertext {
+        Ok(ciphertext) => ciphertext,
+        Err(e) => {
+            return Err(Error::Crypto(format!(
+                "Failed to encrypt data entry: {}",
+                e
+            )))
+        }
+    };
+
+    let mut envelope = Vec::with_capacity(ENVELOPE_HEADER_LEN + nonce.len() + ciphertext.len());
+    envelope.extend_from_slice(&header);
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&ciphertext);
+
+    Ok((envelope, nonce))
+}
+
+// The suite is read from the header, so a vault can hold entries in several suites at once
+fn open_data_entry(
+    data_entry: &EncryptedDataEntry,
+    cipher: &VaultCipher,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let decrypted = if is_legacy_data_entry(data_entry) {
+        decrypt_with_suite(
+            cipher,
+            CipherSuite::Aes256GcmSiv,
+            &data_entry.nonce,
+            &data_entry.content,
+            &[],
+        )
+    } else {
+        let envelope = open_envelope(&data_entry.content)?;
+
+        let associated_data = match envelope.header[0] {
+            ENVELOPE_VERSION_WITHOUT_ASSOCIATED_DATA => Vec::new(),
+            ENVELOPE_VERSION_BOUND_TO_NAME => generate_associated_data(
+                envelope.header,
+                &data_entry.name,
+                &data_entry.content_type,
+            ),
+            _ => generate_associated_data(
+                envelope.header,
+                bound_entry_id(data_entry),
+                &data_entry.content_type,
+            ),
+        };
+
+        decrypt_with_suite(
+            cipher,
+            envelope.suite,
+            envelope.nonce,
+            envelope.ciphertext,
+            &associated_data,
+        )
     };
 
-    Ok(Ciphers {
-        password_cipher,
-        note_cipher,
-        card_cipher,
-        totp_entry_cipher,
+    // An entry moved to another id or retyped fails here just like a corrupted one
+    match decrypted {
+        Ok(decrypted) => Ok(Zeroizing::new(decrypted)),
+        Err(e) => Err(Error::Crypto(format!(
+            "Failed to decrypt data entry {} ({}): {}",
+            data_entry.name, data_entry.content_type, e
+        ))),
+    }
+}
+
+fn decrypt_with_suite(
+    cipher: &VaultCipher,
+    suite: CipherSuite,
+    nonce: &[u8],
+    ciphertext: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, aes_gcm_siv::aead::Error> {
+    let payload = Payload {
+        msg: ciphertext,
+        aad: associated_data,
+    };
+
+    match suite {
+        CipherSuite::Aes256GcmSiv => cipher
+            .aes_256_gcm_siv
+            .decrypt(GenericArray::from_slice(nonce), payload),
+        CipherSuite::XChaCha20Poly1305 => cipher
+            .xchacha20_poly1305
+            .decrypt(GenericArray::from_slice(nonce), payload),
+    }
+}
+
+// Entries written with the fixed nonce have no envelope and must be re-encrypted
+pub fn is_legacy_data_entry(data_entry: &EncryptedDataEntry) -> bool {
+    data_entry.nonce.as_slice() == LEGACY_NONCE
+}
+
+// Entries written before the vault key existed have no envelope or the first envelope version,
+// only those can still be encrypted with the ciphers derived from the master password
+pub fn may_predate_vault_key(data_entry: &EncryptedDataEntry) -> bool {
+    is_legacy_data_entry(data_entry)
+        || data_entry.content.first() == Some(&ENVELOPE_VERSION_WITHOUT_ASSOCIATED_DATA)
+}
+
+// Entries that decrypt but are not written in the current envelope version and cipher suite
+pub fn is_outdated_data_entry(data_entry: &EncryptedDataEntry) -> bool {
+    is_legacy_data_entry(data_entry)
+        || data_entry.content.first() != Some(&ENVELOPE_VERSION)
+        || data_entry.content.get(1) != Some(&(DEFAULT_CIPHER_SUITE as u8))
+}
+
+// Reserved entries have no id and are bound to their name, which never changes
+fn bound_entry_id(data_entry: &EncryptedDataEntry) -> &str {
+    if data_entry.id.is_empty() {
+        &data_entry.name
+    } else {
+        &data_entry.id
+    }
+}
+
+// Length prefixes keep the entry id and content type from running into each other
+fn generate_associated_data(header: &[u8], entry_id: &str, content_type: &str) -> Vec<u8> {
+    let mut associated_data = header.to_vec();
+
+    for field in [entry_id, content_type] {
+        associated_data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        associated_data.extend_from_slice(field.as_bytes());
+    }
+
+    associated_data
+}
+
+struct Envelope<'a> {
+    header: &'a [u8],
+    suite: CipherSuite,
+    nonce: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+fn open_envelope(envelope: &[u8]) -> Result<Envelope<'_>, Error> {
+    if envelope.len() < ENVELOPE_HEADER_LEN {
+        return Err(Error::Crypto(
+            "Encrypted data entry is too short".to_string(),
+        ));
+    }
+
+    if envelope[0] != ENVELOPE_VERSION
+        && envelope[0] != ENVELOPE_VERSION_BOUND_TO_NAME
+        && envelope[0] != ENVELOPE_VERSION_WITHOUT_ASSOCIATED_DATA
+    {
+        return Err(Error::Crypto(format!(
+            "Unsupported envelope version: {}",
+            envelope[0]
+        )));
+    }
+
+    let suite = cipher_suite_from_algorithm(envelope[1])?;
+
+    if envelope.len() < ENVELOPE_HEADER_LEN + nonce_len(suite) {
+        return Err(Error::Crypto(
+            "Encrypted data entry is too short".to_string(),
+        ));
+    }
+
+    let (header, body) = envelope.split_at(ENVELOPE_HEADER_LEN);
+    let (nonce, ciphertext) = body.split_at(nonce_len(suite));
+
+    Ok(Envelope {
+        header,
+        suite,
+        nonce,
+        ciphertext,
+    })
+}
+
+pub fn generate_all_vault_ciphers(vault_key: &[u8]) -> Result<Ciphers, Error> {
+    let mut ciphers = HashMap::new();
+
+    for handler in ENTRY_HANDLERS {
+        ciphers.insert(
+            handler.content_type,
+            generate_vault_cipher(vault_key, handler.content_type)?,
+        );
+    }
+
+    Ok(Ciphers { ciphers })
+}
+
+// The vault key is already uniformly random, so a cheap HKDF expansion is enough per type
+pub fn generate_vault_cipher(
+    vault_key: &[u8],
+    data_entry_type: &str,
+) -> Result<VaultCipher, Error> {
+    let vault_subkey = expand_key(vault_key, &format!("entry/{}", data_entry_type))?;
+
+    create_vault_cipher(vault_subkey.as_slice(), DEFAULT_CIPHER_SUITE)
+}
+
+pub fn generate_kdf_params() -> KdfParams {
+    let mut salt = vec![0u8; KDF_SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    KdfParams {
+        algorithm: DEFAULT_KDF_ALGORITHM.to_string(),
+        memory_cost: DEFAULT_KDF_MEMORY_COST,
+        iterations: DEFAULT_KDF_ITERATIONS,
+        parallelism: DEFAULT_KDF_PARALLELISM,
+        salt,
+    }
+}
+
+// Accounts without parameters still use the deterministic legacy derivation
+pub fn are_kdf_params_outdated(kdf_params: Option<&KdfParams>) -> bool {
+    match kdf_params {
+        Some(kdf_params) => {
+            kdf_params.algorithm != DEFAULT_KDF_ALGORITHM
+                || kdf_params.memory_cost < DEFAULT_KDF_MEMORY_COST
+                || kdf_params.iterations < DEFAULT_KDF_ITERATIONS
+        }
+        None => true,
+    }
+}
+
+// Runs the expensive password hash once and expands it into the authentication hash
+// and the master key that wraps the vault key
+pub fn generate_master_keys(
+    email: &str,
+    password: &str,
+    kdf_params: Option<&KdfParams>,
+) -> Result<MasterKeys, Error> {
+    let kdf_params = match kdf_params {
+        Some(kdf_params) => kdf_params,
+        None => return generate_legacy_master_keys(email, password),
+    };
+
+    let master_secret = derive_master_secret(password, kdf_params)?;
+
+    let auth_key = expand_key(master_secret.as_slice(), "authentication")?;
+    let vault_key_wrapping_key = expand_key(master_secret.as_slice(), "vault-key-wrapping")?;
+
+    Ok(MasterKeys {
+        auth_hash: encode_auth_hash(auth_key.as_slice())?,
+        vault_key_cipher: create_vault_cipher(
+            vault_key_wrapping_key.as_slice(),
+            DEFAULT_CIPHER_SUITE,
+        )?,
     })
 }
+
+// 128 random bits printed as dash separated groups of four hex digits
+pub fn generate_recovery_code() -> Zeroizing<String> {
+    let mut recovery_secret = Zeroizing::new([0u8; RECOVERY_SECRET_LEN]);
+    OsRng.fill_bytes(recovery_secret.as_mut_slice());
+
+    let mut recovery_code = Zeroizing::new(String::with_capacity(RECOVERY_SECRET_LEN * 3));
+
+    for (index, byte) in recovery_secret.iter().enumerate() {
+        if index > 0 && index % 2 == 0 {
+            recovery_code.push('-');
+        }
+
+        // Writing into a String cannot fail
+        let _ = write!(recovery_code, "{:02X}", byte);
+    }
+
+    recovery_code
+}
+
+fn parse_recovery_code(recovery_code: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let hex_digits: Zeroizing<String> = Zeroizing::new(
+        recovery_code
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .collect(),
+    );
+
+    if hex_digits.len() != RECOVERY_SECRET_LEN * 2
+        || !hex_digits.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(Error::Validation("Invalid recovery key".to_string()));
+    }
+
+    let mut recovery_secret = Zeroizing::new(Vec::with_capacity(RECOVERY_SECRET_LEN));
+
+    for index in (0..hex_digits.len()).step_by(2) {
+        match u8::from_str_radix(&hex_digits[index..index + 2], 16) {
+            Ok(byte) => recovery_secret.push(byte),
+            Err(_) => return Err(Error::Validation("Invalid recovery key".to_string())),
+        }
+    }
+
+    Ok(recovery_secret)
+}
+
+// The recovery code is already uniformly random, so it is split like the master password
+// with HKDF alone and no password hash
+pub fn generate_recovery_keys(recovery_code: &str) -> Result<MasterKeys, Error> {
+    let recovery_secret = parse_recovery_code(recovery_code)?;
+
+    let auth_key = expand_key(&recovery_secret, "recovery/authentication")?;
+    let vault_key_wrapping_key = expand_key(&recovery_secret, "recovery/vault-key-wrapping")?;
+
+    Ok(MasterKeys {
+        auth_hash: encode_auth_hash(auth_key.as_slice())?,
+        vault_key_cipher: create_vault_cipher(
+            vault_key_wrapping_key.as_slice(),
+            DEFAULT_CIPHER_SUITE,
+        )?,
+    })
+}
+
+fn encode_auth_hash(auth_key: &[u8]) -> Result<String, Error> {
+    match Output::new(auth_key) {
+        Ok(output) => Ok(output.to_string()),
+        Err(e) => Err(Error::Crypto(format!(
+            "Failed to encode authentication hash: {}",
+            e
+        ))),
+    }
+}
+
+// Accounts without key derivation settings, kept until they are upgraded on login
+fn generate_legacy_master_keys(email: &str, password: &str) -> Result<MasterKeys, Error> {
+    Ok(MasterKeys {
+        auth_hash: hash_deterministic_key(email, password, "authentication")?.to_string(),
+        vault_key_cipher: generate_master_cipher(email, password, "vault_key")?,
+    })
+}
+
+fn derive_master_secret(
+    password: &str,
+    kdf_params: &KdfParams,
+) -> Result<Zeroizing<[u8; VAULT_KEY_LEN]>, Error> {
+    let algorithm = match Algorithm::new(&kdf_params.algorithm) {
+        Ok(algorithm) => algorithm,
+        Err(e) => {
+            return Err(Error::Crypto(format!(
+                "Unsupported key derivation algorithm: {}",
+                e
+            )))
+        }
+    };
+
+    let params = match Params::new(
+        kdf_params.memory_cost,
+        kdf_params.iterations,
+        kdf_params.parallelism,
+        Some(VAULT_KEY_LEN),
+    ) {
+        Ok(params) => params,
+        Err(e) => {
+            return Err(Error::Crypto(format!(
+                "Invalid key derivation parameters: {}",
+                e
+            )))
+        }
+    };
+
+    let mut master_secret = Zeroizing::new([0u8; VAULT_KEY_LEN]);
+
+    if let Err(e) = Argon2::new(algorithm, Version::V0x13, params).hash_password_into(
+        password.as_bytes(),
+        &kdf_params.salt,
+        master_secret.as_mut_slice(),
+    ) {
+        return Err(Error::Crypto(format!("Failed to hash password: {}", e)));
+    }
+
+    Ok(master_secret)
+}
+
+fn expand_key(input_key: &[u8], label: &str) -> Result<Zeroizing<[u8; VAULT_KEY_LEN]>, Error> {
+    let info = format!("{}/{}", HKDF_INFO_PREFIX, label);
+
+    let mut output_key = Zeroizing::new([0u8; VAULT_KEY_LEN]);
+
+    match Hkdf::<Sha256>::new(None, input_key).expand(info.as_bytes(), output_key.as_mut_slice()) {
+        Ok(()) => Ok(output_key),
+        Err(e) => Err(Error::Crypto(format!("Failed to expand key: {}", e))),
+    }
+}
+
+// Ciphers used before the vault key was introduced, only needed to migrate old entries
+#[allow(clippy::question_mark)]
+pub fn generate_all_master_ciphers(email: &str, password: &str) -> Result<Ciphers, Error> {
+    let mut ciphers = HashMap::new();
+
+    for handler in ENTRY_HANDLERS {
+        let cipher = match generate_master_cipher(email, password, handler.content_type) {
+            Ok(cipher) => cipher,
+            Err(e) => return Err(e),
+        };
+
+        ciphers.insert(handler.content_type, cipher);
+    }
+
+    Ok(Ciphers { ciphers })
+}
+
 pub fn generate_master_cipher(
     email: &str,
     password: &str,
     data_entry_type: &str,
-) -> Result<Aes256GcmSiv, String> {
-    let unhashed_master_key = generate_deterministic_key(email, password, data_entry_type);
+) -> Result<VaultCipher, Error> {
+    let master_key_output = hash_deterministic_key(email, password, data_entry_type)?;
+
+    create_vault_cipher(master_key_output.as_bytes(), DEFAULT_CIPHER_SUITE)
+}
+
+#[allow(clippy::question_mark)]
+fn hash_deterministic_key(
+    email: &str,
+    password: &str,
+    data_entry_type: &str,
+) -> Result<Output, Error> {
+    let unhashed_master_key =
+        Zeroizing::new(generate_deterministic_key(email, password, data_entry_type));
 
     let deterministic_salt = match generate_deterministic_salt(&unhashed_master_key) {
         Ok(salt) => salt,
@@ -81,28 +596,222 @@ pub fn generate_master_cipher(
         .hash_password(unhashed_master_key.as_bytes(), deterministic_salt.as_salt())
     {
         Ok(hash) => hash.hash,
-        Err(e) => return Err(format!("Failed to hash password: {}", e)),
-    };
-
-    let master_key_output = match master_key_hash {
-        Some(hash) => hash,
-        None => return Err("Failed to generate master key".to_string()),
+        Err(e) => return Err(Error::Crypto(format!("Failed to hash password: {}", e))),
     };
 
-    let master_key_string = master_key_output.as_bytes();
-
-    let master_key = GenericArray::from_slice(master_key_string);
-
-    Ok(Aes256GcmSiv::new(master_key))
+    match master_key_hash {
+        Some(hash) => Ok(hash),
+        None => Err(Error::Crypto("Failed to generate master key".to_string())),
+    }
 }
 
 pub fn generate_deterministic_key(email: &str, password: &str, data_entry_type: &str) -> String {
     format!("{}{}{}", email, password, data_entry_type)
 }
 
-pub fn generate_deterministic_salt(password: &str) -> Result<SaltString, String> {
+pub fn generate_deterministic_salt(password: &str) -> Result<SaltString, Error> {
     match SaltString::encode_b64(password.as_bytes()) {
         Ok(salt) => Ok(salt),
-        Err(e) => Err(format!("Failed to generate salt: {}", e)),
+        Err(e) => Err(Error::Crypto(format!("Failed to generate salt: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> VaultCipher {
+        create_vault_cipher(&[7u8; VAULT_KEY_LEN], DEFAULT_CIPHER_SUITE).unwrap()
+    }
+
+    fn seal(plaintext: &str, id: &str, name: &str, cipher: &VaultCipher) -> EncryptedDataEntry {
+        let (content, nonce) = encrypt_data_entry(plaintext, id, "password", cipher).unwrap();
+
+        EncryptedDataEntry {
+            id: id.to_string(),
+            name: name.to_string(),
+            content,
+            nonce,
+            content_type: "password".to_string(),
+            revision: None,
+        }
+    }
+
+    // Written like clients before version 3 did, bound to the name
+    fn seal_bound_to_name(plaintext: &str, name: &str, cipher: &VaultCipher) -> Vec<u8> {
+        let header = [ENVELOPE_VERSION_BOUND_TO_NAME, cipher.suite as u8];
+        let associated_data = generate_associated_data(&header, name, "password");
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher
+            .xchacha20_poly1305
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: &associated_data,
+                },
+            )
+            .unwrap();
+
+        [&header[..], &nonce[..], &ciphertext[..]].concat()
+    }
+
+    #[test]
+    fn envelope_round_trips() {
+        let cipher = cipher();
+        let entry = seal("secret", "a", "mail", &cipher);
+
+        assert_eq!(entry.content[0], ENVELOPE_VERSION);
+        assert!(!is_outdated_data_entry(&entry));
+        assert_eq!(
+            decrypt_data_entry(&entry, &cipher).unwrap().as_str(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn renamed_entries_stay_readable() {
+        let cipher = cipher();
+        let mut entry = seal("secret", "a", "mail", &cipher);
+        entry.name = "work mail".to_string();
+
+        assert_eq!(
+            decrypt_data_entry(&entry, &cipher).unwrap().as_str(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn swapped_ids_and_types_fail() {
+        let cipher = cipher();
+
+        let mut moved = seal("secret", "a", "mail", &cipher);
+        moved.id = "b".to_string();
+        assert!(matches!(
+            decrypt_data_entry(&moved, &cipher),
+            Err(Error::Crypto(_))
+        ));
+
+        let mut retyped = seal("secret", "a", "mail", &cipher);
+        retyped.content_type = "note".to_string();
+        assert!(matches!(
+            decrypt_data_entry(&retyped, &cipher),
+            Err(Error::Crypto(_))
+        ));
+    }
+
+    #[test]
+    fn tampered_envelopes_fail() {
+        let cipher = cipher();
+
+        let mut flipped = seal("secret", "a", "mail", &cipher);
+        let last = flipped.content.len() - 1;
+        flipped.content[last] ^= 1;
+        assert!(matches!(
+            decrypt_data_entry(&flipped, &cipher),
+            Err(Error::Crypto(_))
+        ));
+
+        let mut truncated = seal("secret", "a", "mail", &cipher);
+        truncated.content.truncate(ENVELOPE_HEADER_LEN + 4);
+        assert!(matches!(
+            decrypt_data_entry(&truncated, &cipher),
+            Err(Error::Crypto(_))
+        ));
+
+        let mut unknown_version = seal("secret", "a", "mail", &cipher);
+        unknown_version.content[0] = ENVELOPE_VERSION + 1;
+        assert!(matches!(
+            decrypt_data_entry(&unknown_version, &cipher),
+            Err(Error::Crypto(_))
+        ));
+    }
+
+    #[test]
+    fn envelopes_bound_to_the_name_stay_readable() {
+        let cipher = cipher();
+        let mut entry = seal("secret", "a", "mail", &cipher);
+        entry.content = seal_bound_to_name("secret", "mail", &cipher);
+
+        assert!(is_outdated_data_entry(&entry));
+        assert_eq!(
+            decrypt_data_entry(&entry, &cipher).unwrap().as_str(),
+            "secret"
+        );
+
+        entry.name = "work mail".to_string();
+        assert!(decrypt_data_entry(&entry, &cipher).is_err());
+    }
+
+    #[test]
+    fn only_old_formats_may_predate_the_vault_key() {
+        let mut entry = seal("secret", "a", "mail", &cipher());
+        assert!(!may_predate_vault_key(&entry));
+
+        entry.content[0] = ENVELOPE_VERSION_BOUND_TO_NAME;
+        assert!(!may_predate_vault_key(&entry));
+
+        entry.content[0] = ENVELOPE_VERSION_WITHOUT_ASSOCIATED_DATA;
+        assert!(may_predate_vault_key(&entry));
+
+        entry.nonce = LEGACY_NONCE.to_vec();
+        entry.content = vec![0; 32];
+        assert!(may_predate_vault_key(&entry));
+    }
+
+    #[test]
+    fn generated_recovery_codes_parse() {
+        let recovery_code = generate_recovery_code();
+
+        assert_eq!(recovery_code.len(), RECOVERY_SECRET_LEN * 2 + 7);
+        assert_eq!(
+            parse_recovery_code(&recovery_code).unwrap().len(),
+            RECOVERY_SECRET_LEN
+        );
+    }
+
+    #[test]
+    fn recovery_codes_parse_however_they_are_typed() {
+        let expected: Vec<u8> = (0..RECOVERY_SECRET_LEN as u8).collect();
+
+        for recovery_code in [
+            "0001-0203-0405-0607-0809-0A0B-0C0D-0E0F",
+            "0001 0203 0405 0607 0809 0a0b 0c0d 0e0f",
+            "000102030405060708090a0b0c0d0e0f\n",
+        ] {
+            assert_eq!(
+                parse_recovery_code(recovery_code).unwrap().as_slice(),
+                expected.as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_recovery_codes_are_rejected() {
+        for recovery_code in [
+            "",
+            "0001-0203-0405-0607-0809-0A0B-0C0D-0E",
+            "0001-0203-0405-0607-0809-0A0B-0C0D-0E0F-10",
+            "0001-0203-0405-0607-0809-0A0B-0C0D-0E0G",
+            "0001-0203-0405-0607-0809-0A0B-0C0D-0E0\u{e9}",
+        ] {
+            assert!(matches!(
+                parse_recovery_code(recovery_code),
+                Err(Error::Validation(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let entry = seal("secret", "a", "mail", &cipher());
+        let other_cipher =
+            create_vault_cipher(&[8u8; VAULT_KEY_LEN], DEFAULT_CIPHER_SUITE).unwrap();
+
+        assert!(matches!(
+            decrypt_data_entry(&entry, &other_cipher),
+            Err(Error::Crypto(_))
+        ));
     }
 }
@@ -1,13 +1,30 @@
 use std::sync::Mutex;
 
-use crate::encryption::{decrypt_data_entry, encrypt_data_entry};
+use crate::encryption::{is_outdated_data_entry, wrap_vault_key};
+use crate::error::Error;
+use crate::manifest::MANIFEST_CONTENT_TYPE;
 use crate::model::{
-    Card, Ciphers, DataVault, EncryptedDataEntry, EntriesVault, GetAllEncryptedDataEntriesResponse,
-    Note, Password, TOTPEntry,
+    generate_entry_id, Card, Ciphers, DataVault, EncryptedDataEntry, EntriesVault, Note, Password,
+    TOTPEntry, VaultCipher,
 };
-use aes_gcm_siv::Aes256GcmSiv;
+use crate::recovery::{RECOVERY_CODE_CONTENT_TYPE, RECOVERY_KEY_CONTENT_TYPE};
+use crate::vault_entry::{find_entry_handler, EntryField, VaultEntry};
 use rayon::prelude::*;
 
+pub const VAULT_KEY_CONTENT_TYPE: &str = "vault_key";
+// The vault key wrapped by the other master key while the server has not confirmed which master
+// password it expects
+pub const PENDING_VAULT_KEY_CONTENT_TYPE: &str = "pending_vault_key";
+
+// Reserved entries hold keys and metadata, never user data
+pub fn is_reserved_content_type(content_type: &str) -> bool {
+    content_type == VAULT_KEY_CONTENT_TYPE
+        || content_type == PENDING_VAULT_KEY_CONTENT_TYPE
+        || content_type == MANIFEST_CONTENT_TYPE
+        || content_type == RECOVERY_KEY_CONTENT_TYPE
+        || content_type == RECOVERY_CODE_CONTENT_TYPE
+}
+
 // Create entry functions
 pub fn create_password_entry(
     name: &str,
@@ -17,6 +34,7 @@ pub fn create_password_entry(
     expiration_date: &str,
 ) -> Password {
     Password {
+        id: generate_entry_id(),
         name: name.to_string(),
         username: username.to_string(),
         password: password.to_string(),
@@ -28,6 +46,7 @@ pub fn create_password_entry(
 
 pub fn create_note_entry(name: &str, content: &str) -> Note {
     Note {
+        id: generate_entry_id(),
         name: name.to_string(),
         content: content.to_string(),
         created_at: "".to_string(),
@@ -42,6 +61,7 @@ pub fn create_card_entry(
     expiration_date: &str,
 ) -> Card {
     Card {
+        id: generate_entry_id(),
         name: name.to_string(),
         cardholder_name: cardholder_name.to_string(),
         card_number: card_number.to_string(),
@@ -60,6 +80,7 @@ pub fn create_totp_entry(
     period: u64,
 ) -> TOTPEntry {
     TOTPEntry {
+        id: generate_entry_id(),
         name: name.to_string(),
         algorithm: algorithm.to_string(),
         secret: secret.to_string(),
@@ -70,242 +91,338 @@ pub fn create_totp_entry(
     }
 }
 
-// Encrypt entry functions
-pub fn encrypt_password_entry(
-    password: &Password,
-    cipher: &Aes256GcmSiv,
-) -> Result<EncryptedDataEntry, String> {
-    let serialized_data = match serde_json::to_string(&password) {
-        Ok(data) => data,
-        Err(e) => return Err(format!("Failed to serialize password: {}", e)),
-    };
-
-    match encrypt_data_entry(&serialized_data, cipher) {
-        Ok((content, nonce)) => Ok(EncryptedDataEntry {
-            name: password.name.clone(),
-            content,
-            nonce,
-            content_type: "password".to_string(),
-        }),
-        Err(e) => Err(e),
-    }
+// Encrypts with the cipher of the entry type
+pub fn encrypt_vault_entry(
+    vault_entry: &VaultEntry,
+    ciphers: &Ciphers,
+) -> Result<EncryptedDataEntry, Error> {
+    vault_entry.encrypt(ciphers.get(vault_entry.content_type())?)
 }
 
-pub fn encrypt_note_entry(
-    note: &Note,
-    cipher: &Aes256GcmSiv,
-) -> Result<EncryptedDataEntry, String> {
-    let serialized_data = match serde_json::to_string(&note) {
-        Ok(data) => data,
-        Err(e) => return Err(format!("Failed to serialize note: {}", e)),
-    };
+// Decrypts with the handler registered for the content type
+pub fn decrypt_vault_entry(
+    encrypted_data_entry: &EncryptedDataEntry,
+    ciphers: &Ciphers,
+) -> Result<VaultEntry, Error> {
+    let content_type = &encrypted_data_entry.content_type;
 
-    match encrypt_data_entry(&serialized_data, cipher) {
-        Ok((content, nonce)) => Ok(EncryptedDataEntry {
-            name: note.name.clone(),
-            content,
-            nonce,
-            content_type: "note".to_string(),
-        }),
-        Err(e) => Err(e),
+    match find_entry_handler(content_type) {
+        Some(handler) => (handler.decrypt)(encrypted_data_entry, ciphers.get(content_type)?),
+        None => Err(Error::Decode(format!(
+            "Unknown content type: {}",
+            content_type
+        ))),
     }
 }
 
-pub fn encrypt_card_entry(
-    card: &Card,
-    cipher: &Aes256GcmSiv,
-) -> Result<EncryptedDataEntry, String> {
-    let serialized_data = match serde_json::to_string(&card) {
-        Ok(data) => data,
-        Err(e) => return Err(format!("Failed to serialize card: {}", e)),
-    };
+pub fn describe_data_entry(
+    encrypted_data_entry: &EncryptedDataEntry,
+    ciphers: &Ciphers,
+) -> Result<Vec<EntryField>, Error> {
+    decrypt_vault_entry(encrypted_data_entry, ciphers).map(|vault_entry| vault_entry.describe())
+}
 
-    match encrypt_data_entry(&serialized_data, cipher) {
-        Ok((content, nonce)) => Ok(EncryptedDataEntry {
-            name: card.name.clone(),
-            content,
-            nonce,
-            content_type: "card".to_string(),
-        }),
-        Err(e) => Err(e),
+// Vault functions
+// Decrypts the entry into the vault in place of the version with the same id, returning
+// whether it replaced one
+pub fn store_vault_entry(
+    data_vault: &mut DataVault,
+    encrypted_data_entry: &EncryptedDataEntry,
+) -> Result<bool, Error> {
+    // Written by a client that predates ids, the next login migrates it
+    if encrypted_data_entry.id.is_empty() {
+        return Err(Error::Decode(format!(
+            "Entry {} has no id yet",
+            encrypted_data_entry.name
+        )));
     }
-}
 
-pub fn encrypt_totp_entry(
-    totp_entry: &TOTPEntry,
-    cipher: &Aes256GcmSiv,
-) -> Result<EncryptedDataEntry, String> {
-    let serialized_data = match serde_json::to_string(&totp_entry) {
-        Ok(data) => data,
-        Err(e) => return Err(format!("Failed to serialize TOTP entry: {}", e)),
-    };
+    let mut vault_entry = decrypt_vault_entry(encrypted_data_entry, &data_vault.ciphers)?;
 
-    match encrypt_data_entry(&serialized_data, cipher) {
-        Ok((content, nonce)) => Ok(EncryptedDataEntry {
-            name: totp_entry.name.clone(),
-            content,
-            nonce,
-            content_type: "totp_entry".to_string(),
-        }),
-        Err(e) => Err(e),
-    }
+    // An id missing inside is added by the next login
+    assign_entry_id(&mut vault_entry, encrypted_data_entry)?;
+
+    Ok(data_vault.entries_vault.store(vault_entry))
 }
 
-// Decrypt entry functions
-pub fn decrypt_password_entry(
+// Entries encrypted before they had an id get one, returns whether the entry has to be uploaded
+// again to carry it on the outside too. An entry that carries another id inside than outside
+// was moved by someone and is left alone on the server
+fn assign_entry_id(
+    vault_entry: &mut VaultEntry,
     encrypted_data_entry: &EncryptedDataEntry,
-    cipher: &Aes256GcmSiv,
-) -> Result<Password, String> {
-    match decrypt_data_entry(encrypted_data_entry, cipher) {
-        Ok(data) => match serde_json::from_str::<Password>(&data) {
-            Ok(password) => Ok(password),
-            Err(e) => Err(format!("Failed to deserialize password: {}", e)),
-        },
-        Err(e) => Err(e),
+) -> Result<bool, Error> {
+    let id = vault_entry.id_mut();
+
+    if id.is_empty() {
+        *id = if encrypted_data_entry.id.is_empty() {
+            generate_entry_id()
+        } else {
+            encrypted_data_entry.id.clone()
+        };
+
+        return Ok(true);
     }
-}
 
-pub fn decrypt_note_entry(
-    encrypted_data_entry: &EncryptedDataEntry,
-    cipher: &Aes256GcmSiv,
-) -> Result<Note, String> {
-    match decrypt_data_entry(encrypted_data_entry, cipher) {
-        Ok(data) => match serde_json::from_str::<Note>(&data) {
-            Ok(note) => Ok(note),
-            Err(e) => Err(format!("Failed to deserialize note: {}", e)),
-        },
-        Err(e) => Err(e),
+    if encrypted_data_entry.id.is_empty() {
+        return Ok(true);
     }
-}
 
-pub fn decrypt_card_entry(
-    encrypted_data_entry: &EncryptedDataEntry,
-    cipher: &Aes256GcmSiv,
-) -> Result<Card, String> {
-    match decrypt_data_entry(encrypted_data_entry, cipher) {
-        Ok(data) => match serde_json::from_str::<Card>(&data) {
-            Ok(card) => Ok(card),
-            Err(e) => Err(format!("Failed to deserialize card: {}", e)),
-        },
-        Err(e) => Err(e),
+    if *id != encrypted_data_entry.id {
+        return Err(Error::Decode(format!(
+            "The entry {} is stored as {} but was written as {}",
+            encrypted_data_entry.name, encrypted_data_entry.id, id
+        )));
     }
+
+    Ok(false)
 }
 
-pub fn decrypt_totp_entry(
-    encrypted_data_entry: &EncryptedDataEntry,
-    cipher: &Aes256GcmSiv,
-) -> Result<TOTPEntry, String> {
-    match decrypt_data_entry(encrypted_data_entry, cipher) {
-        Ok(data) => match serde_json::from_str::<TOTPEntry>(&data) {
-            Ok(totp_entry) => Ok(totp_entry),
-            Err(e) => Err(format!("Failed to deserialize TOTP entry: {}", e)),
-        },
-        Err(e) => Err(e),
-    }
+// Returns the entries that need re-uploading and the entries that could not be decrypted
+pub fn fill_data_vault_from_entries(
+    data_vault: &mut DataVault,
+    encrypted_data_entries: &[EncryptedDataEntry],
+) -> (Vec<EncryptedDataEntry>, Vec<EncryptedDataEntry>) {
+    fill_data_vault(data_vault, encrypted_data_entries, None)
 }
 
-// Vault functions
-pub fn fill_data_vault_from_response(
+// Decrypts entries written before the vault key existed and re-encrypts them with the vault ciphers
+pub fn fill_data_vault_from_legacy_entries(
     data_vault: &mut DataVault,
-    response: GetAllEncryptedDataEntriesResponse,
-) {
-    let passwords: Mutex<Vec<Password>> = Mutex::new(Vec::new());
-    let notes: Mutex<Vec<Note>> = Mutex::new(Vec::new());
-    let cards: Mutex<Vec<Card>> = Mutex::new(Vec::new());
-    let totp_entries: Mutex<Vec<TOTPEntry>> = Mutex::new(Vec::new());
-
-    response.data.par_iter().for_each(|encrypted_data_entry| {
-        match encrypted_data_entry.content_type.as_str() {
-            "password" => {
-                match decrypt_password_entry(
-                    encrypted_data_entry,
-                    &data_vault.ciphers.password_cipher,
-                ) {
-                    Ok(password) => passwords.lock().unwrap().push(password),
-                    Err(e) => println!("{}", e),
-                }
+    legacy_entries: &[EncryptedDataEntry],
+    legacy_ciphers: &Ciphers,
+) -> (Vec<EncryptedDataEntry>, Vec<EncryptedDataEntry>) {
+    fill_data_vault(data_vault, legacy_entries, Some(legacy_ciphers))
+}
+
+fn fill_data_vault(
+    data_vault: &mut DataVault,
+    encrypted_data_entries: &[EncryptedDataEntry],
+    legacy_ciphers: Option<&Ciphers>,
+) -> (Vec<EncryptedDataEntry>, Vec<EncryptedDataEntry>) {
+    let vault_entries: Mutex<Vec<VaultEntry>> = Mutex::new(Vec::new());
+    let migrated_entries: Mutex<Vec<EncryptedDataEntry>> = Mutex::new(Vec::new());
+    let failed_entries: Mutex<Vec<EncryptedDataEntry>> = Mutex::new(Vec::new());
+    let integrity_warnings: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    let decrypt_ciphers = legacy_ciphers.unwrap_or(&data_vault.ciphers);
+    let encrypt_ciphers = &data_vault.ciphers;
+
+    encrypted_data_entries
+        .par_iter()
+        .for_each(|encrypted_data_entry| {
+            let content_type = encrypted_data_entry.content_type.as_str();
+
+            if is_reserved_content_type(content_type) {
+                return;
             }
-            "note" => {
-                match decrypt_note_entry(encrypted_data_entry, &data_vault.ciphers.note_cipher) {
-                    Ok(note) => notes.lock().unwrap().push(note),
-                    Err(e) => println!("{}", e),
-                }
+
+            if find_entry_handler(content_type).is_none() {
+                println!("Unknown content type: {}", content_type);
+                return;
             }
-            "card" => {
-                match decrypt_card_entry(encrypted_data_entry, &data_vault.ciphers.card_cipher) {
-                    Ok(card) => cards.lock().unwrap().push(card),
-                    Err(e) => println!("{}", e),
+
+            let mut vault_entry = match decrypt_vault_entry(encrypted_data_entry, decrypt_ciphers) {
+                Ok(vault_entry) => vault_entry,
+                Err(e) => {
+                    println!("{}", e);
+                    failed_entries
+                        .lock()
+                        .unwrap()
+                        .push(encrypted_data_entry.clone());
+                    return;
                 }
-            }
-            "totp_entry" => {
-                match decrypt_totp_entry(
-                    encrypted_data_entry,
-                    &data_vault.ciphers.totp_entry_cipher,
-                ) {
-                    Ok(totp_entry) => totp_entries.lock().unwrap().push(totp_entry),
-                    Err(e) => println!("{}", e),
+            };
+
+            let is_id_outdated = match assign_entry_id(&mut vault_entry, encrypted_data_entry) {
+                Ok(is_id_outdated) => is_id_outdated,
+                Err(e) => {
+                    println!("{}", e);
+                    integrity_warnings.lock().unwrap().push(e.to_string());
+                    return;
+                }
+            };
+
+            if legacy_ciphers.is_some()
+                || is_outdated_data_entry(encrypted_data_entry)
+                || is_id_outdated
+            {
+                match encrypt_vault_entry(&vault_entry, encrypt_ciphers) {
+                    Ok(migrated_entry) => migrated_entries.lock().unwrap().push(migrated_entry),
+                    Err(e) => println!("Failed to migrate entry: {}", e),
                 }
             }
-            _ => println!(
-                "Unknown content type: {}",
-                encrypted_data_entry.content_type
-            ),
-        }
-    });
 
-    let mut password_guard = passwords.lock().unwrap();
-    let mut note_guard = notes.lock().unwrap();
-    let mut card_guard = cards.lock().unwrap();
-    let mut totp_entry_guard = totp_entries.lock().unwrap();
+            vault_entries.lock().unwrap().push(vault_entry);
+        });
 
     data_vault
         .entries_vault
-        .passwords
-        .append(&mut password_guard);
+        .entries
+        .append(&mut vault_entries.lock().unwrap());
 
-    data_vault.entries_vault.notes.append(&mut note_guard);
+    data_vault
+        .integrity_warnings
+        .append(&mut integrity_warnings.lock().unwrap());
 
-    data_vault.entries_vault.cards.append(&mut card_guard);
+    (
+        migrated_entries.into_inner().unwrap(),
+        failed_entries.into_inner().unwrap(),
+    )
+}
 
-    data_vault
-        .entries_vault
-        .totp_entries
-        .append(&mut totp_entry_guard);
+// Reserved entry holding the vault key wrapped by the master key
+pub fn create_protected_vault_key_entry(
+    vault_key: &[u8],
+    master_cipher: &VaultCipher,
+) -> Result<EncryptedDataEntry, Error> {
+    create_wrapped_vault_key_entry(vault_key, VAULT_KEY_CONTENT_TYPE, master_cipher)
 }
 
+pub fn create_pending_vault_key_entry(
+    vault_key: &[u8],
+    master_cipher: &VaultCipher,
+) -> Result<EncryptedDataEntry, Error> {
+    create_wrapped_vault_key_entry(vault_key, PENDING_VAULT_KEY_CONTENT_TYPE, master_cipher)
+}
+
+fn create_wrapped_vault_key_entry(
+    vault_key: &[u8],
+    content_type: &str,
+    master_cipher: &VaultCipher,
+) -> Result<EncryptedDataEntry, Error> {
+    let (content, nonce) = wrap_vault_key(vault_key, content_type, content_type, master_cipher)?;
+
+    Ok(EncryptedDataEntry {
+        id: String::new(),
+        name: content_type.to_string(),
+        content,
+        nonce,
+        content_type: content_type.to_string(),
+        revision: None,
+    })
+}
+
+pub fn find_protected_vault_key_entry(
+    encrypted_data_entries: &[EncryptedDataEntry],
+) -> Option<&EncryptedDataEntry> {
+    encrypted_data_entries
+        .iter()
+        .find(|entry| entry.content_type == VAULT_KEY_CONTENT_TYPE)
+}
+
+pub fn find_pending_vault_key_entry(
+    encrypted_data_entries: &[EncryptedDataEntry],
+) -> Option<&EncryptedDataEntry> {
+    encrypted_data_entries
+        .iter()
+        .find(|entry| entry.content_type == PENDING_VAULT_KEY_CONTENT_TYPE)
+}
+
+// Fails if any entry cannot be encrypted, so that no entry is silently left behind
 pub fn encrypt_entry_vault(
     entry_vault: &EntriesVault,
-    ciphers: Ciphers,
-) -> Vec<EncryptedDataEntry> {
-    let encrypted_entries = Mutex::new(Vec::new());
-
-    entry_vault.passwords.par_iter().for_each(|password| {
-        match encrypt_password_entry(password, &ciphers.password_cipher) {
-            Ok(encrypted_entry) => encrypted_entries.lock().unwrap().push(encrypted_entry),
-            Err(e) => println!("{}", e),
-        }
-    });
+    ciphers: &Ciphers,
+) -> Result<Vec<EncryptedDataEntry>, Error> {
+    entry_vault
+        .entries
+        .par_iter()
+        .map(|vault_entry| encrypt_vault_entry(vault_entry, ciphers))
+        .collect()
+}
 
-    entry_vault.notes.par_iter().for_each(|note| {
-        match encrypt_note_entry(note, &ciphers.note_cipher) {
-            Ok(encrypted_entry) => encrypted_entries.lock().unwrap().push(encrypted_entry),
-            Err(e) => println!("{}", e),
-        }
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{
+        encrypt_data_entry, generate_vault_cipher, generate_vault_key, unwrap_vault_key,
+    };
 
-    entry_vault.cards.par_iter().for_each(|card| {
-        match encrypt_card_entry(card, &ciphers.card_cipher) {
-            Ok(encrypted_entry) => encrypted_entries.lock().unwrap().push(encrypted_entry),
-            Err(e) => println!("{}", e),
-        }
-    });
+    // Encrypted for the outer id, whatever id the entry carries inside
+    fn encrypted(password: &Password, id: &str, ciphers: &Ciphers) -> EncryptedDataEntry {
+        let (content, nonce) = encrypt_data_entry(
+            &serde_json::to_string(password).unwrap(),
+            id,
+            "password",
+            ciphers.get("password").unwrap(),
+        )
+        .unwrap();
 
-    entry_vault.totp_entries.par_iter().for_each(|totp_entry| {
-        match encrypt_totp_entry(totp_entry, &ciphers.totp_entry_cipher) {
-            Ok(encrypted_entry) => encrypted_entries.lock().unwrap().push(encrypted_entry),
-            Err(e) => println!("{}", e),
+        EncryptedDataEntry {
+            id: id.to_string(),
+            name: password.name.clone(),
+            content,
+            nonce,
+            content_type: "password".to_string(),
+            revision: None,
         }
-    });
+    }
+
+    #[test]
+    fn entries_without_an_id_inside_get_the_outer_one() {
+        let mut vault_entry = VaultEntry::Password(create_password_entry("mail", "", "", "", ""));
+        *vault_entry.id_mut() = String::new();
+
+        let encrypted_entry = EncryptedDataEntry {
+            id: "a".to_string(),
+            name: "mail".to_string(),
+            content: Vec::new(),
+            nonce: Vec::new(),
+            content_type: "password".to_string(),
+            revision: None,
+        };
+
+        assert!(assign_entry_id(&mut vault_entry, &encrypted_entry).unwrap());
+        assert_eq!(vault_entry.id(), "a");
+        assert!(!assign_entry_id(&mut vault_entry, &encrypted_entry).unwrap());
+    }
 
-    encrypted_entries.into_inner().unwrap()
+    #[test]
+    fn moved_entries_are_reported_and_not_uploaded_again() {
+        let vault_key = generate_vault_key();
+        let mut data_vault = DataVault::new("user@example.com", None, &vault_key).unwrap();
+
+        let password = create_password_entry("mail", "", "", "", "");
+        let encrypted_entry = encrypted(&password, &password.id, &data_vault.ciphers);
+        let moved_entry = encrypted(&password, "other", &data_vault.ciphers);
+
+        let (migrated_entries, failed_entries) = data_vault.fill(&[encrypted_entry]);
+        assert!(migrated_entries.is_empty());
+        assert!(failed_entries.is_empty());
+        assert!(data_vault.integrity_warnings.is_empty());
+        assert_eq!(data_vault.entries_vault.entries.len(), 1);
+
+        let mut data_vault = DataVault::new("user@example.com", None, &vault_key).unwrap();
+        let (migrated_entries, failed_entries) =
+            data_vault.fill(std::slice::from_ref(&moved_entry));
+
+        assert!(migrated_entries.is_empty());
+        assert!(failed_entries.is_empty());
+        assert_eq!(data_vault.integrity_warnings.len(), 1);
+        assert!(data_vault.entries_vault.entries.is_empty());
+
+        assert!(matches!(
+            store_vault_entry(&mut data_vault, &moved_entry),
+            Err(Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn pending_vault_keys_cannot_pass_for_the_protected_one() {
+        let vault_key = generate_vault_key();
+        let master_cipher = generate_vault_cipher(&generate_vault_key(), "master").unwrap();
+
+        let entries = vec![create_pending_vault_key_entry(&vault_key, &master_cipher).unwrap()];
+        assert!(find_protected_vault_key_entry(&entries).is_none());
+        assert!(is_reserved_content_type(PENDING_VAULT_KEY_CONTENT_TYPE));
+
+        let pending_vault_key = find_pending_vault_key_entry(&entries).unwrap();
+        let unwrapped = unwrap_vault_key(pending_vault_key, &master_cipher).unwrap();
+        assert_eq!(unwrapped.as_slice(), vault_key.as_slice());
+
+        let mut relabelled = pending_vault_key.clone();
+        relabelled.name = VAULT_KEY_CONTENT_TYPE.to_string();
+        relabelled.content_type = VAULT_KEY_CONTENT_TYPE.to_string();
+        assert!(unwrap_vault_key(&relabelled, &master_cipher).is_err());
+    }
 }
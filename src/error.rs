@@ -0,0 +1,114 @@
+use std::fmt;
+
+// Shared by every layer so the GUI can tell a network failure from a rejected request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // The request never got a response
+    Network(String),
+    // The server answered with an error
+    Server { status: u16, message: String },
+    // The session is missing or expired and the user has to log in again
+    Unauthorized(String),
+    // A response or a decrypted entry could not be parsed
+    Decode(String),
+    // Key derivation, encryption or decryption failed
+    Crypto(String),
+    // Input rejected before anything was sent
+    Validation(String),
+    // The server certificate was rejected, by the pin or the trusted authorities
+    Tls(String),
+    Totp(String),
+    // A local file could not be read or written
+    Storage(String),
+    // The user stopped the operation before it finished
+    Cancelled,
+}
+
+impl Error {
+    // Error responses carry the HTTP status, 401 meaning the session is gone
+    pub fn from_server(status: reqwest::StatusCode, message: String) -> Error {
+        if status == reqwest::StatusCode::UNAUTHORIZED {
+            Error::Unauthorized(message)
+        } else {
+            Error::Server {
+                status: status.as_u16(),
+                message,
+            }
+        }
+    }
+
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, Error::Unauthorized(_))
+    }
+
+    // Failures on the way to the server and answers from an overloaded or restarting server
+    // are worth sending again as is
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::Network(_)
+                | Error::Server {
+                    status: 429 | 502 | 503 | 504,
+                    ..
+                }
+        )
+    }
+
+    // The entry changed on the server since the revision the change was made against
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, Error::Server { status: 409, .. })
+    }
+
+    // The server answered and refused the request, so nothing changed there. Without an answer
+    // the request may still have been applied
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self,
+            Error::Unauthorized(_)
+                | Error::Server {
+                    status: 400..=499,
+                    ..
+                }
+        )
+    }
+
+    // Keeps the variant while adding what was being done when it failed
+    pub fn context(self, context: &str) -> Error {
+        match self {
+            Error::Network(message) => Error::Network(format!("{}: {}", context, message)),
+            Error::Server { status, message } => Error::Server {
+                status,
+                message: format!("{}: {}", context, message),
+            },
+            Error::Unauthorized(message) => {
+                Error::Unauthorized(format!("{}: {}", context, message))
+            }
+            Error::Decode(message) => Error::Decode(format!("{}: {}", context, message)),
+            Error::Crypto(message) => Error::Crypto(format!("{}: {}", context, message)),
+            Error::Validation(message) => Error::Validation(format!("{}: {}", context, message)),
+            Error::Tls(message) => Error::Tls(format!("{}: {}", context, message)),
+            Error::Totp(message) => Error::Totp(format!("{}: {}", context, message)),
+            Error::Storage(message) => Error::Storage(format!("{}: {}", context, message)),
+            Error::Cancelled => Error::Cancelled,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(message) => write!(f, "Network error: {}", message),
+            Error::Server { message, .. } => write!(f, "{}", message),
+            Error::Unauthorized(message) => write!(f, "{}", message),
+            Error::Decode(message) => write!(f, "{}", message),
+            Error::Crypto(message) => write!(f, "{}", message),
+            Error::Validation(message) => write!(f, "{}", message),
+            Error::Tls(message) => write!(f, "Server certificate rejected: {}", message),
+            Error::Totp(message) => write!(f, "{}", message),
+            Error::Storage(message) => write!(f, "{}", message),
+            Error::Cancelled => write!(f, "Operation cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,406 +1,2722 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::fmt;
 
-use relm4::ComponentController;
+use zeroize::Zeroizing;
 
-use super::add_entry_prompt::AddEntryPrompt;
-use super::auth_prompt::AuthPrompt;
-use super::auth_response_dialog::AuthResponseDialogMsg;
 use super::entry_list_item::EntryListItem;
-use crate::gui::add_entry_response_dialog::AddEntryResponseDialogMsg;
-use crate::gui::entry_list_item::EntryType;
-use crate::model::DataVault;
+use crate::cache::{apply_entry_changes, update_vault_cache, VaultCache};
+use crate::constraints;
+use crate::encryption::{
+    are_kdf_params_outdated, generate_all_master_ciphers, generate_all_vault_ciphers,
+    generate_kdf_params, generate_master_keys, generate_recovery_code, generate_recovery_keys,
+    generate_vault_cipher, generate_vault_key, is_outdated_data_entry, may_predate_vault_key,
+    unwrap_vault_key,
+};
+use crate::error::Error;
+use crate::manifest::{
+    create_manifest, decrypt_manifest, encrypt_manifest, find_manifest_entry, rebase_manifest,
+    record_manifest_entry, remember_manifest, remove_manifest_entry, verify_manifest,
+    ManifestAnchor, MANIFEST_CONTENT_TYPE,
+};
+use crate::model::{
+    entry_key, generate_idempotency_key, Ciphers, DataVault, DeletedDataEntry, EncryptedDataEntry,
+    EncryptedDataEntryChanges, EntriesVault, KdfParams, Manifest, MasterKeys, SimpleResponse,
+    VaultCipher,
+};
+use crate::outbox::{Outbox, QueuedChange, SyncConflict};
+use crate::recovery::{
+    create_recovery_entries, decrypt_recovery_code_entry, find_recovery_code_entry,
+    find_recovery_key_entry, generate_emergency_kit,
+};
 use crate::requests::*;
-use crate::{entries::*, AppState};
+use crate::vault_entry::{EntryData, VaultEntry};
+use crate::{entries::*, AppState, Connection};
+
+// Functions ending in _action run on a background thread and only see the connection,
+// the vault itself is read and updated on the GTK thread before and after them
 
 pub fn login_action(
     email: &str,
     password: &str,
-    auth_prompt: &mut AuthPrompt,
-) -> Result<(), String> {
-    let mut app_state = auth_prompt.app_state.borrow_mut();
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<DataVault, Error> {
+    cancellation.check()?;
+
+    // Accounts without key derivation settings on the server still use the legacy derivation
+    let prelogin = match prelogin_request(
+        email,
+        &connection.client,
+        &connection.base_url,
+        cancellation,
+    ) {
+        Ok(prelogin_response) => prelogin_response.data,
+        Err(e) if e.is_retryable() => {
+            return open_offline_vault(email, password, connection, cancellation, e)
+        }
+        Err(e) => return Err(e.context("Login failed")),
+    };
+    let kdf_params = prelogin.kdf_params;
+
+    let master_keys = match authenticate(
+        email,
+        password,
+        kdf_params.as_ref(),
+        prelogin.is_legacy_account,
+        connection,
+        cancellation,
+    ) {
+        Ok(master_keys) => master_keys,
+        Err(e) if e.is_retryable() => {
+            return open_offline_vault(email, password, connection, cancellation, e)
+        }
+        Err(e) => return Err(e.context("Login failed")),
+    };
+
+    // A login cancelled after the server accepted it still has to end the session
+    let result = unlock_vault(
+        email,
+        password,
+        kdf_params.as_ref(),
+        &master_keys,
+        connection,
+        cancellation,
+    )
+    .and_then(|data_vault| cancellation.check().map(|()| data_vault));
+
+    let mut data_vault = match result {
+        Ok(data_vault) => data_vault,
+        Err(e) => {
+            logout_request(&connection.client, &connection.base_url).unwrap_or_default();
+
+            return Err(e.context("Failed to unlock vault"));
+        }
+    };
+
+    if are_kdf_params_outdated(data_vault.kdf_params.as_ref()) {
+        if let Err(e) = upgrade_kdf_params(password, &mut data_vault, &master_keys, connection) {
+            println!("Failed to upgrade key derivation settings: {}", e);
+        }
+    }
+
+    Ok(data_vault)
+}
+
+// Opens the copy saved by the last online login when the server cannot be reached, the master
+// password is checked by unwrapping the cached vault key and nothing can be changed
+fn open_offline_vault(
+    email: &str,
+    password: &str,
+    connection: &Connection,
+    cancellation: &Cancellation,
+    network_error: Error,
+) -> Result<DataVault, Error> {
+    if (!constraints::is_email_valid(email)) || (!constraints::is_password_valid(password)) {
+        return Err(Error::Validation("Invalid email or password".to_string()));
+    }
+
+    let vault_cache = match VaultCache::load(&connection.base_url, email) {
+        Ok(vault_cache) => vault_cache,
+        Err(e) => {
+            println!("No offline copy to open: {}", e);
+
+            return Err(network_error.context("Login failed"));
+        }
+    };
+
+    let master_keys = generate_master_keys(email, password, vault_cache.kdf_params.as_ref())?;
+
+    cancellation.check()?;
+
+    let vault_key = match find_protected_vault_key_entry(&vault_cache.entries) {
+        Some(protected_vault_key) => {
+            match unwrap_vault_key(protected_vault_key, &master_keys.vault_key_cipher) {
+                Ok(vault_key) => vault_key,
+                Err(_) => return Err(Error::Validation("Invalid email or password".to_string())),
+            }
+        }
+        None => {
+            return Err(Error::Decode(
+                "Failed to find the protected vault key in the offline copy".to_string(),
+            ))
+        }
+    };
+
+    // Migrations are redone in memory and uploaded by the next online login
+    let (mut data_vault, _, _) = decrypt_vault(
+        email,
+        password,
+        vault_cache.kdf_params.as_ref(),
+        &vault_key,
+        vault_cache.entries,
+        &connection.base_url,
+    )?;
+
+    println!("Opened offline copy synced at {}", vault_cache.synced_at);
+
+    data_vault.offline_synced_at = Some(vault_cache.synced_at);
+    data_vault.sync_cursor = vault_cache.cursor;
+
+    Ok(data_vault)
+}
+
+// Starts a new session for the unlocked vault, the entries in memory are left as they are
+pub fn reauthenticate_action(
+    email: &str,
+    password: &str,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<(), Error> {
+    cancellation.check()?;
+
+    let prelogin = match prelogin_request(
+        email,
+        &connection.client,
+        &connection.base_url,
+        cancellation,
+    ) {
+        Ok(prelogin_response) => prelogin_response.data,
+        Err(e) => return Err(e.context("Login failed")),
+    };
+
+    authenticate(
+        email,
+        password,
+        prelogin.kdf_params.as_ref(),
+        prelogin.is_legacy_account,
+        connection,
+        cancellation,
+    )
+    .map(|_| ())
+    .map_err(|e| e.context("Login failed"))
+}
+
+// Derives the master keys and logs in with the authentication hash
+fn authenticate(
+    email: &str,
+    password: &str,
+    kdf_params: Option<&KdfParams>,
+    is_legacy_account: bool,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<MasterKeys, Error> {
+    if (!constraints::is_email_valid(email)) || (!constraints::is_password_valid(password)) {
+        return Err(Error::Validation("Invalid email or password".to_string()));
+    }
+
+    let master_keys = generate_master_keys(email, password, kdf_params)?;
+
+    cancellation.check()?;
+
+    let response = match login_request(
+        email,
+        &master_keys.auth_hash,
+        &connection.client,
+        &connection.base_url,
+    ) {
+        Ok(response) => response,
+        // Only when the server says the account predates the authentication hash
+        Err(e) if is_legacy_account && !e.is_retryable() => {
+            migrate_legacy_account(email, password, &master_keys, connection).map_err(|_| e)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    match response {
+        Some(response) => println!("Login successful: {}", response.status),
+        None => println!("Login successful"),
+    }
+
+    Ok(master_keys)
+}
+
+// Unwraps the vault key, decrypts every entry and finishes any pending migration
+fn unlock_vault(
+    email: &str,
+    password: &str,
+    kdf_params: Option<&KdfParams>,
+    master_keys: &MasterKeys,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<DataVault, Error> {
+    let (encrypted_entries, sync_cursor) =
+        fetch_encrypted_entries(email, connection, cancellation)?;
+
+    // Kept for logins while the server cannot be reached, and as the start of the next delta
+    if let Err(e) = VaultCache::new(
+        email,
+        kdf_params.cloned(),
+        encrypted_entries.clone(),
+        sync_cursor,
+    )
+    .save(&connection.base_url)
+    {
+        println!("Failed to save offline copy: {}", e);
+    }
+
+    let master_cipher = &master_keys.vault_key_cipher;
+
+    let vault_key = match find_protected_vault_key_entry(&encrypted_entries) {
+        Some(protected_vault_key) => {
+            let vault_key = match find_pending_vault_key_entry(&encrypted_entries) {
+                Some(pending_vault_key) => settle_pending_vault_key(
+                    email,
+                    protected_vault_key,
+                    pending_vault_key,
+                    master_cipher,
+                    connection,
+                )?,
+                None => unwrap_vault_key(protected_vault_key, master_cipher)?,
+            };
+
+            // Rewrap vault keys stored in an older envelope so they get the associated data
+            if is_outdated_data_entry(protected_vault_key) {
+                if let Err(e) = update_protected_vault_key(&vault_key, master_cipher, connection) {
+                    println!("Failed to migrate vault key: {}", e);
+                }
+            }
+
+            vault_key
+        }
+        // Accounts created before the vault key existed get one on their first login
+        None => upload_new_vault_key(master_cipher, connection, cancellation)?,
+    };
+
+    let (mut data_vault, migrated_entries, is_manifest_outdated) = decrypt_vault(
+        email,
+        password,
+        kdf_params,
+        &vault_key,
+        encrypted_entries,
+        &connection.base_url,
+    )?;
+
+    data_vault.sync_cursor = sync_cursor;
+
+    let stored_entries = upload_migrated_entries(migrated_entries, &mut data_vault, connection);
+
+    if !stored_entries.is_empty() {
+        update_vault_cache(&connection.base_url, email, |vault_cache| {
+            for stored_entry in stored_entries.iter() {
+                vault_cache.remove_entry(&entry_key(
+                    "",
+                    &stored_entry.name,
+                    &stored_entry.content_type,
+                ));
+                vault_cache.store_entry(stored_entry);
+            }
+        });
+    }
+
+    if is_manifest_outdated {
+        match encrypt_data_vault_manifest(&data_vault.vault_key, &data_vault.manifest)
+            .and_then(|encrypted_manifest| upload_manifest(&encrypted_manifest, connection))
+        {
+            Ok(()) => remember_manifest(&connection.base_url, email, &data_vault.manifest),
+            Err(e) => println!("Failed to update vault manifest: {}", e),
+        }
+    }
+
+    Ok(data_vault)
+}
+
+// What the server has beyond a cursor
+#[derive(Debug)]
+pub enum RemoteChanges {
+    Delta(EncryptedDataEntryChanges),
+    // The whole vault, when there is no cursor to start from or the server no longer knows it
+    Full {
+        entries: Vec<EncryptedDataEntry>,
+        cursor: Option<u64>,
+    },
+}
+
+fn fetch_remote_changes(
+    cursor: Option<u64>,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<RemoteChanges, Error> {
+    if let Some(cursor) = cursor {
+        match get_encrypted_data_entry_changes_request(
+            cursor,
+            &connection.client,
+            &connection.base_url,
+            cancellation,
+        ) {
+            Ok(response) => return Ok(RemoteChanges::Delta(response.data)),
+            // Anything but a lost session or connection is solved by downloading everything
+            Err(e) if e.is_unauthorized() || e.is_retryable() || e == Error::Cancelled => {
+                return Err(e)
+            }
+            Err(e) => println!("Delta sync unavailable, fetching all entries: {}", e),
+        }
+    }
+
+    let response = get_all_encrypted_data_entries_request(
+        &connection.client,
+        &connection.base_url,
+        cancellation,
+    )?;
+
+    Ok(RemoteChanges::Full {
+        entries: response.data,
+        cursor: response.cursor,
+    })
+}
+
+// Starts from the offline copy when it has a cursor, so only what changed since is downloaded
+fn fetch_encrypted_entries(
+    email: &str,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<(Vec<EncryptedDataEntry>, Option<u64>), Error> {
+    let (mut entries, cursor) = match VaultCache::load(&connection.base_url, email) {
+        Ok(vault_cache) => (vault_cache.entries, vault_cache.cursor),
+        Err(_) => (Vec::new(), None),
+    };
+
+    match fetch_remote_changes(cursor, connection, cancellation)? {
+        RemoteChanges::Delta(changes) => {
+            apply_entry_changes(&mut entries, &changes);
+
+            Ok((entries, Some(changes.cursor)))
+        }
+        RemoteChanges::Full { entries, cursor } => Ok((entries, cursor)),
+    }
+}
+
+// Checks the entries against the manifest and decrypts them with the queued changes on top,
+// returning the entries that have to be uploaded again re-encrypted and whether the manifest
+// has to be uploaded again
+fn decrypt_vault(
+    email: &str,
+    password: &str,
+    kdf_params: Option<&KdfParams>,
+    vault_key: &[u8],
+    mut encrypted_entries: Vec<EncryptedDataEntry>,
+    server_url: &str,
+) -> Result<(DataVault, Vec<EncryptedDataEntry>, bool), Error> {
+    let manifest_cipher = generate_vault_cipher(vault_key, MANIFEST_CONTENT_TYPE)?;
+
+    let anchor = match ManifestAnchor::load(server_url, email) {
+        Ok(anchor) => anchor,
+        Err(e) => {
+            println!("Failed to load manifest anchor: {}", e);
+            None
+        }
+    };
+
+    let IntegrityCheck {
+        mut manifest,
+        mut rebased_manifest,
+        warnings: integrity_warnings,
+        mut is_manifest_outdated,
+    } = check_vault_integrity(&encrypted_entries, &manifest_cipher, anchor.as_ref());
+
+    for warning in integrity_warnings.iter() {
+        println!("Vault integrity warning: {}", warning);
+    }
+
+    // Only a manifest that checked out moves the anchor, a created one once it is uploaded
+    if integrity_warnings.is_empty() && !is_manifest_outdated {
+        remember_manifest(server_url, email, &manifest);
+    }
+
+    let outbox = load_outbox(server_url, email);
+
+    let mut data_vault = DataVault::new(email, kdf_params.cloned(), vault_key)?;
+
+    for encrypted_entry in encrypted_entries.iter() {
+        data_vault.set_revision(encrypted_entry);
+    }
+
+    // The queued changes are not on the server, so they only go on top after the check
+    if let Some(rebased_manifest) = rebased_manifest.as_mut() {
+        outbox.apply_to(&mut encrypted_entries.clone(), rebased_manifest);
+    }
+    outbox.apply_to(&mut encrypted_entries, &mut manifest);
+
+    data_vault.manifest = manifest;
+    data_vault.rebased_manifest = rebased_manifest;
+    data_vault.integrity_warnings = integrity_warnings;
+    data_vault.outbox = outbox;
+
+    let (mut migrated_entries, failed_entries) = data_vault.fill(&encrypted_entries);
+
+    // Entries still encrypted with the ciphers derived straight from the master password. Deriving
+    // those takes an Argon2 run per content type, so an entry written with the vault key that
+    // fails to decrypt does not get to slow down every login
+    let (legacy_entries, failed_entries): (Vec<_>, Vec<_>) =
+        failed_entries.into_iter().partition(may_predate_vault_key);
+
+    let mut unreadable_entries = failed_entries;
+
+    if !legacy_entries.is_empty() {
+        let legacy_ciphers = generate_all_master_ciphers(email, password)?;
+
+        let (mut legacy_migrated_entries, legacy_failed_entries) =
+            fill_data_vault_from_legacy_entries(&mut data_vault, &legacy_entries, &legacy_ciphers);
+
+        migrated_entries.append(&mut legacy_migrated_entries);
+        unreadable_entries.extend(legacy_failed_entries);
+    }
+
+    // An entry that cannot be decrypted may have been tampered with, so it is not just left out
+    for unreadable_entry in unreadable_entries {
+        println!("Failed to decrypt entry: {}", unreadable_entry.name);
+
+        data_vault.integrity_warnings.push(format!(
+            "The entry {} could not be decrypted",
+            unreadable_entry.name
+        ));
+    }
+
+    is_manifest_outdated |= !migrated_entries.is_empty();
+
+    Ok((data_vault, migrated_entries, is_manifest_outdated))
+}
+
+// A damaged outbox is reported and replaced by the next queued change
+fn load_outbox(server_url: &str, email: &str) -> Outbox {
+    match Outbox::load(server_url, email) {
+        Ok(outbox) => outbox,
+        Err(e) => {
+            println!("Failed to load queued changes: {}", e);
+
+            Outbox::new(email)
+        }
+    }
+}
+
+fn upload_new_vault_key(
+    master_cipher: &VaultCipher,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let vault_key = generate_vault_key();
+
+    let protected_vault_key = create_protected_vault_key_entry(&vault_key, master_cipher)?;
+
+    add_encrypted_data_entry_request(
+        &protected_vault_key,
+        &generate_idempotency_key(),
+        &connection.client,
+        &connection.base_url,
+        cancellation,
+    )?;
+
+    Ok(vault_key)
+}
+
+// Re-derives the master keys with the current default settings
+fn upgrade_kdf_params(
+    password: &str,
+    data_vault: &mut DataVault,
+    current_master_keys: &MasterKeys,
+    connection: &Connection,
+) -> Result<(), Error> {
+    let new_kdf_params = generate_kdf_params();
+    let new_master_keys = generate_master_keys(&data_vault.email, password, Some(&new_kdf_params))?;
+
+    // Keeps the vault key readable with the current master key until the server answers
+    upload_pending_vault_key(
+        &data_vault.vault_key,
+        &current_master_keys.vault_key_cipher,
+        connection,
+    )?;
+
+    if let Err(e) = update_protected_vault_key(
+        &data_vault.vault_key,
+        &new_master_keys.vault_key_cipher,
+        connection,
+    ) {
+        // Without an answer the new wrapping may be stored, so the pending one has to stay
+        if e.is_rejected() {
+            remove_pending_vault_key(&data_vault.email, connection);
+        }
+
+        return Err(e);
+    }
+
+    match change_password_request(
+        &current_master_keys.auth_hash,
+        &new_master_keys.auth_hash,
+        Some(&new_kdf_params),
+        &connection.client,
+        &connection.base_url,
+    ) {
+        Ok(_) => {}
+        // Put back the vault key wrapped by the master key the server still expects
+        Err(e) if e.is_rejected() => {
+            match update_protected_vault_key(
+                &data_vault.vault_key,
+                &current_master_keys.vault_key_cipher,
+                connection,
+            ) {
+                Ok(()) => remove_pending_vault_key(&data_vault.email, connection),
+                Err(e) => println!("Failed to restore vault key: {}", e),
+            }
+
+            return Err(e);
+        }
+        // The server may have taken the new settings, both wrappings stay until the next login
+        // shows which master key it expects
+        Err(e) => return Err(e),
+    }
+
+    remove_pending_vault_key(&data_vault.email, connection);
+
+    println!("Upgraded key derivation settings");
+
+    data_vault.kdf_params = Some(new_kdf_params);
+
+    Ok(())
+}
+
+// A password change the server did not answer leaves the vault key wrapped by both master keys,
+// the one that just logged in tells which wrapping the server still matches
+fn settle_pending_vault_key(
+    email: &str,
+    protected_vault_key: &EncryptedDataEntry,
+    pending_vault_key: &EncryptedDataEntry,
+    master_cipher: &VaultCipher,
+    connection: &Connection,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let vault_key = match unwrap_vault_key(protected_vault_key, master_cipher) {
+        Ok(vault_key) => vault_key,
+        Err(e) => {
+            let vault_key = match unwrap_vault_key(pending_vault_key, master_cipher) {
+                Ok(vault_key) => vault_key,
+                Err(_) => return Err(e),
+            };
+
+            // The server refused the change, the pending wrapping is kept until this succeeds
+            if let Err(e) = update_protected_vault_key(&vault_key, master_cipher, connection) {
+                println!("Failed to restore vault key: {}", e);
+
+                return Ok(vault_key);
+            }
+
+            update_vault_cache(&connection.base_url, email, |vault_cache| {
+                if let Ok(protected_vault_key) =
+                    create_protected_vault_key_entry(&vault_key, master_cipher)
+                {
+                    vault_cache.store_entry(&protected_vault_key);
+                }
+            });
+
+            vault_key
+        }
+    };
+
+    remove_pending_vault_key(email, connection);
+
+    Ok(vault_key)
+}
+
+// Stores the pending wrapping, replacing one left over by an earlier change
+fn upload_pending_vault_key(
+    vault_key: &[u8],
+    master_cipher: &VaultCipher,
+    connection: &Connection,
+) -> Result<(), Error> {
+    let pending_vault_key = create_pending_vault_key_entry(vault_key, master_cipher)?;
+
+    if update_encrypted_data_entry_request(
+        PENDING_VAULT_KEY_CONTENT_TYPE,
+        &pending_vault_key,
+        None,
+        &generate_idempotency_key(),
+        &connection.client,
+        &connection.base_url,
+        &Cancellation::default(),
+    )
+    .is_err()
+    {
+        add_encrypted_data_entry_request(
+            &pending_vault_key,
+            &generate_idempotency_key(),
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        )?;
+    }
+
+    Ok(())
+}
+
+// A leftover is removed by the next login, so failing here is not an error
+fn remove_pending_vault_key(email: &str, connection: &Connection) {
+    if let Err(e) = delete_encrypted_data_entry_request(
+        "",
+        PENDING_VAULT_KEY_CONTENT_TYPE,
+        PENDING_VAULT_KEY_CONTENT_TYPE,
+        None,
+        &generate_idempotency_key(),
+        &connection.client,
+        &connection.base_url,
+        &Cancellation::default(),
+    ) {
+        println!("Failed to remove pending vault key: {}", e);
+        return;
+    }
+
+    update_vault_cache(&connection.base_url, email, |vault_cache| {
+        vault_cache.remove_entry(&entry_key(
+            "",
+            PENDING_VAULT_KEY_CONTENT_TYPE,
+            PENDING_VAULT_KEY_CONTENT_TYPE,
+        ));
+    });
+}
+
+fn update_protected_vault_key(
+    vault_key: &[u8],
+    master_cipher: &VaultCipher,
+    connection: &Connection,
+) -> Result<(), Error> {
+    let protected_vault_key = create_protected_vault_key_entry(vault_key, master_cipher)?;
+
+    update_encrypted_data_entry_request(
+        VAULT_KEY_CONTENT_TYPE,
+        &protected_vault_key,
+        None,
+        &generate_idempotency_key(),
+        &connection.client,
+        &connection.base_url,
+        &Cancellation::default(),
+    )?;
+
+    Ok(())
+}
+
+// Logs in with the raw master password and replaces it on the server with the authentication hash
+fn migrate_legacy_account(
+    email: &str,
+    password: &str,
+    master_keys: &MasterKeys,
+    connection: &Connection,
+) -> Result<Option<SimpleResponse>, Error> {
+    let response = legacy_login_request(email, password, &connection.client, &connection.base_url)?;
+
+    change_password_request(
+        password,
+        &master_keys.auth_hash,
+        None,
+        &connection.client,
+        &connection.base_url,
+    )?;
+
+    println!("Migrated legacy account to authentication hash");
+
+    Ok(response)
+}
+
+// Returns the emergency kit to show when a recovery key was created
+pub fn register_action(
+    email: &str,
+    password1: &str,
+    password2: &str,
+    create_recovery_key: bool,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<Option<Zeroizing<String>>, Error> {
+    let recovery_code = if create_recovery_key {
+        Some(generate_recovery_code())
+    } else {
+        None
+    };
+
+    let master_keys = match register(
+        email,
+        password1,
+        password2,
+        recovery_code.as_deref().map(String::as_str),
+        connection,
+        cancellation,
+    ) {
+        Ok(master_keys) => master_keys,
+        Err(e) => {
+            println!("Register failed: {}", e);
+
+            return Err(e);
+        }
+    };
+
+    // The emergency kit is only worth showing once the recovery entries are on the server
+    if let Err(e) = initialize_vault_key(
+        email,
+        &master_keys,
+        recovery_code.as_deref().map(String::as_str),
+        connection,
+    ) {
+        println!("Failed to initialize vault key: {}", e);
+
+        return Err(e.context("The account was created but setting up its vault failed"));
+    }
+
+    Ok(recovery_code
+        .map(|recovery_code| generate_emergency_kit(email, &connection.base_url, &recovery_code)))
+}
+
+fn register(
+    email: &str,
+    password1: &str,
+    password2: &str,
+    recovery_code: Option<&str>,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<MasterKeys, Error> {
+    if password1 != password2 {
+        return Err(Error::Validation("Passwords do not match".to_string()));
+    }
+
+    if (!constraints::is_email_valid(email)) || (!constraints::is_password_valid(password1)) {
+        return Err(Error::Validation("Invalid email or password".to_string()));
+    }
+
+    let kdf_params = generate_kdf_params();
+    let master_keys = generate_master_keys(email, password1, Some(&kdf_params))?;
+
+    let recovery_keys = match recovery_code {
+        Some(recovery_code) => Some(generate_recovery_keys(recovery_code)?),
+        None => None,
+    };
+
+    cancellation.check()?;
+
+    let response = register_request(
+        email,
+        &master_keys.auth_hash,
+        &kdf_params,
+        recovery_keys
+            .as_ref()
+            .map(|recovery_keys| recovery_keys.auth_hash.as_str()),
+        &connection.client,
+        &connection.base_url,
+    )?;
+
+    println!("Register successful: {}", response.status);
+
+    Ok(master_keys)
+}
+
+// Generates the vault key of a freshly registered account and stores it on the server,
+// along with its recovery copy when a recovery key was requested
+fn initialize_vault_key(
+    email: &str,
+    master_keys: &MasterKeys,
+    recovery_code: Option<&str>,
+    connection: &Connection,
+) -> Result<(), Error> {
+    login_request(
+        email,
+        &master_keys.auth_hash,
+        &connection.client,
+        &connection.base_url,
+    )?;
+
+    // The account exists by now, so its vault is set up even if the registration was cancelled
+    let result = upload_new_vault_key(
+        &master_keys.vault_key_cipher,
+        connection,
+        &Cancellation::default(),
+    )
+    .and_then(|vault_key| match recovery_code {
+        Some(recovery_code) => upload_recovery_entries(recovery_code, &vault_key, connection),
+        None => Ok(()),
+    });
+
+    logout_request(&connection.client, &connection.base_url).unwrap_or_default();
+
+    result
+}
+
+fn upload_recovery_entries(
+    recovery_code: &str,
+    vault_key: &[u8],
+    connection: &Connection,
+) -> Result<(), Error> {
+    for recovery_entry in create_recovery_entries(recovery_code, vault_key)? {
+        add_encrypted_data_entry_request(
+            &recovery_entry,
+            &generate_idempotency_key(),
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn recover_action(
+    email: &str,
+    recovery_code: &str,
+    new_password1: &str,
+    new_password2: &str,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<(), Error> {
+    match recover(
+        email,
+        recovery_code,
+        new_password1,
+        new_password2,
+        connection,
+        cancellation,
+    ) {
+        Ok(()) => {
+            println!("Account recovery successful");
+
+            Ok(())
+        }
+        Err(e) => {
+            println!("Account recovery failed: {}", e);
+
+            Err(e)
+        }
+    }
+}
+
+// Resets the master password with the recovery code and wraps the vault key with the new master key
+fn recover(
+    email: &str,
+    recovery_code: &str,
+    new_password1: &str,
+    new_password2: &str,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<(), Error> {
+    if new_password1 != new_password2 {
+        return Err(Error::Validation("Passwords do not match".to_string()));
+    }
+
+    if (!constraints::is_email_valid(email)) || (!constraints::is_password_valid(new_password1)) {
+        return Err(Error::Validation("Invalid email or password".to_string()));
+    }
+
+    let recovery_keys = generate_recovery_keys(recovery_code)?;
+
+    let new_kdf_params = generate_kdf_params();
+    let new_master_keys = generate_master_keys(email, new_password1, Some(&new_kdf_params))?;
+
+    cancellation.check()?;
+
+    recover_request(
+        email,
+        &recovery_keys.auth_hash,
+        &new_master_keys.auth_hash,
+        &new_kdf_params,
+        &connection.client,
+        &connection.base_url,
+    )?;
+
+    let result = rewrap_recovered_vault_key(&recovery_keys, &new_master_keys, connection);
+
+    logout_request(&connection.client, &connection.base_url).unwrap_or_default();
+
+    result
+}
+
+fn rewrap_recovered_vault_key(
+    recovery_keys: &MasterKeys,
+    new_master_keys: &MasterKeys,
+    connection: &Connection,
+) -> Result<(), Error> {
+    let encrypted_entries = get_all_encrypted_data_entries_request(
+        &connection.client,
+        &connection.base_url,
+        &Cancellation::default(),
+    )?
+    .data;
+
+    let vault_key = match find_recovery_key_entry(&encrypted_entries) {
+        Some(recovery_key_entry) => {
+            unwrap_vault_key(recovery_key_entry, &recovery_keys.vault_key_cipher)?
+        }
+        None => {
+            return Err(Error::Decode(
+                "Failed to find the recovery copy of the vault key".to_string(),
+            ))
+        }
+    };
+
+    update_protected_vault_key(&vault_key, &new_master_keys.vault_key_cipher, connection)
+}
+
+// Returns the entries the server accepted, entries that just got an id replace the ones known
+// by their name
+fn upload_migrated_entries(
+    migrated_entries: Vec<EncryptedDataEntry>,
+    data_vault: &mut DataVault,
+    connection: &Connection,
+) -> Vec<EncryptedDataEntry> {
+    let mut stored_entries = Vec::new();
+
+    for mut migrated_entry in migrated_entries {
+        let name = &migrated_entry.name;
+        let legacy_key = entry_key("", name, &migrated_entry.content_type);
+
+        match update_encrypted_data_entry_request(
+            name,
+            &migrated_entry,
+            None,
+            &generate_idempotency_key(),
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        ) {
+            Ok(response) => {
+                println!("Migrated legacy entry {}: {}", name, response.status);
+
+                remove_manifest_entry(&mut data_vault.manifest, &legacy_key);
+                record_manifest_entry(&mut data_vault.manifest, &migrated_entry);
+
+                migrated_entry.revision = response.data.revision;
+                data_vault.remove_revision(&legacy_key);
+                data_vault.set_revision(&migrated_entry);
+
+                stored_entries.push(migrated_entry);
+            }
+            Err(e) => println!("Failed to migrate legacy entry {}: {}", name, e),
+        }
+    }
+
+    stored_entries
+}
+
+// What checking the entries returned by the server against the manifest found
+struct IntegrityCheck {
+    manifest: Manifest,
+    // The manifest following the server state, only taken once the user accepts the warnings
+    rebased_manifest: Option<Manifest>,
+    warnings: Vec<String>,
+    // Set when the server has no manifest yet and this device never saw one either
+    is_manifest_outdated: bool,
+}
+
+// Checks the entries returned by the server against the manifest and the manifest against the
+// newest one this device saw
+fn check_vault_integrity(
+    encrypted_data_entries: &[EncryptedDataEntry],
+    manifest_cipher: &VaultCipher,
+    anchor: Option<&ManifestAnchor>,
+) -> IntegrityCheck {
+    let mut warnings = Vec::new();
+
+    let manifest = match find_manifest_entry(encrypted_data_entries) {
+        Some(encrypted_manifest) => match decrypt_manifest(encrypted_manifest, manifest_cipher) {
+            Ok(manifest) => Some(manifest),
+            Err(e) => {
+                warnings.push(format!("Failed to read the vault manifest: {}", e));
+                None
+            }
+        },
+        None => {
+            if anchor.is_some() {
+                warnings.push("The vault manifest is missing".to_string());
+            }
+            None
+        }
+    };
+
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => {
+            let manifest = create_manifest(encrypted_data_entries);
+
+            // Vaults created before the manifest existed start by trusting the current server state
+            if warnings.is_empty() {
+                return IntegrityCheck {
+                    manifest,
+                    rebased_manifest: None,
+                    warnings,
+                    is_manifest_outdated: true,
+                };
+            }
+
+            return IntegrityCheck {
+                manifest: manifest.clone(),
+                rebased_manifest: Some(manifest),
+                warnings,
+                is_manifest_outdated: false,
+            };
+        }
+    };
+
+    if let Some(warning) = anchor.and_then(|anchor| anchor.check(&manifest)) {
+        warnings.push(warning);
+    }
+
+    warnings.append(&mut verify_manifest(&manifest, encrypted_data_entries));
+
+    if warnings.is_empty() {
+        return IntegrityCheck {
+            manifest,
+            rebased_manifest: None,
+            warnings,
+            is_manifest_outdated: false,
+        };
+    }
+
+    // The manifest keeps flagging the server state until the user accepts it
+    let mut rebased_manifest = manifest.clone();
+    rebase_manifest(&mut rebased_manifest, encrypted_data_entries);
+
+    IntegrityCheck {
+        manifest,
+        rebased_manifest: Some(rebased_manifest),
+        warnings,
+        is_manifest_outdated: false,
+    }
+}
+
+// Takes the server state the integrity warnings were about as the new baseline, the manifest goes
+// out with the next sync and moves the anchor past the version that was flagged
+pub fn accept_server_state(app_state: &mut AppState) -> Result<(), Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    let mut manifest = match data_vault.rebased_manifest.take() {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+
+    let base_url = &app_state.connection.base_url;
+
+    match ManifestAnchor::load(base_url, &data_vault.email) {
+        Ok(Some(anchor)) if manifest.version <= anchor.version => {
+            manifest.version = anchor.version + 1;
+        }
+        Ok(_) => (),
+        Err(e) => println!("Failed to load manifest anchor: {}", e),
+    }
+
+    data_vault.manifest = manifest;
+    data_vault.outbox.is_manifest_outdated = true;
+
+    data_vault.outbox.save(base_url)
+}
+
+fn encrypt_data_vault_manifest(
+    vault_key: &[u8],
+    manifest: &Manifest,
+) -> Result<EncryptedDataEntry, Error> {
+    let manifest_cipher = generate_vault_cipher(vault_key, MANIFEST_CONTENT_TYPE)?;
+
+    encrypt_manifest(manifest, &manifest_cipher)
+}
+
+// Stores the manifest, creating the reserved entry the first time
+fn upload_manifest(
+    encrypted_manifest: &EncryptedDataEntry,
+    connection: &Connection,
+) -> Result<(), Error> {
+    if update_encrypted_data_entry_request(
+        MANIFEST_CONTENT_TYPE,
+        encrypted_manifest,
+        None,
+        &generate_idempotency_key(),
+        &connection.client,
+        &connection.base_url,
+        &Cancellation::default(),
+    )
+    .is_err()
+    {
+        add_encrypted_data_entry_request(
+            encrypted_manifest,
+            &generate_idempotency_key(),
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        )?;
+    }
+
+    Ok(())
+}
+
+// Prepared from the unlocked vault: the entries are re-encrypted under the new vault key up front,
+// so the background part only derives the master keys and talks to the server
+pub struct PasswordChange {
+    email: String,
+    current_kdf_params: Option<KdfParams>,
+    current_vault_key: Zeroizing<Vec<u8>>,
+    new_kdf_params: KdfParams,
+    new_vault_key: Zeroizing<Vec<u8>>,
+    new_ciphers: Ciphers,
+    new_manifest: Manifest,
+    replacement_entries: Vec<EncryptedDataEntry>,
+    // Unset when the server did not answer the change, the next login finds out which password
+    // it took
+    pub is_confirmed: bool,
+}
+
+impl fmt::Debug for PasswordChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordChange")
+            .field("email", &self.email)
+            .finish_non_exhaustive()
+    }
+}
+
+pub fn prepare_password_change(
+    new_password1: &str,
+    new_password2: &str,
+    app_state: &AppState,
+) -> Result<PasswordChange, Error> {
+    if new_password1 != new_password2 {
+        return Err(Error::Validation("Passwords do not match".to_string()));
+    }
+
+    if !constraints::is_password_valid(new_password1) {
+        return Err(Error::Validation("Invalid password".to_string()));
+    }
+
+    let data_vault = match app_state.vault.as_ref() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    // Every entry is uploaded again, which only works against the server state
+    if data_vault.is_offline() {
+        return Err(Error::Validation(
+            "The vault is offline, log in again once the server is reachable".to_string(),
+        ));
+    }
+
+    if !data_vault.outbox.is_empty() {
+        return Err(Error::Validation(
+            "Changes made offline are still waiting to be synced".to_string(),
+        ));
+    }
+
+    let new_vault_key = generate_vault_key();
+    let new_ciphers = generate_all_vault_ciphers(&new_vault_key)?;
+
+    let replacement_entries = encrypt_entry_vault(&data_vault.entries_vault, &new_ciphers)?;
+
+    let mut new_manifest = data_vault.manifest.clone();
+
+    for replacement_entry in replacement_entries.iter() {
+        record_manifest_entry(&mut new_manifest, replacement_entry);
+    }
+
+    Ok(PasswordChange {
+        email: data_vault.email.clone(),
+        current_kdf_params: data_vault.kdf_params.clone(),
+        current_vault_key: data_vault.vault_key.clone(),
+        new_kdf_params: generate_kdf_params(),
+        new_vault_key,
+        new_ciphers,
+        new_manifest,
+        replacement_entries,
+        is_confirmed: false,
+    })
+}
+
+// Rotates the vault key along with the master password: every entry is uploaded re-encrypted
+// with the new ciphers, and the server copies are restored if a step fails, unless the server may
+// have taken the new password
+pub fn change_password_action(
+    current_password: &str,
+    new_password: &str,
+    mut password_change: PasswordChange,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<PasswordChange, Error> {
+    match change_password(
+        current_password,
+        new_password,
+        &password_change,
+        connection,
+        cancellation,
+    ) {
+        Ok(is_confirmed) => {
+            if is_confirmed {
+                println!("Change password successful");
+            } else {
+                println!("Change password sent, the server did not confirm it");
+            }
+
+            password_change.is_confirmed = is_confirmed;
+
+            Ok(password_change)
+        }
+        Err(e) => {
+            println!("Change password failed: {}", e);
+
+            Err(e)
+        }
+    }
+}
+
+fn change_password(
+    current_password: &str,
+    new_password: &str,
+    password_change: &PasswordChange,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<bool, Error> {
+    // The current server state is both the proof of the current password and the rollback copy
+    let original_response = get_all_encrypted_data_entries_request(
+        &connection.client,
+        &connection.base_url,
+        cancellation,
+    )?;
+    let original_entries = original_response.data;
+
+    let current_master_keys = generate_master_keys(
+        &password_change.email,
+        current_password,
+        password_change.current_kdf_params.as_ref(),
+    )?;
+
+    match find_protected_vault_key_entry(&original_entries) {
+        Some(protected_vault_key) => {
+            if unwrap_vault_key(protected_vault_key, &current_master_keys.vault_key_cipher).is_err()
+            {
+                return Err(Error::Validation(
+                    "Current password is incorrect".to_string(),
+                ));
+            }
+        }
+        None => {
+            return Err(Error::Decode(
+                "Failed to find the protected vault key".to_string(),
+            ))
+        }
+    }
+
+    cancellation.check()?;
+
+    let new_master_keys = generate_master_keys(
+        &password_change.email,
+        new_password,
+        Some(&password_change.new_kdf_params),
+    )?;
+
+    let mut replacement_entries = password_change.replacement_entries.clone();
+
+    // The recovery copy has to follow the new vault key or the recovery code stops working
+    if let Some(recovery_code_entry) = find_recovery_code_entry(&original_entries) {
+        let recovery_code =
+            decrypt_recovery_code_entry(recovery_code_entry, &password_change.current_vault_key)?;
+
+        replacement_entries.append(&mut create_recovery_entries(
+            &recovery_code,
+            &password_change.new_vault_key,
+        )?);
+    }
+
+    replacement_entries.push(encrypt_data_vault_manifest(
+        &password_change.new_vault_key,
+        &password_change.new_manifest,
+    )?);
+
+    // The vault key goes last so that it never points to entries that were not uploaded
+    replacement_entries.push(create_protected_vault_key_entry(
+        &password_change.new_vault_key,
+        &new_master_keys.vault_key_cipher,
+    )?);
+
+    // Nothing has been changed on the server up to here
+    cancellation.check()?;
+
+    upload_replacement_entries(&replacement_entries, &original_entries, connection)?;
+
+    // Keeps the new vault key readable with the current password until the server answers
+    if let Err(e) = upload_pending_vault_key(
+        &password_change.new_vault_key,
+        &current_master_keys.vault_key_cipher,
+        connection,
+    ) {
+        restore_original_entries(&replacement_entries, &original_entries, connection);
+
+        return Err(e);
+    }
+
+    match change_password_request(
+        &current_master_keys.auth_hash,
+        &new_master_keys.auth_hash,
+        Some(&password_change.new_kdf_params),
+        &connection.client,
+        &connection.base_url,
+    ) {
+        Ok(_) => {}
+        // The server still expects the current password
+        Err(e) if e.is_rejected() => {
+            restore_original_entries(&replacement_entries, &original_entries, connection);
+            remove_pending_vault_key(&password_change.email, connection);
+
+            return Err(e);
+        }
+        // The entries are under the new vault key whichever password the server took, and the
+        // next login keeps the wrapping that matches it. The offline copy is left as it was
+        Err(e) => {
+            println!("Failed to confirm password change: {}", e);
+
+            return Ok(false);
+        }
+    }
+
+    remove_pending_vault_key(&password_change.email, connection);
+
+    // The old offline copy would still open with the old password
+    let mut vault_cache = VaultCache::new(
+        &password_change.email,
+        Some(password_change.new_kdf_params.clone()),
+        original_entries,
+        original_response.cursor,
+    );
+
+    for replacement_entry in replacement_entries.iter() {
+        vault_cache.store_entry(replacement_entry);
+    }
+
+    if let Err(e) = vault_cache.save(&connection.base_url) {
+        println!("Failed to save offline copy: {}", e);
+    }
+
+    Ok(true)
+}
+
+pub fn apply_password_change(
+    password_change: PasswordChange,
+    app_state: &mut AppState,
+) -> Result<(), Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    // The entries on the server use the new vault key even when the change was not confirmed
+    if password_change.is_confirmed {
+        data_vault.kdf_params = Some(password_change.new_kdf_params);
+    }
+    data_vault.vault_key = password_change.new_vault_key;
+    data_vault.ciphers = password_change.new_ciphers;
+    data_vault.manifest = password_change.new_manifest;
+
+    remember_manifest(
+        &app_state.connection.base_url,
+        &data_vault.email,
+        &data_vault.manifest,
+    );
+
+    // Every entry was uploaded again, the next refresh brings the new revisions
+    data_vault.revisions.clear();
+
+    Ok(())
+}
+
+// Uploads every replacement, restoring the ones already uploaded if one of them fails
+fn upload_replacement_entries(
+    replacement_entries: &[EncryptedDataEntry],
+    original_entries: &[EncryptedDataEntry],
+    connection: &Connection,
+) -> Result<(), Error> {
+    for (index, replacement_entry) in replacement_entries.iter().enumerate() {
+        if let Err(e) = update_encrypted_data_entry_request(
+            &replacement_entry.name,
+            replacement_entry,
+            None,
+            &generate_idempotency_key(),
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        ) {
+            restore_original_entries(&replacement_entries[..index], original_entries, connection);
+
+            return Err(e.context(&format!(
+                "Failed to upload entry {}",
+                replacement_entry.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn restore_original_entries(
+    replaced_entries: &[EncryptedDataEntry],
+    original_entries: &[EncryptedDataEntry],
+    connection: &Connection,
+) {
+    for replaced_entry in replaced_entries {
+        let original_entry = match original_entries
+            .iter()
+            .find(|original_entry| original_entry.key() == replaced_entry.key())
+        {
+            Some(original_entry) => original_entry,
+            None => {
+                println!("Failed to find original of entry {}", replaced_entry.name);
+                continue;
+            }
+        };
+
+        if let Err(e) = update_encrypted_data_entry_request(
+            &original_entry.name,
+            original_entry,
+            None,
+            &generate_idempotency_key(),
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        ) {
+            println!("Failed to restore entry {}: {}", original_entry.name, e);
+        }
+    }
+}
+
+// The window closes right away, the server is told in the background
+pub fn logout_action(connection: &Connection) {
+    let connection = connection.clone();
+
+    relm4::spawn_blocking(move || {
+        logout_request(&connection.client, &connection.base_url).unwrap_or_default();
+    });
+}
+
+// Encrypted on the GTK thread, uploaded in the background along with the updated manifest
+pub struct PendingEntry {
+    entry: VaultEntry,
+    encrypted_entry: EncryptedDataEntry,
+    manifest: Manifest,
+    encrypted_manifest: EncryptedDataEntry,
+    // Also queued with the entry, the attempt may have reached the server
+    idempotency_key: String,
+    // Set when the entry goes to the outbox instead of the server
+    is_queued: bool,
+    is_manifest_uploaded: bool,
+}
+
+impl fmt::Debug for PendingEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingEntry")
+            .field("content_type", &self.encrypted_entry.content_type)
+            .finish_non_exhaustive()
+    }
+}
+
+pub fn prepare_password_entry(
+    name: &str,
+    username: &str,
+    password: &str,
+    url: &str,
+    expiration_date: &str,
+    app_state: &AppState,
+) -> Result<PendingEntry, Error> {
+    let entry = create_password_entry(name, username, password, url, expiration_date);
+
+    prepare_entry(entry.into_vault_entry(), app_state)
+}
+
+pub fn prepare_note_entry(
+    name: &str,
+    content: &str,
+    app_state: &AppState,
+) -> Result<PendingEntry, Error> {
+    let entry = create_note_entry(name, content);
+
+    prepare_entry(entry.into_vault_entry(), app_state)
+}
+
+pub fn prepare_card_entry(
+    name: &str,
+    cardholder_name: &str,
+    card_number: &str,
+    security_code: &str,
+    expiration_date: &str,
+    app_state: &AppState,
+) -> Result<PendingEntry, Error> {
+    let entry = create_card_entry(
+        name,
+        cardholder_name,
+        card_number,
+        security_code,
+        expiration_date,
+    );
+
+    prepare_entry(entry.into_vault_entry(), app_state)
+}
+
+pub fn prepare_totp_entry(
+    name: &str,
+    algorithm: &str,
+    secret: &str,
+    digits: &str,
+    skew: &str,
+    period: &str,
+    app_state: &AppState,
+) -> Result<PendingEntry, Error> {
+    let (digits, skew, period) = parse_totp_settings(algorithm, digits, skew, period)?;
+
+    let entry = create_totp_entry(name, algorithm, secret, digits, skew, period);
+
+    prepare_entry(entry.into_vault_entry(), app_state)
+}
+
+// Checked the same way for a new entry and an edited one
+pub fn parse_totp_settings(
+    algorithm: &str,
+    digits: &str,
+    skew: &str,
+    period: &str,
+) -> Result<(usize, u8, u64), Error> {
+    if algorithm != "SHA1" && algorithm != "SHA256" && algorithm != "SHA512" {
+        return Err(Error::Validation("Invalid algorithm".to_string()));
+    }
+
+    let digits = match digits.parse::<usize>() {
+        Ok(digits) => digits,
+        Err(_) => return Err(Error::Validation("Invalid digits".to_string())),
+    };
+
+    let skew = match skew.parse::<u8>() {
+        Ok(skew) => skew,
+        Err(_) => return Err(Error::Validation("Invalid skew".to_string())),
+    };
+
+    let period = match period.parse::<u64>() {
+        Ok(period) => period,
+        Err(_) => return Err(Error::Validation("Invalid period".to_string())),
+    };
+
+    Ok((digits, skew, period))
+}
+
+fn prepare_entry(entry: VaultEntry, app_state: &AppState) -> Result<PendingEntry, Error> {
+    let data_vault = match app_state.vault.as_ref() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    let encrypted_entry = encrypt_vault_entry(&entry, &data_vault.ciphers)?;
+
+    let mut manifest = data_vault.manifest.clone();
+    record_manifest_entry(&mut manifest, &encrypted_entry);
+
+    let encrypted_manifest = encrypt_data_vault_manifest(&data_vault.vault_key, &manifest)?;
+
+    Ok(PendingEntry {
+        entry,
+        encrypted_entry,
+        manifest,
+        encrypted_manifest,
+        idempotency_key: generate_idempotency_key(),
+        // Queued changes go first, so that the server sees every change in order
+        is_queued: data_vault.is_offline() || !data_vault.outbox.is_empty(),
+        is_manifest_uploaded: false,
+    })
+}
+
+pub fn add_entry_action(
+    mut pending_entry: PendingEntry,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<PendingEntry, Error> {
+    cancellation.check()?;
+
+    if pending_entry.is_queued {
+        return Ok(pending_entry);
+    }
+
+    let content_type = &pending_entry.encrypted_entry.content_type;
+
+    match add_encrypted_data_entry_request(
+        &pending_entry.encrypted_entry,
+        &pending_entry.idempotency_key,
+        &connection.client,
+        &connection.base_url,
+        cancellation,
+    ) {
+        Ok(response) => {
+            println!("Add {} entry successful: {}", content_type, response.status);
+
+            pending_entry.encrypted_entry.revision = response.data.revision;
+        }
+        // Sent again by the next sync, which also asks the user about a conflict
+        Err(e) if e.is_retryable() || e.is_conflict() => {
+            println!("Queueing {} entry: {}", content_type, e);
+
+            pending_entry.is_queued = true;
+
+            return Ok(pending_entry);
+        }
+        Err(e) => {
+            println!("Add {} entry failed: {}", content_type, e);
+
+            return Err(e);
+        }
+    }
+
+    // The entry is stored by now, a stale manifest only shows up as a warning on the next login
+    match upload_manifest(&pending_entry.encrypted_manifest, connection) {
+        Ok(()) => pending_entry.is_manifest_uploaded = true,
+        Err(e) => println!("Failed to update vault manifest: {}", e),
+    }
+
+    Ok(pending_entry)
+}
+
+pub fn apply_new_entry(
+    pending_entry: PendingEntry,
+    app_state: &mut AppState,
+) -> Result<EntryListItem, Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    record_manifest_entry(&mut data_vault.manifest, &pending_entry.encrypted_entry);
+
+    if pending_entry.is_queued {
+        queue_change(
+            QueuedChange::Add {
+                entry: pending_entry.encrypted_entry.clone(),
+                idempotency_key: pending_entry.idempotency_key.clone(),
+            },
+            data_vault,
+            &app_state.connection,
+        );
+    } else {
+        data_vault.set_revision(&pending_entry.encrypted_entry);
+
+        update_vault_cache(
+            &app_state.connection.base_url,
+            &data_vault.email,
+            |vault_cache| {
+                vault_cache.store_entry(&pending_entry.encrypted_entry);
+                vault_cache.store_entry(&pending_entry.encrypted_manifest);
+            },
+        );
+
+        if pending_entry.is_manifest_uploaded {
+            remember_manifest(
+                &app_state.connection.base_url,
+                &data_vault.email,
+                &pending_entry.manifest,
+            );
+        }
+    }
+
+    let entry_list_item = EntryListItem::from_vault_entry(&pending_entry.entry);
+    data_vault.entries_vault.entries.push(pending_entry.entry);
+
+    Ok(entry_list_item)
+}
+
+// An edited entry, encrypted on the GTK thread and sent against the revision the edit started from
+pub struct PendingUpdate {
+    entry: VaultEntry,
+    // The name the server knows the entry by
+    old_name: String,
+    encrypted_entry: EncryptedDataEntry,
+    manifest: Manifest,
+    encrypted_manifest: EncryptedDataEntry,
+    base_revision: Option<u64>,
+    idempotency_key: String,
+    // Set when the change goes to the outbox instead of the server
+    is_queued: bool,
+    is_manifest_uploaded: bool,
+}
+
+impl fmt::Debug for PendingUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingUpdate")
+            .field("content_type", &self.encrypted_entry.content_type)
+            .field("base_revision", &self.base_revision)
+            .finish_non_exhaustive()
+    }
+}
+
+// The entry keeps its id, so renaming it only changes the name sent along
+pub fn prepare_update(
+    entry: VaultEntry,
+    base_revision: Option<u64>,
+    app_state: &AppState,
+) -> Result<PendingUpdate, Error> {
+    let data_vault = match app_state.vault.as_ref() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    if entry.name().trim().is_empty() {
+        return Err(Error::Validation("The name cannot be empty".to_string()));
+    }
+
+    // A refresh may have removed the entry while it was being edited
+    let old_name = match data_vault.entries_vault.get(entry.id()) {
+        Some(current_entry) => current_entry.name().to_string(),
+        None => {
+            return Err(Error::Validation(
+                "The entry was deleted in the meantime".to_string(),
+            ))
+        }
+    };
+
+    let encrypted_entry = encrypt_vault_entry(&entry, &data_vault.ciphers)?;
 
-    match login_request(email, password, &app_state.client, &app_state.base_url) {
+    let mut manifest = data_vault.manifest.clone();
+    record_manifest_entry(&mut manifest, &encrypted_entry);
+
+    let encrypted_manifest = encrypt_data_vault_manifest(&data_vault.vault_key, &manifest)?;
+
+    Ok(PendingUpdate {
+        entry,
+        old_name,
+        encrypted_entry,
+        manifest,
+        encrypted_manifest,
+        base_revision,
+        idempotency_key: generate_idempotency_key(),
+        is_queued: data_vault.is_offline() || !data_vault.outbox.is_empty(),
+        is_manifest_uploaded: false,
+    })
+}
+
+pub fn update_entry_action(
+    mut pending_update: PendingUpdate,
+    connection: &Connection,
+    cancellation: &Cancellation,
+) -> Result<PendingUpdate, Error> {
+    cancellation.check()?;
+
+    if pending_update.is_queued {
+        return Ok(pending_update);
+    }
+
+    let encrypted_entry = &pending_update.encrypted_entry;
+    let content_type = &encrypted_entry.content_type;
+
+    match update_encrypted_data_entry_request(
+        &pending_update.old_name,
+        encrypted_entry,
+        pending_update.base_revision,
+        &pending_update.idempotency_key,
+        &connection.client,
+        &connection.base_url,
+        cancellation,
+    ) {
         Ok(response) => {
-            println!("Login successful: {}", response.status);
+            println!(
+                "Update {} entry successful: {}",
+                content_type, response.status
+            );
 
-            let data_vault = match DataVault::new(email, password) {
-                Ok(data_vault) => data_vault,
-                Err(e) => {
-                    panic!("Error creating data vault: {}", e);
-                }
-            };
+            pending_update.encrypted_entry.revision = response.data.revision;
+        }
+        // A conflict is shown by the next sync, which fetches the version on the server. A
+        // cancelled update is not queued, the server never took it
+        Err(e) if e.is_retryable() || e.is_conflict() => {
+            cancellation.check()?;
 
-            app_state.vault = Some(data_vault);
+            println!("Queueing update of {}: {}", pending_update.old_name, e);
 
-            Ok(())
+            pending_update.is_queued = true;
+
+            return Ok(pending_update);
         }
         Err(e) => {
-            auth_prompt
-                .response_dialog
-                .emit(AuthResponseDialogMsg::LoginFail(e.to_string()));
+            println!("Update {} entry failed: {}", content_type, e);
 
-            Err(format!("Login failed: {}", e))
+            return Err(e);
         }
     }
-}
 
-pub fn register_action(
-    email: &str,
-    password1: &str,
-    password2: &str,
-    auth_prompt: &mut AuthPrompt,
-) {
-    let app_state = auth_prompt.app_state.borrow();
+    match upload_manifest(&pending_update.encrypted_manifest, connection) {
+        Ok(()) => pending_update.is_manifest_uploaded = true,
+        Err(e) => println!("Failed to update vault manifest: {}", e),
+    }
 
-    match register_request(
-        email,
-        password1,
-        password2,
-        &app_state.client,
-        &app_state.base_url,
-    ) {
-        Ok(response) => {
-            println!("Register successful: {}", response.status);
+    Ok(pending_update)
+}
 
-            auth_prompt
-                .response_dialog
-                .emit(AuthResponseDialogMsg::RegisterSuccess);
+pub fn apply_update(
+    pending_update: PendingUpdate,
+    app_state: &mut AppState,
+) -> Result<EntryListItem, Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
         }
-        Err(e) => {
-            println!("Register failed: {}", e);
+    };
 
-            auth_prompt
-                .response_dialog
-                .emit(AuthResponseDialogMsg::RegisterFail(e.to_string()));
+    record_manifest_entry(&mut data_vault.manifest, &pending_update.encrypted_entry);
+
+    if pending_update.is_queued {
+        queue_change(
+            QueuedChange::Update {
+                old_name: pending_update.old_name.clone(),
+                entry: pending_update.encrypted_entry.clone(),
+                base_revision: pending_update.base_revision,
+                idempotency_key: pending_update.idempotency_key.clone(),
+            },
+            data_vault,
+            &app_state.connection,
+        );
+    } else {
+        data_vault.set_revision(&pending_update.encrypted_entry);
+
+        update_vault_cache(
+            &app_state.connection.base_url,
+            &data_vault.email,
+            |vault_cache| {
+                vault_cache.store_entry(&pending_update.encrypted_entry);
+                vault_cache.store_entry(&pending_update.encrypted_manifest);
+            },
+        );
+
+        if pending_update.is_manifest_uploaded {
+            remember_manifest(
+                &app_state.connection.base_url,
+                &data_vault.email,
+                &pending_update.manifest,
+            );
         }
     }
+
+    let entry_list_item = EntryListItem::from_vault_entry(&pending_update.entry);
+    data_vault.entries_vault.store(pending_update.entry);
+
+    Ok(entry_list_item)
+}
+
+// An update the user cancelled is only kept when the server already has it
+pub fn apply_cancelled_update(
+    pending_update: PendingUpdate,
+    app_state: &mut AppState,
+) -> Result<Option<EntryListItem>, Error> {
+    if pending_update.is_queued {
+        return Ok(None);
+    }
+
+    match apply_update(pending_update, app_state) {
+        Ok(entry_list_item) => Ok(Some(entry_list_item)),
+        Err(e) => Err(e),
+    }
 }
 
-pub fn logout_action(app_state: &Rc<RefCell<AppState>>) {
-    logout_request(&app_state.borrow().client, &app_state.borrow().base_url).unwrap_or_default();
+// The manifest without the entry is encrypted on the GTK thread and uploaded after the delete
+#[derive(Debug)]
+pub struct PendingDeletion {
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+    manifest: Manifest,
+    encrypted_manifest: EncryptedDataEntry,
+    base_revision: Option<u64>,
+    idempotency_key: String,
+    is_queued: bool,
+    is_manifest_uploaded: bool,
 }
 
-pub fn add_password_action(
+pub fn prepare_deletion(
+    id: &str,
     name: &str,
-    username: &str,
-    password: &str,
-    url: &str,
-    expiration_date: &str,
-    add_entry_prompt: &mut AddEntryPrompt,
-) -> Result<EntryListItem, String> {
-    let entry = create_password_entry(name, username, password, url, expiration_date);
+    content_type: &str,
+    app_state: &AppState,
+) -> Result<PendingDeletion, Error> {
+    let data_vault = match app_state.vault.as_ref() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
 
-    let mut app_state = add_entry_prompt.app_state.borrow_mut();
+    let key = entry_key(id, name, content_type);
 
-    if let Some(vault) = &app_state.vault {
-        let encrypted_entry = match encrypt_password_entry(&entry, &vault.ciphers.password_cipher) {
-            Ok(encrypted_entry) => encrypted_entry,
-            Err(e) => {
-                panic!("Failed to encrypt entry: {}", e);
-            }
-        };
+    let mut manifest = data_vault.manifest.clone();
+    remove_manifest_entry(&mut manifest, &key);
 
-        match add_encrypted_data_entry_request(
-            encrypted_entry,
-            &app_state.client,
-            &app_state.base_url,
-        ) {
-            Ok(response) => {
-                println!("Add password entry successful: {}", response.status);
+    let encrypted_manifest = encrypt_data_vault_manifest(&data_vault.vault_key, &manifest)?;
 
-                let data_vault = match app_state.vault.as_mut() {
-                    Some(vault) => vault,
-                    None => {
-                        panic!("Failed to get reference to data vault");
-                    }
-                };
+    Ok(PendingDeletion {
+        id: id.to_string(),
+        name: name.to_string(),
+        content_type: content_type.to_string(),
+        manifest,
+        encrypted_manifest,
+        base_revision: data_vault.revision(&key),
+        idempotency_key: generate_idempotency_key(),
+        is_queued: data_vault.is_offline() || !data_vault.outbox.is_empty(),
+        is_manifest_uploaded: false,
+    })
+}
 
-                let entries_vault = &mut data_vault.entries_vault;
-                entries_vault.passwords.push(entry);
+pub fn delete_entry_action(
+    mut pending_deletion: PendingDeletion,
+    connection: &Connection,
+) -> Result<PendingDeletion, Error> {
+    if pending_deletion.is_queued {
+        return Ok(pending_deletion);
+    }
+
+    match delete_encrypted_data_entry_request(
+        &pending_deletion.id,
+        &pending_deletion.name,
+        &pending_deletion.content_type,
+        pending_deletion.base_revision,
+        &pending_deletion.idempotency_key,
+        &connection.client,
+        &connection.base_url,
+        &Cancellation::default(),
+    ) {
+        Ok(response) => {
+            match response {
+                Some(response) => println!("Delete entry successful: {}", response.status),
+                None => println!("Delete entry successful"),
+            }
 
-                return Ok(EntryListItem::new(name, username, EntryType::Password));
+            match upload_manifest(&pending_deletion.encrypted_manifest, connection) {
+                Ok(()) => pending_deletion.is_manifest_uploaded = true,
+                Err(e) => println!("Failed to update vault manifest: {}", e),
             }
-            Err(e) => {
-                println!("Add password entry failed: {}", e);
 
-                add_entry_prompt
-                    .response_dialog
-                    .emit(AddEntryResponseDialogMsg::AddEntryFail(e.to_string()));
+            Ok(pending_deletion)
+        }
+        Err(e) if e.is_retryable() || e.is_conflict() => {
+            println!("Queueing deletion of {}: {}", pending_deletion.name, e);
 
-                return Err(e.to_string());
-            }
+            pending_deletion.is_queued = true;
+
+            Ok(pending_deletion)
+        }
+        Err(e) => {
+            println!("Delete entry failed: {}", e);
+
+            Err(e)
+        }
+    }
+}
+
+pub fn apply_deletion(
+    pending_deletion: &PendingDeletion,
+    app_state: &mut AppState,
+) -> Result<(), Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    let id = &pending_deletion.id;
+    let content_type = pending_deletion.content_type.as_str();
+    let key = entry_key(id, &pending_deletion.name, content_type);
+
+    data_vault.entries_vault.remove(id);
+
+    remove_manifest_entry(&mut data_vault.manifest, &key);
+    data_vault.remove_revision(&key);
+
+    if pending_deletion.is_queued {
+        queue_change(
+            QueuedChange::Delete {
+                id: id.clone(),
+                name: pending_deletion.name.clone(),
+                content_type: content_type.to_string(),
+                base_revision: pending_deletion.base_revision,
+                idempotency_key: pending_deletion.idempotency_key.clone(),
+            },
+            data_vault,
+            &app_state.connection,
+        );
+    } else {
+        update_vault_cache(
+            &app_state.connection.base_url,
+            &data_vault.email,
+            |vault_cache| {
+                vault_cache.remove_entry(&key);
+                vault_cache.store_entry(&pending_deletion.encrypted_manifest);
+            },
+        );
+
+        if pending_deletion.is_manifest_uploaded {
+            remember_manifest(
+                &app_state.connection.base_url,
+                &data_vault.email,
+                &pending_deletion.manifest,
+            );
         }
     }
 
-    panic!("Failed to get reference to app state");
+    Ok(())
 }
 
-pub fn add_note_action(
-    name: &str,
-    content: &str,
-    add_entry_prompt: &mut AddEntryPrompt,
-) -> Result<EntryListItem, String> {
-    let entry = create_note_entry(name, content);
+// Kept on disk right away, a change that only lives in memory is lost with the window
+fn queue_change(change: QueuedChange, data_vault: &mut DataVault, connection: &Connection) {
+    data_vault.outbox.push(change);
 
-    let mut app_state = add_entry_prompt.app_state.borrow_mut();
+    if let Err(e) = data_vault.outbox.save(&connection.base_url) {
+        println!("Failed to save queued changes: {}", e);
+    }
+}
 
-    if let Some(vault) = &app_state.vault {
-        let encrypted_entry = match encrypt_note_entry(&entry, &vault.ciphers.note_cipher) {
-            Ok(encrypted_entry) => encrypted_entry,
-            Err(e) => {
-                panic!("Failed to encrypt entry: {}", e);
-            }
-        };
+// The changes to send and the manifest that describes the vault once they are on the server
+#[derive(Debug)]
+pub struct PendingSync {
+    changes: Vec<QueuedChange>,
+    manifest: Manifest,
+    encrypted_manifest: EncryptedDataEntry,
+    is_manifest_outdated: bool,
+}
 
-        match add_encrypted_data_entry_request(
-            encrypted_entry,
-            &app_state.client,
-            &app_state.base_url,
-        ) {
-            Ok(response) => {
-                println!("Add note entry successful: {}", response.status);
+#[derive(Debug)]
+pub struct SyncReport {
+    // Along with the entry as the server stored it, None for a deletion
+    applied: Vec<(QueuedChange, Option<EncryptedDataEntry>)>,
+    conflicts: Vec<SyncConflict>,
+    uploaded_manifest: Option<(Manifest, EncryptedDataEntry)>,
+    // Why the remaining changes are still queued
+    pub error: Option<Error>,
+}
+
+// Returns None when there is nothing to send
+pub fn prepare_outbox_sync(app_state: &AppState) -> Result<Option<PendingSync>, Error> {
+    let data_vault = match app_state.vault.as_ref() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    let changes = data_vault.outbox.sendable_changes();
+
+    if changes.is_empty() && !data_vault.outbox.is_manifest_outdated {
+        return Ok(None);
+    }
+
+    let encrypted_manifest =
+        encrypt_data_vault_manifest(&data_vault.vault_key, &data_vault.manifest)?;
+
+    Ok(Some(PendingSync {
+        changes,
+        manifest: data_vault.manifest.clone(),
+        encrypted_manifest,
+        is_manifest_outdated: data_vault.outbox.is_manifest_outdated,
+    }))
+}
+
+// Sends the queued changes in order against the revisions they were made on, stopping at the
+// first one that fails for any other reason than a conflict
+pub fn sync_outbox_action(pending_sync: PendingSync, connection: &Connection) -> SyncReport {
+    let mut report = SyncReport {
+        applied: Vec::new(),
+        conflicts: Vec::new(),
+        uploaded_manifest: None,
+        error: None,
+    };
+
+    // Fetched once, for the first conflict
+    let mut remote_entries: Option<Vec<EncryptedDataEntry>> = None;
 
-                let data_vault = match app_state.vault.as_mut() {
-                    Some(vault) => vault,
-                    None => {
-                        panic!("Failed to get reference to data vault");
+    for change in pending_sync.changes {
+        match send_queued_change(&change, connection) {
+            Ok(stored_entry) => {
+                println!("Synced queued change to {}", change.name());
+
+                report.applied.push((change, stored_entry));
+            }
+            Err(e) if e.is_conflict() => {
+                if remote_entries.is_none() {
+                    match get_all_encrypted_data_entries_request(
+                        &connection.client,
+                        &connection.base_url,
+                        &Cancellation::default(),
+                    ) {
+                        Ok(response) => remote_entries = Some(response.data),
+                        Err(e) => {
+                            report.error = Some(e);
+                            break;
+                        }
                     }
-                };
+                }
+
+                let remote = remote_entries.as_ref().and_then(|remote_entries| {
+                    remote_entries
+                        .iter()
+                        .find(|remote| remote.key() == change.key())
+                        .cloned()
+                });
+
+                // Deleting an entry the server no longer has is done already
+                if remote.is_none() && matches!(change, QueuedChange::Delete { .. }) {
+                    report.applied.push((change, None));
+                    continue;
+                }
 
-                let entries_vault = &mut data_vault.entries_vault;
-                entries_vault.notes.push(entry);
+                println!("Conflict on queued change to {}", change.name());
 
-                return Ok(EntryListItem::new(name, "", EntryType::Note));
+                report.conflicts.push(SyncConflict { change, remote });
             }
             Err(e) => {
-                println!("Add note entry failed: {}", e);
+                println!("Failed to sync queued change to {}: {}", change.name(), e);
 
-                add_entry_prompt
-                    .response_dialog
-                    .emit(AddEntryResponseDialogMsg::AddEntryFail(e.to_string()));
+                report.error = Some(e);
+                break;
+            }
+        }
+    }
 
-                return Err(e.to_string());
+    if !report.applied.is_empty() || pending_sync.is_manifest_outdated {
+        match upload_manifest(&pending_sync.encrypted_manifest, connection) {
+            Ok(()) => {
+                report.uploaded_manifest =
+                    Some((pending_sync.manifest, pending_sync.encrypted_manifest))
             }
+            Err(e) => println!("Failed to update vault manifest: {}", e),
         }
     }
 
-    panic!("Failed to get reference to app state");
+    report
 }
 
-pub fn add_card_action(
-    name: &str,
-    cardholder_name: &str,
-    card_number: &str,
-    security_code: &str,
-    expiration_date: &str,
-    add_entry_prompt: &mut AddEntryPrompt,
-) -> Result<EntryListItem, String> {
-    let entry = create_card_entry(
-        name,
-        cardholder_name,
-        card_number,
-        security_code,
-        expiration_date,
-    );
+fn send_queued_change(
+    change: &QueuedChange,
+    connection: &Connection,
+) -> Result<Option<EncryptedDataEntry>, Error> {
+    let idempotency_key = change.idempotency_key();
+
+    let response = match change {
+        QueuedChange::Add { entry, .. } => add_encrypted_data_entry_request(
+            entry,
+            &idempotency_key,
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        )?,
+        QueuedChange::Update {
+            old_name,
+            entry,
+            base_revision,
+            ..
+        } => update_encrypted_data_entry_request(
+            old_name,
+            entry,
+            *base_revision,
+            &idempotency_key,
+            &connection.client,
+            &connection.base_url,
+            &Cancellation::default(),
+        )?,
+        QueuedChange::Delete {
+            id,
+            name,
+            content_type,
+            base_revision,
+            ..
+        } => {
+            delete_encrypted_data_entry_request(
+                id,
+                name,
+                content_type,
+                *base_revision,
+                &idempotency_key,
+                &connection.client,
+                &connection.base_url,
+                &Cancellation::default(),
+            )?;
+
+            return Ok(None);
+        }
+    };
 
-    let mut app_state = add_entry_prompt.app_state.borrow_mut();
+    // Only the revision is taken from the response, the content is what was sent
+    let mut stored_entry = match change.local_entry() {
+        Some(entry) => entry.clone(),
+        None => return Ok(None),
+    };
+    stored_entry.revision = response.data.revision;
 
-    if let Some(vault) = &app_state.vault {
-        let encrypted_entry = match encrypt_card_entry(&entry, &vault.ciphers.card_cipher) {
-            Ok(encrypted_entry) => encrypted_entry,
-            Err(e) => {
-                panic!("Failed to encrypt entry: {}", e);
+    Ok(Some(stored_entry))
+}
+
+// Drops the changes the server accepted from the outbox, returning the new conflicts
+pub fn apply_outbox_sync(
+    report: SyncReport,
+    app_state: &mut AppState,
+) -> Result<Vec<SyncConflict>, Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    for (change, stored_entry) in report.applied.iter() {
+        data_vault.outbox.remove(change);
+        data_vault.remove_revision(&change.key());
+
+        if let Some(stored_entry) = stored_entry {
+            data_vault.set_revision(stored_entry);
+        }
+    }
+
+    if let Some((manifest, _)) = &report.uploaded_manifest {
+        data_vault.outbox.is_manifest_outdated = false;
+
+        remember_manifest(&app_state.connection.base_url, &data_vault.email, manifest);
+    }
+
+    data_vault
+        .outbox
+        .conflicts
+        .extend(report.conflicts.iter().cloned());
+
+    if let Err(e) = data_vault.outbox.save(&app_state.connection.base_url) {
+        println!("Failed to save queued changes: {}", e);
+    }
+
+    update_vault_cache(
+        &app_state.connection.base_url,
+        &data_vault.email,
+        |vault_cache| {
+            for (change, stored_entry) in report.applied.iter() {
+                vault_cache.remove_entry(&change.key());
+
+                if let Some(stored_entry) = stored_entry {
+                    vault_cache.store_entry(stored_entry);
+                }
             }
+
+            if let Some((_, uploaded_manifest)) = &report.uploaded_manifest {
+                vault_cache.store_entry(uploaded_manifest);
+            }
+        },
+    );
+
+    Ok(report.conflicts)
+}
+
+// Keeping the local version sends it again against the server revision, keeping the server
+// version replaces the local one in the vault
+pub fn resolve_conflict(
+    conflict: &SyncConflict,
+    keep_local: bool,
+    app_state: &mut AppState,
+) -> Result<(), Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
+        }
+    };
+
+    // Sent against another revision, so the server has to take it as a new change
+    if keep_local {
+        let idempotency_key = generate_idempotency_key();
+        let rebased_change = match (conflict.change.local_entry(), &conflict.remote) {
+            (Some(entry), Some(remote)) => QueuedChange::Update {
+                old_name: remote.name.clone(),
+                entry: entry.clone(),
+                base_revision: remote.revision,
+                idempotency_key,
+            },
+            (Some(entry), None) => QueuedChange::Add {
+                entry: entry.clone(),
+                idempotency_key,
+            },
+            (None, remote) => QueuedChange::Delete {
+                id: conflict.change.id().to_string(),
+                name: conflict.change.name().to_string(),
+                content_type: conflict.change.content_type().to_string(),
+                base_revision: remote.as_ref().and_then(|remote| remote.revision),
+                idempotency_key,
+            },
         };
 
-        match add_encrypted_data_entry_request(
-            encrypted_entry,
-            &app_state.client,
-            &app_state.base_url,
-        ) {
-            Ok(response) => {
-                println!("Add card entry successful: {}", response.status);
+        data_vault.outbox.replace(&conflict.change, rebased_change);
+    } else {
+        // Checked first so that a version that cannot be read does not replace the local one
+        if let Some(remote) = &conflict.remote {
+            describe_data_entry(remote, &data_vault.ciphers)?;
+        }
 
-                let data_vault = match app_state.vault.as_mut() {
-                    Some(vault) => vault,
-                    None => {
-                        panic!("Failed to get reference to data vault");
-                    }
-                };
+        data_vault.outbox.remove(&conflict.change);
 
-                let entries_vault = &mut data_vault.entries_vault;
-                entries_vault.cards.push(entry);
+        if let Some(entry) = conflict.change.local_entry() {
+            data_vault.entries_vault.remove(&entry.id);
+            remove_manifest_entry(&mut data_vault.manifest, &entry.key());
+        }
 
-                return Ok(EntryListItem::new(name, "", EntryType::Card));
-            }
-            Err(e) => {
-                println!("Add card entry failed: {}", e);
+        match &conflict.remote {
+            Some(remote) => {
+                data_vault.entries_vault.remove(&remote.id);
 
-                add_entry_prompt
-                    .response_dialog
-                    .emit(AddEntryResponseDialogMsg::AddEntryFail(e.to_string()));
+                data_vault.fill(std::slice::from_ref(remote));
 
-                return Err(e.to_string());
+                record_manifest_entry(&mut data_vault.manifest, remote);
+                data_vault.set_revision(remote);
+            }
+            None => {
+                data_vault.remove_revision(&conflict.change.key());
             }
         }
+
+        // The manifest on the server still lists the version that was thrown away
+        data_vault.outbox.is_manifest_outdated = true;
+
+        update_vault_cache(
+            &app_state.connection.base_url,
+            &data_vault.email,
+            |vault_cache| match &conflict.remote {
+                Some(remote) => vault_cache.store_entry(remote),
+                None => vault_cache.remove_entry(&conflict.change.key()),
+            },
+        );
+    }
+
+    data_vault
+        .outbox
+        .conflicts
+        .retain(|other| other != conflict);
+
+    if let Err(e) = data_vault.outbox.save(&app_state.connection.base_url) {
+        println!("Failed to save queued changes: {}", e);
     }
 
-    panic!("Failed to get reference to app state");
+    Ok(())
 }
 
-pub fn add_totp_action(
-    name: &str,
-    algorithm: &str,
-    secret: &str,
-    digits: &str,
-    skew: &str,
-    period: &str,
-    add_entry_prompt: &mut AddEntryPrompt,
-) -> Result<EntryListItem, String> {
-    if algorithm != "SHA1" && algorithm != "SHA256" && algorithm != "SHA512" {
-        add_entry_prompt
-            .response_dialog
-            .emit(AddEntryResponseDialogMsg::AddEntryFail(
-                "Invalid algorithm".to_string(),
-            ));
-        return Err("Invalid algorithm".to_string());
+// Where the refresh starts from, None downloads every entry
+#[derive(Debug)]
+pub struct PendingRefresh {
+    email: String,
+    cursor: Option<u64>,
+}
+
+// What the server sent along with the whole server state it adds up to
+#[derive(Debug)]
+pub struct RefreshedVault {
+    remote_changes: RemoteChanges,
+    entries: Vec<EncryptedDataEntry>,
+    // As the offline copy had it before the refresh, None when there is no offline copy
+    previous_vault_key: Option<EncryptedDataEntry>,
+}
+
+// How the list view follows a refresh, an updated entry keeps its place
+#[derive(Debug)]
+pub enum EntryListChange {
+    Added(EntryListItem),
+    Updated(EntryListItem),
+    Removed { id: String },
+}
+
+#[derive(Debug)]
+pub enum RefreshOutcome {
+    // The warnings are only the ones no earlier refresh reported
+    Applied {
+        list_changes: Vec<EntryListChange>,
+        warnings: Vec<String>,
+    },
+    // Entries the vault key cannot decrypt after the protected vault key was replaced mean the
+    // master password was changed elsewhere
+    VaultKeyChanged,
+}
+
+pub fn prepare_refresh(app_state: &AppState) -> Result<PendingRefresh, Error> {
+    match app_state.vault.as_ref() {
+        Some(data_vault) => Ok(PendingRefresh {
+            email: data_vault.email.clone(),
+            cursor: data_vault.sync_cursor,
+        }),
+        None => Err(Error::Unauthorized(
+            "Failed to get reference to data vault".to_string(),
+        )),
     }
+}
 
-    let digits = match digits.parse::<usize>() {
-        Ok(digits) => digits,
-        Err(_) => {
-            add_entry_prompt
-                .response_dialog
-                .emit(AddEntryResponseDialogMsg::AddEntryFail(
-                    "Invalid digits".to_string(),
-                ));
-            return Err("Invalid digits".to_string());
+// Merges the changes into the offline copy so the whole server state can be checked against the
+// manifest, a delta is only asked for when the offline copy is at the same cursor as the vault
+pub fn refresh_action(
+    pending_refresh: PendingRefresh,
+    connection: &Connection,
+) -> Result<RefreshedVault, Error> {
+    let vault_cache = match VaultCache::load(&connection.base_url, &pending_refresh.email) {
+        Ok(vault_cache) if vault_cache.cursor == pending_refresh.cursor => Some(vault_cache),
+        Ok(_) => None,
+        Err(e) => {
+            println!("Refreshing without offline copy: {}", e);
+            None
         }
     };
 
-    let skew = match skew.parse::<u8>() {
-        Ok(skew) => skew,
-        Err(_) => {
-            add_entry_prompt
-                .response_dialog
-                .emit(AddEntryResponseDialogMsg::AddEntryFail(
-                    "Invalid skew".to_string(),
-                ));
-            return Err("Invalid skew".to_string());
+    let (mut entries, cursor) = match vault_cache {
+        Some(vault_cache) => (vault_cache.entries, vault_cache.cursor),
+        None => (Vec::new(), None),
+    };
+
+    let previous_vault_key = find_protected_vault_key_entry(&entries).cloned();
+
+    let remote_changes = fetch_remote_changes(cursor, connection, &Cancellation::default())?;
+
+    let entries = match &remote_changes {
+        RemoteChanges::Delta(changes) => {
+            apply_entry_changes(&mut entries, changes);
+            entries
         }
+        RemoteChanges::Full { entries, .. } => entries.clone(),
     };
 
-    let period = match period.parse::<u64>() {
-        Ok(period) => period,
-        Err(_) => {
-            add_entry_prompt
-                .response_dialog
-                .emit(AddEntryResponseDialogMsg::AddEntryFail(
-                    "Invalid period".to_string(),
-                ));
-            return Err("Invalid period".to_string());
+    Ok(RefreshedVault {
+        remote_changes,
+        entries,
+        previous_vault_key,
+    })
+}
+
+// Brings the vault up to what the server has, leaving alone the entries with a queued change
+// since the outbox sync decides what happens to those. An entry that cannot be read is reported
+// and tried again by the next refresh, the cursor only moves once every entry was applied
+pub fn apply_refresh(
+    refreshed_vault: RefreshedVault,
+    app_state: &mut AppState,
+) -> Result<RefreshOutcome, Error> {
+    let data_vault = match app_state.vault.as_mut() {
+        Some(vault) => vault,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to get reference to data vault".to_string(),
+            ))
         }
     };
 
-    let entry = create_totp_entry(name, algorithm, secret, digits, skew, period);
+    let RefreshedVault {
+        remote_changes,
+        entries,
+        previous_vault_key,
+    } = refreshed_vault;
+
+    let (changed_entries, deleted_entries, cursor) = match &remote_changes {
+        RemoteChanges::Delta(changes) => (
+            changes.changed.clone(),
+            changes.deleted.clone(),
+            Some(changes.cursor),
+        ),
+        RemoteChanges::Full { entries, cursor } => (
+            entries.clone(),
+            find_removed_entries(&data_vault.entries_vault, entries),
+            *cursor,
+        ),
+    };
 
-    let mut app_state = add_entry_prompt.app_state.borrow_mut();
+    let mut list_changes = Vec::new();
+    let mut warnings = Vec::new();
+    let mut is_decryption_failed = false;
 
-    if let Some(vault) = &app_state.vault {
-        let encrypted_entry = match encrypt_totp_entry(&entry, &vault.ciphers.totp_entry_cipher) {
-            Ok(encrypted_entry) => encrypted_entry,
-            Err(e) => {
-                panic!("Failed to encrypt entry: {}", e);
-            }
-        };
+    for deleted_entry in deleted_entries.iter() {
+        let key = deleted_entry.key();
+        let content_type = &deleted_entry.content_type;
 
-        match add_encrypted_data_entry_request(
-            encrypted_entry,
-            &app_state.client,
-            &app_state.base_url,
-        ) {
-            Ok(response) => {
-                println!("Add card entry successful: {}", response.status);
+        data_vault.remove_revision(&key);
 
-                let data_vault = match app_state.vault.as_mut() {
-                    Some(vault) => vault,
-                    None => {
-                        panic!("Failed to get reference to data vault");
-                    }
-                };
+        if is_reserved_content_type(content_type) || data_vault.outbox.has_change_for(&key) {
+            continue;
+        }
 
-                let entries_vault = &mut data_vault.entries_vault;
-                entries_vault.totp_entries.push(entry);
+        remove_manifest_entry(&mut data_vault.manifest, &key);
 
-                return Ok(EntryListItem::new(name, "", EntryType::TOTP));
-            }
+        if data_vault.entries_vault.remove(&deleted_entry.id) {
+            list_changes.push(EntryListChange::Removed {
+                id: deleted_entry.id.clone(),
+            });
+        }
+    }
+
+    for changed_entry in changed_entries.iter() {
+        let key = changed_entry.key();
+        let name = &changed_entry.name;
+        let content_type = &changed_entry.content_type;
+
+        // Already in the vault as the server has it
+        if changed_entry.revision.is_some() && data_vault.revision(&key) == changed_entry.revision {
+            continue;
+        }
+
+        if is_reserved_content_type(content_type) {
+            data_vault.set_revision(changed_entry);
+            continue;
+        }
+
+        if data_vault.outbox.has_change_for(&key) {
+            continue;
+        }
+
+        let is_replaced = match store_vault_entry(data_vault, changed_entry) {
+            Ok(is_replaced) => is_replaced,
             Err(e) => {
-                println!("Add TOTP entry failed: {}", e);
+                println!("Skipping entry {}: {}", name, e);
 
-                add_entry_prompt
-                    .response_dialog
-                    .emit(AddEntryResponseDialogMsg::AddEntryFail(e.to_string()));
+                if matches!(e, Error::Crypto(_)) {
+                    is_decryption_failed = true;
+                }
 
-                return Err(e.to_string());
+                warnings.push(format!("The entry {} could not be read: {}", name, e));
+                continue;
             }
+        };
+
+        data_vault.set_revision(changed_entry);
+        record_manifest_entry(&mut data_vault.manifest, changed_entry);
+
+        let item = match data_vault.entries_vault.get(&changed_entry.id) {
+            Some(vault_entry) => EntryListItem::from_vault_entry(vault_entry),
+            None => continue,
+        };
+
+        if is_replaced {
+            list_changes.push(EntryListChange::Updated(item));
+        } else {
+            list_changes.push(EntryListChange::Added(item));
         }
     }
 
-    panic!("Failed to get reference to app state");
-}
+    if is_decryption_failed && is_vault_key_replaced(previous_vault_key.as_ref(), &entries) {
+        return Ok(RefreshOutcome::VaultKeyChanged);
+    }
 
-pub fn delete_entry_action(
-    name: &str,
-    content_type: &str,
-    app_state: Rc<RefCell<AppState>>,
-) -> Result<(), String> {
-    let mut app_state = app_state.borrow_mut();
+    if warnings.is_empty() {
+        data_vault.sync_cursor = cursor;
+    }
+    data_vault.offline_synced_at = None;
 
-    match delete_encrypted_data_entry_request(
-        name,
-        content_type,
-        &app_state.client,
-        &app_state.base_url,
-    ) {
-        Ok(response) => {
-            println!("Delete entry successful: {}", response.status);
+    let base_url = &app_state.connection.base_url;
 
-            let data_vault = match app_state.vault.as_mut() {
-                Some(vault) => vault,
-                None => {
-                    panic!("Failed to get reference to data vault");
-                }
-            };
+    // The manifest on the server lags behind until the one queued here goes out
+    if !data_vault.outbox.is_manifest_outdated {
+        let manifest_cipher = generate_vault_cipher(&data_vault.vault_key, MANIFEST_CONTENT_TYPE)?;
 
-            let entries_vault = &mut data_vault.entries_vault;
+        let anchor = match ManifestAnchor::load(base_url, &data_vault.email) {
+            Ok(anchor) => anchor,
+            Err(e) => {
+                println!("Failed to load manifest anchor: {}", e);
+                None
+            }
+        };
 
-            match content_type {
-                "password" => {
-                    entries_vault.passwords.retain(|entry| entry.name != name);
-                }
-                "note" => {
-                    entries_vault.notes.retain(|entry| entry.name != name);
-                }
-                "card" => {
-                    entries_vault.cards.retain(|entry| entry.name != name);
-                }
-                "totp" => {
-                    entries_vault
-                        .totp_entries
-                        .retain(|entry| entry.name != name);
-                }
-                _ => {
-                    panic!("Invalid content type");
-                }
+        let mut integrity_check =
+            check_vault_integrity(&entries, &manifest_cipher, anchor.as_ref());
+
+        if integrity_check.warnings.is_empty() {
+            if !integrity_check.is_manifest_outdated {
+                remember_manifest(base_url, &data_vault.email, &integrity_check.manifest);
+            }
+        } else {
+            for warning in integrity_check.warnings.iter() {
+                println!("Vault integrity warning: {}", warning);
             }
 
-            Ok(())
+            data_vault.rebased_manifest = integrity_check.rebased_manifest;
+            warnings.append(&mut integrity_check.warnings);
         }
-        Err(e) => {
-            println!("Delete entry failed: {}", e);
+    }
+
+    let new_warnings = warnings
+        .iter()
+        .filter(|warning| !data_vault.refresh_warnings.contains(warning))
+        .cloned()
+        .collect();
+    data_vault.refresh_warnings = warnings;
+
+    let sync_cursor = data_vault.sync_cursor;
+
+    match remote_changes {
+        RemoteChanges::Delta(changes) => {
+            update_vault_cache(base_url, &data_vault.email, |vault_cache| {
+                apply_entry_changes(&mut vault_cache.entries, &changes);
+                vault_cache.cursor = sync_cursor;
+            });
+        }
+        RemoteChanges::Full { entries, .. } => {
+            if let Err(e) = VaultCache::new(
+                &data_vault.email,
+                data_vault.kdf_params.clone(),
+                entries,
+                sync_cursor,
+            )
+            .save(base_url)
+            {
+                println!("Failed to save offline copy: {}", e);
+            }
+        }
+    }
 
-            Err(e.to_string())
+    Ok(RefreshOutcome::Applied {
+        list_changes,
+        warnings: new_warnings,
+    })
+}
+
+// A password change elsewhere re-encrypts the entries first and replaces the vault key last
+fn is_vault_key_replaced(
+    previous_vault_key: Option<&EncryptedDataEntry>,
+    entries: &[EncryptedDataEntry],
+) -> bool {
+    match (previous_vault_key, find_protected_vault_key_entry(entries)) {
+        (Some(previous), Some(current)) => {
+            previous.content != current.content || previous.nonce != current.nonce
         }
+        _ => false,
     }
 }
+
+// Entries of the vault the full list from the server no longer has
+fn find_removed_entries(
+    entries_vault: &EntriesVault,
+    server_entries: &[EncryptedDataEntry],
+) -> Vec<DeletedDataEntry> {
+    entries_vault
+        .entries
+        .iter()
+        .filter(|vault_entry| {
+            !server_entries
+                .iter()
+                .any(|entry| entry.id == vault_entry.id())
+        })
+        .map(|vault_entry| DeletedDataEntry {
+            id: vault_entry.id().to_string(),
+            name: vault_entry.name().to_string(),
+            content_type: vault_entry.content_type().to_string(),
+        })
+        .collect()
+}
@@ -1,14 +1,20 @@
 use std::{cell::RefCell, rc::Rc};
 
 use super::{
-    actions::*, add_entry_response_dialog::AddEntryResponseDialog, entry_list_item::EntryListItem,
-    utils::generate_random_password,
+    actions::*,
+    add_entry_response_dialog::{
+        AddEntryResponseDialog, AddEntryResponseDialogMsg, AddEntryResponseDialogOutput,
+    },
+    entry_list_item::EntryListItem,
+    utils::{copy_secret_to_clipboard, generate_random_password},
 };
+use crate::error::Error;
+use crate::requests::Cancellation;
 use crate::AppState;
 
 use super::main_window::EntryTypeView;
 use adw::prelude::*;
-use relm4::{component::Connector, prelude::*};
+use relm4::{prelude::*, Controller};
 use relm4_icons::icon_names;
 
 pub struct AddPassword {
@@ -46,12 +52,16 @@ pub struct AddEntryPrompt {
 
     entry_type_view: EntryTypeView,
 
+    // Set while the entry is uploaded in the background
+    is_busy: bool,
+    cancellation: Cancellation,
+
     add_password: AddPassword,
     add_note: AddNote,
     add_card: AddCard,
     add_totp: AddTOTPEntry,
 
-    pub response_dialog: Connector<AddEntryResponseDialog>,
+    pub response_dialog: Controller<AddEntryResponseDialog>,
 
     pub app_state: Rc<RefCell<AppState>>,
 }
@@ -61,22 +71,30 @@ pub enum AddEntryPromptMsg {
     SetMode(EntryTypeView),
 
     AddPress,
+    CancelPress,
 
     Show,
 
     GenerateRandomPassword,
 }
 
+#[derive(Debug)]
+pub enum AddEntryCommandOutput {
+    AddFinished(Result<PendingEntry, Error>),
+}
+
 #[derive(Debug)]
 pub enum AddEntryPromptOutput {
     NewEntryListItem(EntryListItem),
+    SessionExpired,
 }
 
 #[relm4::component(pub)]
-impl SimpleComponent for AddEntryPrompt {
+impl Component for AddEntryPrompt {
     type Init = Rc<RefCell<AppState>>;
     type Input = AddEntryPromptMsg;
     type Output = AddEntryPromptOutput;
+    type CommandOutput = AddEntryCommandOutput;
 
     view! {
         adw::ApplicationWindow {
@@ -327,10 +345,34 @@ impl SimpleComponent for AddEntryPrompt {
                 gtk::Button {
                     set_margin_all: 40,
                     set_label: "Add",
+                    #[watch]
+                    set_sensitive: !model.is_busy,
                     connect_clicked[sender] => move |_| {
                         sender.input(AddEntryPromptMsg::AddPress);
                     }
-                }
+                },
+
+                // Shown while the entry is uploaded
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_halign: gtk::Align::Center,
+                    set_margin_bottom: 20,
+                    set_spacing: 10,
+
+                    #[watch]
+                    set_visible: model.is_busy,
+
+                    gtk::Spinner {
+                        #[watch]
+                        set_spinning: model.is_busy,
+                    },
+                    gtk::Button {
+                        set_label: "Cancel",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AddEntryPromptMsg::CancelPress);
+                        }
+                    }
+                },
             }
         }
     }
@@ -345,6 +387,9 @@ impl SimpleComponent for AddEntryPrompt {
 
             entry_type_view: EntryTypeView::Password,
 
+            is_busy: false,
+            cancellation: Cancellation::default(),
+
             add_password: AddPassword {
                 name: gtk::EntryBuffer::default(),
                 username: gtk::EntryBuffer::default(),
@@ -374,7 +419,10 @@ impl SimpleComponent for AddEntryPrompt {
 
             response_dialog: AddEntryResponseDialog::builder()
                 .transient_for(&root)
-                .launch(()),
+                .launch(())
+                .forward(sender.input_sender(), |msg| match msg {
+                    AddEntryResponseDialogOutput::Retry => AddEntryPromptMsg::AddPress,
+                }),
 
             app_state: state,
         };
@@ -384,101 +432,153 @@ impl SimpleComponent for AddEntryPrompt {
         ComponentParts { model, widgets }
     }
 
-    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
         match msg {
             AddEntryPromptMsg::SetMode(mode) => {
                 self.entry_type_view = mode;
             }
 
-            AddEntryPromptMsg::AddPress => match self.entry_type_view {
-                EntryTypeView::Password => {
-                    let name = self.add_password.name.text();
-                    let username = self.add_password.username.text();
-                    let password = self.add_password.password.text();
-                    let url = self.add_password.url.text();
-                    let expiration_date = self.add_password.expiration_date.text();
-
-                    if let Ok(new_entry_list_item) = add_password_action(
-                        &name,
-                        &username,
-                        &password,
-                        &url,
-                        &expiration_date,
-                        self,
-                    ) {
-                        sender
-                            .output(AddEntryPromptOutput::NewEntryListItem(new_entry_list_item))
-                            .unwrap();
+            AddEntryPromptMsg::AddPress => {
+                let app_state = self.app_state.borrow();
+
+                // Encrypting needs the vault, so it happens here and only the upload is moved away
+                let pending_entry = match self.entry_type_view {
+                    EntryTypeView::Password => {
+                        let name = self.add_password.name.text();
+                        let username = self.add_password.username.text();
+                        let password = self.add_password.password.text();
+                        let url = self.add_password.url.text();
+                        let expiration_date = self.add_password.expiration_date.text();
+
+                        prepare_password_entry(
+                            &name,
+                            &username,
+                            &password,
+                            &url,
+                            &expiration_date,
+                            &app_state,
+                        )
                     }
-                }
 
-                EntryTypeView::Note => {
-                    let name = self.add_note.name.text();
-                    let content = self.add_note.content.text(
-                        &self.add_note.content.start_iter(),
-                        &self.add_note.content.end_iter(),
-                        false,
-                    );
-
-                    if let Ok(new_entry_list_item) = add_note_action(&name, &content, self) {
-                        sender
-                            .output(AddEntryPromptOutput::NewEntryListItem(new_entry_list_item))
-                            .unwrap();
+                    EntryTypeView::Note => {
+                        let name = self.add_note.name.text();
+                        let content = self.add_note.content.text(
+                            &self.add_note.content.start_iter(),
+                            &self.add_note.content.end_iter(),
+                            false,
+                        );
+
+                        prepare_note_entry(&name, &content, &app_state)
                     }
-                }
 
-                EntryTypeView::Card => {
-                    let name = self.add_card.name.text();
-                    let cardholder_name = self.add_card.cardholder_name.text();
-                    let card_number = self.add_card.card_number.text();
-                    let security_code = self.add_card.security_code.text();
-                    let expiration_date = self.add_card.expiration_date.text();
-
-                    if let Ok(new_entry_list_item) = add_card_action(
-                        &name,
-                        &cardholder_name,
-                        &card_number,
-                        &security_code,
-                        &expiration_date,
-                        self,
-                    ) {
-                        sender
-                            .output(AddEntryPromptOutput::NewEntryListItem(new_entry_list_item))
-                            .unwrap();
+                    EntryTypeView::Card => {
+                        let name = self.add_card.name.text();
+                        let cardholder_name = self.add_card.cardholder_name.text();
+                        let card_number = self.add_card.card_number.text();
+                        let security_code = self.add_card.security_code.text();
+                        let expiration_date = self.add_card.expiration_date.text();
+
+                        prepare_card_entry(
+                            &name,
+                            &cardholder_name,
+                            &card_number,
+                            &security_code,
+                            &expiration_date,
+                            &app_state,
+                        )
                     }
-                }
 
-                EntryTypeView::TOTP => {
-                    let name = self.add_totp.name.text();
-                    let algorithm = self.add_totp.algorithm.text();
-                    let secret = self.add_totp.secret.text();
-                    let digits = self.add_totp.digits.text();
-                    let skew = self.add_totp.skew.text();
-                    let period = self.add_totp.period.text();
-
-                    if let Ok(new_entry_list_item) =
-                        add_totp_action(&name, &algorithm, &secret, &digits, &skew, &period, self)
-                    {
-                        sender
-                            .output(AddEntryPromptOutput::NewEntryListItem(new_entry_list_item))
-                            .unwrap();
+                    EntryTypeView::TOTP => {
+                        let name = self.add_totp.name.text();
+                        let algorithm = self.add_totp.algorithm.text();
+                        let secret = self.add_totp.secret.text();
+                        let digits = self.add_totp.digits.text();
+                        let skew = self.add_totp.skew.text();
+                        let period = self.add_totp.period.text();
+
+                        prepare_totp_entry(
+                            &name, &algorithm, &secret, &digits, &skew, &period, &app_state,
+                        )
                     }
-                }
-            },
+                };
+
+                let connection = app_state.connection.clone();
+                drop(app_state);
+
+                let pending_entry = match pending_entry {
+                    Ok(pending_entry) => pending_entry,
+                    Err(e) => {
+                        self.handle_error(e, &sender);
+                        return;
+                    }
+                };
+
+                self.is_busy = true;
+                self.cancellation = Cancellation::default();
+                let cancellation = self.cancellation.clone();
+
+                sender.spawn_oneshot_command(move || {
+                    AddEntryCommandOutput::AddFinished(add_entry_action(
+                        pending_entry,
+                        &connection,
+                        &cancellation,
+                    ))
+                });
+            }
+
+            AddEntryPromptMsg::CancelPress => {
+                self.cancellation.cancel();
+            }
 
             AddEntryPromptMsg::Show => {
                 self.is_active = true;
             }
 
             AddEntryPromptMsg::GenerateRandomPassword => {
-                let gen_pass = generate_random_password();
+                let config = self.app_state.borrow().settings.effective();
+                let gen_pass = generate_random_password(&config.generator);
+
+                copy_secret_to_clipboard(&gen_pass, &config);
+            }
+        }
+    }
 
-                // lol
-                let button = gtk::Button::builder().build();
-                let clipboard = button.clipboard();
+    fn update_cmd(
+        &mut self,
+        msg: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        self.is_busy = false;
+
+        match msg {
+            AddEntryCommandOutput::AddFinished(Ok(pending_entry)) => {
+                let result = apply_new_entry(pending_entry, &mut self.app_state.borrow_mut());
+
+                match result {
+                    Ok(new_entry_list_item) => sender
+                        .output(AddEntryPromptOutput::NewEntryListItem(new_entry_list_item))
+                        .unwrap(),
+                    Err(e) => self.handle_error(e, &sender),
+                }
+            }
+            AddEntryCommandOutput::AddFinished(Err(e)) => self.handle_error(e, &sender),
+        }
+    }
+}
 
-                clipboard.set_text(&gen_pass);
+impl AddEntryPrompt {
+    fn handle_error(&mut self, error: Error, sender: &ComponentSender<Self>) {
+        match error {
+            Error::Cancelled => {}
+            // The session is gone, so the main window has to send the user back to login
+            e if e.is_unauthorized() => {
+                self.is_active = false;
+                sender.output(AddEntryPromptOutput::SessionExpired).unwrap();
             }
+            e => self
+                .response_dialog
+                .emit(AddEntryResponseDialogMsg::AddEntryFail(e)),
         }
     }
 }
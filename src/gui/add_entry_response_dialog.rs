@@ -1,22 +1,32 @@
 use adw::prelude::*;
 use relm4::prelude::*;
 
+use crate::error::Error;
+
 pub struct AddEntryResponseDialog {
     pub error_text: String,
+    can_retry: bool,
     is_active: bool,
 }
 
 #[derive(Debug)]
 pub enum AddEntryResponseDialogMsg {
-    AddEntryFail(String),
+    AddEntryFail(Error),
+    EditEntryFail(Error),
     AddEntrySuccess,
+    Retry,
+}
+
+#[derive(Debug)]
+pub enum AddEntryResponseDialogOutput {
+    Retry,
 }
 
 #[relm4::component(pub)]
 impl SimpleComponent for AddEntryResponseDialog {
     type Init = ();
     type Input = AddEntryResponseDialogMsg;
-    type Output = ();
+    type Output = AddEntryResponseDialogOutput;
 
     view! {
         #[name = "dialog"]
@@ -25,18 +35,27 @@ impl SimpleComponent for AddEntryResponseDialog {
             set_visible: model.is_active,
             #[watch]
             set_heading: Some(&model.error_text),
+            add_response: ("retry", "Retry"),
+            #[watch]
+            set_response_enabled: ("retry", model.can_retry),
             add_response: ("close", "Close"),
+            set_close_response: "close",
             set_hide_on_close: true,
+
+            connect_response: (Some("retry"), move |_, _| {
+                sender.input(AddEntryResponseDialogMsg::Retry);
+            }),
         }
     }
 
     fn init(
         (): Self::Init,
         root: Self::Root,
-        _sender: ComponentSender<Self>,
+        sender: ComponentSender<Self>,
     ) -> ComponentParts<Self> {
         let model = AddEntryResponseDialog {
             error_text: String::new(),
+            can_retry: false,
             is_active: false,
         };
 
@@ -45,14 +64,27 @@ impl SimpleComponent for AddEntryResponseDialog {
         ComponentParts { model, widgets }
     }
 
-    fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
         match msg {
-            AddEntryResponseDialogMsg::AddEntryFail(error_text) => {
-                self.error_text = error_text;
+            // Requests that never reached the server can be sent again from the dialog
+            AddEntryResponseDialogMsg::AddEntryFail(error) => {
+                self.error_text = error.to_string();
+                self.can_retry = error.is_retryable();
+                self.is_active = true;
+            }
+
+            AddEntryResponseDialogMsg::EditEntryFail(error) => {
+                self.error_text = format!("Failed to save the changes: {}", error);
+                self.can_retry = error.is_retryable();
                 self.is_active = true;
             }
 
             AddEntryResponseDialogMsg::AddEntrySuccess => {}
+
+            AddEntryResponseDialogMsg::Retry => {
+                self.is_active = false;
+                sender.output(AddEntryResponseDialogOutput::Retry).unwrap();
+            }
         }
     }
 }
@@ -3,10 +3,7 @@ use std::{cell::RefCell, rc::Rc};
 use adw::prelude::*;
 use relm4::{prelude::*, Controller, SimpleComponent};
 
-use crate::{
-    entries::fill_data_vault_from_response, requests::get_all_encrypted_data_entries_request,
-    AppState,
-};
+use crate::AppState;
 
 use super::{
     auth_prompt::{AuthPrompt, LoggedInMsg},
@@ -24,6 +21,7 @@ pub struct AppTopWrapper {
 pub enum AppTopWrapperInput {
     LoggedIn,
     LoggedOut,
+    SwitchProfile(String),
 }
 
 #[relm4::component(pub)]
@@ -62,42 +60,56 @@ impl SimpleComponent for AppTopWrapper {
     fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
         match msg {
             AppTopWrapperInput::LoggedIn => {
-                let encrypted_entries_response = match get_all_encrypted_data_entries_request(
-                    &self.app_state.borrow().client,
-                    &self.app_state.borrow().base_url,
-                ) {
-                    Ok(encrypted_entries) => encrypted_entries,
-                    Err(e) => {
-                        println!("Failed to get encrypted entries: {}", e);
-                        return;
-                    }
-                };
-
-                // Fill the data vault
-                match self.app_state.borrow_mut().vault.as_mut() {
-                    Some(vault) => {
-                        fill_data_vault_from_response(vault, encrypted_entries_response);
-                    }
-                    None => {
-                        println!("Failed to get mutable reference to data vault");
-                        return;
-                    }
-                };
-
                 self.auth_prompt = None;
                 self.main_window = Some(
                     MainWindow::builder()
                         .launch(self.app_state.clone())
                         .forward(sender.input_sender(), |msg| match msg {
                             LoggedOutMsg::LoggedOut => AppTopWrapperInput::LoggedOut,
+                            LoggedOutMsg::SwitchProfile(name) => {
+                                AppTopWrapperInput::SwitchProfile(name)
+                            }
                         }),
                 );
             }
 
+            // The window of the previous profile closes without logging out
+            AppTopWrapperInput::SwitchProfile(name) => {
+                self.main_window = None;
+
+                let mut app_state = self.app_state.borrow_mut();
+
+                if let Err(e) = app_state.switch_profile(&name) {
+                    println!("Failed to switch profile: {}", e);
+                }
+
+                let is_unlocked = app_state.vault.is_some();
+                drop(app_state);
+
+                if is_unlocked {
+                    sender.input(AppTopWrapperInput::LoggedIn);
+                } else {
+                    self.auth_prompt = Some(
+                        AuthPrompt::builder()
+                            .launch(self.app_state.clone())
+                            .forward(sender.input_sender(), |msg| match msg {
+                                LoggedInMsg::LoggedIn => AppTopWrapperInput::LoggedIn,
+                            }),
+                    );
+                }
+            }
+
             AppTopWrapperInput::LoggedOut => {
+                // Dropping the window and the vault wipes the keys and entries from memory
                 self.main_window = None;
 
-                self.app_state.borrow_mut().vault = None;
+                let mut app_state = self.app_state.borrow_mut();
+                app_state.vault = None;
+
+                // Server and timeout changes saved during the session apply from here on
+                app_state.reconnect();
+
+                drop(app_state);
 
                 self.auth_prompt = Some(
                     AuthPrompt::builder()
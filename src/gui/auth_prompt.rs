@@ -1,15 +1,26 @@
-use super::actions::{login_action, register_action};
-use super::auth_response_dialog::AuthResponseDialog;
-use crate::AppState;
+use super::actions::{login_action, recover_action, register_action};
+use super::auth_response_dialog::{
+    AuthResponseDialog, AuthResponseDialogMsg, AuthResponseDialogOutput,
+};
+use super::preferences_prompt::{PreferencesPrompt, PreferencesPromptMsg};
+use super::profile_prompt::{ProfilePrompt, ProfilePromptMsg, ProfilePromptOutput};
+use super::recovery_key_dialog::{RecoveryKeyDialog, RecoveryKeyDialogMsg};
+use crate::config::Config;
+use crate::error::Error;
+use crate::model::DataVault;
+use crate::requests::Cancellation;
+use crate::{AppState, Connection};
 use adw::prelude::*;
-use relm4::{component::Connector, prelude::*};
+use relm4::{component::Connector, prelude::*, Controller};
 use std::cell::RefCell;
 use std::rc::Rc;
+use zeroize::Zeroizing;
 
 #[derive(Debug)]
 pub enum AuthAppMode {
     Login,
     Register,
+    Recover,
 }
 
 pub struct AuthPrompt {
@@ -17,14 +28,31 @@ pub struct AuthPrompt {
 
     mode: AuthAppMode,
 
+    // Set while a request runs in the background, the inputs stay locked until it returns
+    is_busy: bool,
+    cancellation: Cancellation,
+
+    // Mirrors the order of the profiles in the settings
+    profile_names: gtk::StringList,
+    profile_drop_down: gtk::DropDown,
+
     login_email: gtk::EntryBuffer,
     login_password: gtk::EntryBuffer,
 
     register_email: gtk::EntryBuffer,
     register_password1: gtk::EntryBuffer,
     register_password2: gtk::EntryBuffer,
+    create_recovery_key: bool,
+
+    recover_email: gtk::EntryBuffer,
+    recovery_code: gtk::EntryBuffer,
+    recover_password1: gtk::EntryBuffer,
+    recover_password2: gtk::EntryBuffer,
 
-    pub response_dialog: Connector<AuthResponseDialog>,
+    pub response_dialog: Controller<AuthResponseDialog>,
+    pub recovery_key_dialog: Connector<RecoveryKeyDialog>,
+    preferences_prompt: Controller<PreferencesPrompt>,
+    profile_prompt: Controller<ProfilePrompt>,
 
     pub app_state: Rc<RefCell<AppState>>,
 }
@@ -32,9 +60,28 @@ pub struct AuthPrompt {
 #[derive(Debug)]
 pub enum AuthMsg {
     SetMode(AuthAppMode),
+    SetCreateRecoveryKey(bool),
+
+    SelectProfile(String),
+    ShowProfilePrompt,
+    ProfileAdded(String),
+    RemoveProfilePress,
 
     LoginPress,
     RegisterPress,
+    RecoverPress,
+    CancelPress,
+
+    ShowPreferences,
+
+    Retry,
+}
+
+#[derive(Debug)]
+pub enum AuthCommandOutput {
+    LoginFinished(Result<Box<DataVault>, Error>),
+    RegisterFinished(Result<Option<Zeroizing<String>>, Error>),
+    RecoverFinished(Result<(), Error>),
 }
 
 #[derive(Debug)]
@@ -43,10 +90,11 @@ pub enum LoggedInMsg {
 }
 
 #[relm4::component(pub)]
-impl SimpleComponent for AuthPrompt {
+impl Component for AuthPrompt {
     type Init = Rc<RefCell<AppState>>;
     type Input = AuthMsg;
     type Output = LoggedInMsg;
+    type CommandOutput = AuthCommandOutput;
 
     view! {
         adw::ApplicationWindow {
@@ -69,6 +117,8 @@ impl SimpleComponent for AuthPrompt {
                     #[wrap(Some)]
                     set_title_widget = &gtk::Box {
                         add_css_class: "linked",
+                        #[watch]
+                        set_sensitive: !model.is_busy,
                         append: group = &gtk::ToggleButton {
                             set_label: "Login",
                             set_has_frame: true,
@@ -85,6 +135,58 @@ impl SimpleComponent for AuthPrompt {
                                 sender.input(AuthMsg::SetMode(AuthAppMode::Register));
                             }
                         },
+                        gtk::ToggleButton {
+                            set_label: "Recover",
+                            set_has_frame: true,
+                            set_group: Some(&group),
+                            connect_clicked[sender] => move |_| {
+                                sender.input(AuthMsg::SetMode(AuthAppMode::Recover));
+                            }
+                        },
+                    },
+
+                    pack_end = &gtk::Button {
+                        set_has_frame: true,
+                        set_icon_name: "settings",
+                        set_tooltip_text: Some("Preferences"),
+
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AuthMsg::ShowPreferences);
+                        }
+                    },
+                },
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_margin_all: 10,
+                    set_spacing: 10,
+                    #[watch]
+                    set_sensitive: !model.is_busy,
+
+                    gtk::Label {
+                        set_label: "Profile"
+                    },
+                    #[local_ref]
+                    profile_drop_down -> gtk::DropDown {
+                        set_hexpand: true,
+                    },
+                    gtk::Button {
+                        set_has_frame: true,
+                        set_icon_name: "plus-large",
+                        set_tooltip_text: Some("New profile"),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AuthMsg::ShowProfilePrompt);
+                        }
+                    },
+                    gtk::Button {
+                        set_has_frame: true,
+                        set_icon_name: "user-trash",
+                        set_tooltip_text: Some("Remove profile"),
+                        #[watch]
+                        set_sensitive: model.profile_names.n_items() > 1,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AuthMsg::RemoveProfilePress);
+                        }
                     },
                 },
 
@@ -98,6 +200,7 @@ impl SimpleComponent for AuthPrompt {
                     set_visible: match model.mode {
                         AuthAppMode::Login => true,
                         AuthAppMode::Register => false,
+                        AuthAppMode::Recover => false,
                     },
 
                     gtk::Label {
@@ -117,6 +220,8 @@ impl SimpleComponent for AuthPrompt {
                     gtk::Button {
                         set_margin_all: 40,
                         set_label: "Login",
+                        #[watch]
+                        set_sensitive: !model.is_busy,
                         connect_clicked[sender] => move |_| {
                             sender.input(AuthMsg::LoginPress);
                         }
@@ -133,6 +238,7 @@ impl SimpleComponent for AuthPrompt {
                     set_visible: match model.mode {
                         AuthAppMode::Login => false,
                         AuthAppMode::Register => true,
+                        AuthAppMode::Recover => false,
                     },
 
                     gtk::Label {
@@ -157,14 +263,99 @@ impl SimpleComponent for AuthPrompt {
                         set_input_purpose: gtk::InputPurpose::Password,
                         set_visibility: false,
                     },
+                    gtk::CheckButton {
+                        set_label: Some("Create a recovery key"),
+                        set_active: true,
+                        connect_toggled[sender] => move |check_button| {
+                            sender.input(AuthMsg::SetCreateRecoveryKey(check_button.is_active()));
+                        }
+                    },
                     gtk::Button {
                         set_margin_all: 40,
                         set_label: "Register",
+                        #[watch]
+                        set_sensitive: !model.is_busy,
                         connect_clicked[sender] => move |_| {
                             sender.input(AuthMsg::RegisterPress);
                         }
                     }
                 },
+
+                // Recover Box
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_margin_all: 10,
+                    set_spacing: 10,
+
+                    #[watch]
+                    set_visible: match model.mode {
+                        AuthAppMode::Login => false,
+                        AuthAppMode::Register => false,
+                        AuthAppMode::Recover => true,
+                    },
+
+                    gtk::Label {
+                        set_label: "Email"
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.recover_email,
+                    },
+                    gtk::Label {
+                        set_label: "Recovery Key"
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.recovery_code,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                    },
+                    gtk::Label {
+                        set_label: "New Password"
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.recover_password1,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                    },
+                    gtk::Label {
+                        set_label: "Confirm New Password"
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.recover_password2,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                    },
+                    gtk::Button {
+                        set_margin_all: 40,
+                        set_label: "Recover",
+                        #[watch]
+                        set_sensitive: !model.is_busy,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AuthMsg::RecoverPress);
+                        }
+                    }
+                },
+
+                // Shown while a request is running
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_halign: gtk::Align::Center,
+                    set_margin_bottom: 20,
+                    set_spacing: 10,
+
+                    #[watch]
+                    set_visible: model.is_busy,
+
+                    gtk::Spinner {
+                        #[watch]
+                        set_spinning: model.is_busy,
+                    },
+                    gtk::Button {
+                        set_label: "Cancel",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AuthMsg::CancelPress);
+                        }
+                    }
+                },
             },
         },
     }
@@ -174,6 +365,45 @@ impl SimpleComponent for AuthPrompt {
         root: Self::Root,
         sender: ComponentSender<Self>,
     ) -> ComponentParts<Self> {
+        let preferences_prompt = PreferencesPrompt::builder()
+            .transient_for(&root)
+            .launch(state.clone())
+            .detach();
+
+        let profile_prompt = ProfilePrompt::builder()
+            .transient_for(&root)
+            .launch(state.clone())
+            .forward(sender.input_sender(), |msg| match msg {
+                ProfilePromptOutput::Added(name) => AuthMsg::ProfileAdded(name),
+            });
+
+        let config = state.borrow().settings.effective();
+
+        let profile_names = gtk::StringList::default();
+        for profile in &config.profiles {
+            profile_names.append(&profile.name);
+        }
+
+        let profile_drop_down =
+            gtk::DropDown::new(Some(profile_names.clone()), gtk::Expression::NONE);
+        profile_drop_down.set_selected(profile_position(&config, &config.active_profile));
+
+        // Sends the name rather than the position, which shifts when profiles are removed
+        let profile_sender = sender.clone();
+        profile_drop_down.connect_selected_notify(move |drop_down| {
+            if let Some(item) = drop_down
+                .selected_item()
+                .and_downcast::<gtk::StringObject>()
+            {
+                profile_sender.input(AuthMsg::SelectProfile(item.string().to_string()));
+            }
+        });
+
+        let login_email = gtk::EntryBuffer::default();
+        if let Some(email) = &config.active_profile().email {
+            login_email.set_text(email);
+        }
+
         let model = AuthPrompt {
             is_active: true,
 
@@ -181,46 +411,282 @@ impl SimpleComponent for AuthPrompt {
 
             mode: AuthAppMode::Login,
 
-            login_email: gtk::EntryBuffer::default(),
+            is_busy: false,
+            cancellation: Cancellation::default(),
+
+            profile_names,
+            profile_drop_down,
+
+            login_email,
             login_password: gtk::EntryBuffer::default(),
 
             register_email: gtk::EntryBuffer::default(),
             register_password1: gtk::EntryBuffer::default(),
             register_password2: gtk::EntryBuffer::default(),
+            create_recovery_key: true,
+
+            recover_email: gtk::EntryBuffer::default(),
+            recovery_code: gtk::EntryBuffer::default(),
+            recover_password1: gtk::EntryBuffer::default(),
+            recover_password2: gtk::EntryBuffer::default(),
 
             response_dialog: AuthResponseDialog::builder()
                 .transient_for(&root)
-                .launch(()),
+                .launch(())
+                .forward(sender.input_sender(), |msg| match msg {
+                    AuthResponseDialogOutput::Retry => AuthMsg::Retry,
+                }),
+            recovery_key_dialog: RecoveryKeyDialog::builder().transient_for(&root).launch(()),
+            preferences_prompt,
+            profile_prompt,
         };
 
+        let profile_drop_down = &model.profile_drop_down;
+
         let widgets = view_output!();
 
         ComponentParts { model, widgets }
     }
 
-    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
         match msg {
             AuthMsg::SetMode(mode) => {
                 self.mode = mode;
             }
-            AuthMsg::LoginPress => {
-                let email = self.login_email.text();
-                let password = self.login_password.text();
+            AuthMsg::SetCreateRecoveryKey(create_recovery_key) => {
+                self.create_recovery_key = create_recovery_key;
+            }
+            AuthMsg::SelectProfile(name) => {
+                self.select_profile(&name, &sender);
+            }
+
+            AuthMsg::ShowProfilePrompt => {
+                self.profile_prompt.emit(ProfilePromptMsg::Show);
+            }
 
-                if login_action(&email, &password, self).is_ok() {
-                    sender.output(LoggedInMsg::LoggedIn).unwrap();
-                    self.is_active = false;
+            AuthMsg::ProfileAdded(name) => {
+                self.profile_names.append(&name);
+                self.select_profile(&name, &sender);
+            }
+
+            // Moves to the first other profile, then drops the one that was selected
+            AuthMsg::RemoveProfilePress => {
+                let config = self.app_state.borrow().settings.effective();
+                let removed = config.active_profile.clone();
+
+                let next = match config
+                    .profiles
+                    .iter()
+                    .find(|profile| profile.name != removed)
+                {
+                    Some(profile) => profile.name.clone(),
+                    None => return,
+                };
+
+                self.select_profile(&next, &sender);
+
+                if let Err(e) = self.app_state.borrow_mut().remove_profile(&removed) {
+                    println!("Failed to remove profile: {}", e);
+                    return;
                 }
+
+                self.profile_names
+                    .remove(profile_position(&config, &removed));
+            }
+
+            AuthMsg::LoginPress => {
+                let email = self.login_email.text().to_string();
+                let password = Zeroizing::new(self.login_password.text().to_string());
+
+                let connection = self.start_request();
+                let cancellation = self.cancellation.clone();
+
+                sender.spawn_oneshot_command(move || {
+                    AuthCommandOutput::LoginFinished(
+                        login_action(&email, &password, &connection, &cancellation).map(Box::new),
+                    )
+                });
             }
 
             AuthMsg::RegisterPress => {
-                let email = self.register_email.text();
-                let password1 = self.register_password1.text();
-                let password2 = self.register_password2.text();
+                let email = self.register_email.text().to_string();
+                let password1 = Zeroizing::new(self.register_password1.text().to_string());
+                let password2 = Zeroizing::new(self.register_password2.text().to_string());
+                let create_recovery_key = self.create_recovery_key;
+
+                let connection = self.start_request();
+                let cancellation = self.cancellation.clone();
+
+                sender.spawn_oneshot_command(move || {
+                    AuthCommandOutput::RegisterFinished(register_action(
+                        &email,
+                        &password1,
+                        &password2,
+                        create_recovery_key,
+                        &connection,
+                        &cancellation,
+                    ))
+                });
+            }
+
+            AuthMsg::RecoverPress => {
+                let email = self.recover_email.text().to_string();
+                let recovery_code = Zeroizing::new(self.recovery_code.text().to_string());
+                let password1 = Zeroizing::new(self.recover_password1.text().to_string());
+                let password2 = Zeroizing::new(self.recover_password2.text().to_string());
+
+                let connection = self.start_request();
+                let cancellation = self.cancellation.clone();
+
+                sender.spawn_oneshot_command(move || {
+                    AuthCommandOutput::RecoverFinished(recover_action(
+                        &email,
+                        &recovery_code,
+                        &password1,
+                        &password2,
+                        &connection,
+                        &cancellation,
+                    ))
+                });
+            }
+
+            AuthMsg::CancelPress => {
+                self.cancellation.cancel();
+            }
+
+            AuthMsg::ShowPreferences => {
+                self.preferences_prompt.emit(PreferencesPromptMsg::Show);
+            }
 
-                register_action(&email, &password1, &password2, self);
+            // Sends the request of the current mode again after a network error
+            AuthMsg::Retry => match self.mode {
+                AuthAppMode::Login => sender.input(AuthMsg::LoginPress),
+                AuthAppMode::Register => sender.input(AuthMsg::RegisterPress),
+                AuthAppMode::Recover => sender.input(AuthMsg::RecoverPress),
+            },
+        }
+    }
+
+    fn update_cmd(
+        &mut self,
+        msg: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        self.is_busy = false;
+
+        match msg {
+            AuthCommandOutput::LoginFinished(Ok(data_vault)) => {
+                let mut app_state = self.app_state.borrow_mut();
+                app_state.vault = Some(*data_vault);
+
+                // Filled in for the next login to this profile
+                let email = self.login_email.text().to_string();
+                let active_profile = app_state.settings.active_profile.clone();
+                app_state.settings.update_saved(|config| {
+                    if let Some(profile) = config.profile_mut(&active_profile) {
+                        profile.email = Some(email);
+                    }
+                });
+
+                drop(app_state);
+
+                // Drop the master password from the entry buffer as soon as it is used
+                self.login_password.set_text("");
+
+                sender.output(LoggedInMsg::LoggedIn).unwrap();
+                self.is_active = false;
             }
+            AuthCommandOutput::LoginFinished(Err(e)) => {
+                if e != Error::Cancelled {
+                    self.response_dialog
+                        .emit(AuthResponseDialogMsg::LoginFail(e));
+                }
+            }
+
+            AuthCommandOutput::RegisterFinished(Ok(emergency_kit)) => match emergency_kit {
+                Some(emergency_kit) => self
+                    .recovery_key_dialog
+                    .emit(RecoveryKeyDialogMsg::Show(emergency_kit)),
+                None => self
+                    .response_dialog
+                    .emit(AuthResponseDialogMsg::RegisterSuccess),
+            },
+            AuthCommandOutput::RegisterFinished(Err(e)) => {
+                if e != Error::Cancelled {
+                    self.response_dialog
+                        .emit(AuthResponseDialogMsg::RegisterFail(e));
+                }
+            }
+
+            AuthCommandOutput::RecoverFinished(Ok(())) => {
+                self.recovery_code.set_text("");
+                self.recover_password1.set_text("");
+                self.recover_password2.set_text("");
+
+                self.response_dialog
+                    .emit(AuthResponseDialogMsg::RecoverSuccess);
+            }
+            AuthCommandOutput::RecoverFinished(Err(e)) => {
+                if e != Error::Cancelled {
+                    self.response_dialog
+                        .emit(AuthResponseDialogMsg::RecoverFail(e));
+                }
+            }
+        }
+    }
+}
+
+impl AuthPrompt {
+    // Locks the inputs and hands out what the background request needs
+    fn start_request(&mut self) -> Connection {
+        self.is_busy = true;
+        self.cancellation = Cancellation::default();
+
+        self.app_state.borrow().connection.clone()
+    }
+
+    // A profile that is still logged in in the background opens right away
+    fn select_profile(&mut self, name: &str, sender: &ComponentSender<Self>) {
+        let mut app_state = self.app_state.borrow_mut();
+
+        if app_state.settings.active_profile == name {
+            return;
+        }
+
+        if let Err(e) = app_state.switch_profile(name) {
+            println!("Failed to switch profile: {}", e);
+            return;
+        }
+
+        let config = app_state.settings.saved.clone();
+        self.profile_drop_down
+            .set_selected(profile_position(&config, name));
+
+        if app_state.vault.is_some() {
+            drop(app_state);
+
+            sender.output(LoggedInMsg::LoggedIn).unwrap();
+            self.is_active = false;
+            return;
         }
+
+        match &config.active_profile().email {
+            Some(email) => self.login_email.set_text(email),
+            None => self.login_email.set_text(""),
+        }
+        self.login_password.set_text("");
+    }
+}
+
+fn profile_position(config: &Config, name: &str) -> u32 {
+    match config
+        .profiles
+        .iter()
+        .position(|profile| profile.name == name)
+    {
+        Some(position) => position as u32,
+        None => 0,
     }
 }
 
@@ -1,24 +1,38 @@
 use adw::prelude::*;
 use relm4::prelude::*;
 
+use crate::error::Error;
+
 pub struct AuthResponseDialog {
     pub error_text: String,
+    body_text: String,
+    can_retry: bool,
     is_active: bool,
 }
 
 #[derive(Debug)]
 pub enum AuthResponseDialogMsg {
-    LoginFail(String),
+    LoginFail(Error),
 
     RegisterSuccess,
-    RegisterFail(String),
+    RegisterFail(Error),
+
+    RecoverSuccess,
+    RecoverFail(Error),
+
+    Retry,
+}
+
+#[derive(Debug)]
+pub enum AuthResponseDialogOutput {
+    Retry,
 }
 
 #[relm4::component(pub)]
 impl SimpleComponent for AuthResponseDialog {
     type Init = ();
     type Input = AuthResponseDialogMsg;
-    type Output = ();
+    type Output = AuthResponseDialogOutput;
 
     view! {
         #[name = "dialog"]
@@ -27,18 +41,30 @@ impl SimpleComponent for AuthResponseDialog {
             set_visible: model.is_active,
             #[watch]
             set_heading: Some(&model.error_text),
+            #[watch]
+            set_body: &model.body_text,
+            add_response: ("retry", "Retry"),
+            #[watch]
+            set_response_enabled: ("retry", model.can_retry),
             add_response: ("close", "Close"),
+            set_close_response: "close",
             set_hide_on_close: true,
+
+            connect_response: (Some("retry"), move |_, _| {
+                sender.input(AuthResponseDialogMsg::Retry);
+            }),
         }
     }
 
     fn init(
         (): Self::Init,
         root: Self::Root,
-        _sender: ComponentSender<Self>,
+        sender: ComponentSender<Self>,
     ) -> ComponentParts<Self> {
         let model = AuthResponseDialog {
             error_text: String::new(),
+            body_text: String::new(),
+            can_retry: false,
             is_active: false,
         };
 
@@ -47,11 +73,13 @@ impl SimpleComponent for AuthResponseDialog {
         ComponentParts { model, widgets }
     }
 
-    fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+        self.can_retry = false;
+        self.body_text.clear();
+
         match msg {
-            AuthResponseDialogMsg::LoginFail(error_text) => {
-                self.error_text = error_text;
-                self.is_active = true;
+            AuthResponseDialogMsg::LoginFail(error) => {
+                self.show_error(&error);
             }
 
             AuthResponseDialogMsg::RegisterSuccess => {
@@ -59,10 +87,39 @@ impl SimpleComponent for AuthResponseDialog {
                 self.is_active = true;
             }
 
-            AuthResponseDialogMsg::RegisterFail(error_text) => {
-                self.error_text = error_text;
+            AuthResponseDialogMsg::RegisterFail(error) => {
+                self.show_error(&error);
+            }
+
+            AuthResponseDialogMsg::RecoverSuccess => {
+                self.error_text = "Account recovered, log in with the new password".to_string();
                 self.is_active = true;
             }
+
+            AuthResponseDialogMsg::Retry => {
+                self.is_active = false;
+                sender.output(AuthResponseDialogOutput::Retry).unwrap();
+            }
+
+            AuthResponseDialogMsg::RecoverFail(error) => {
+                self.show_error(&error);
+            }
+        }
+    }
+}
+
+impl AuthResponseDialog {
+    // Requests that never reached the server can be sent again from the dialog
+    fn show_error(&mut self, error: &Error) {
+        self.error_text = error.to_string();
+        self.can_retry = error.is_retryable();
+
+        if let Error::Tls(_) = error {
+            self.body_text = "The connection was stopped before anything was sent. Someone may be \
+                intercepting it, or the server certificate changed. Check the CA bundle and the \
+                certificate pin of this profile with the server administrator."
+                .to_string();
         }
+        self.is_active = true;
     }
 }
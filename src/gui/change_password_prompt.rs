@@ -0,0 +1,278 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{
+    actions::{
+        apply_password_change, change_password_action, prepare_password_change, PasswordChange,
+    },
+    change_password_response_dialog::{
+        ChangePasswordResponseDialog, ChangePasswordResponseDialogMsg,
+    },
+};
+use crate::error::Error;
+use crate::requests::Cancellation;
+use crate::AppState;
+
+use adw::prelude::*;
+use relm4::{component::Connector, prelude::*};
+use zeroize::Zeroizing;
+
+pub struct ChangePasswordPrompt {
+    is_active: bool,
+
+    // Set while the entries are re-encrypted and uploaded in the background
+    is_busy: bool,
+    cancellation: Cancellation,
+
+    current_password: gtk::EntryBuffer,
+    new_password1: gtk::EntryBuffer,
+    new_password2: gtk::EntryBuffer,
+
+    pub response_dialog: Connector<ChangePasswordResponseDialog>,
+
+    pub app_state: Rc<RefCell<AppState>>,
+}
+
+#[derive(Debug)]
+pub enum ChangePasswordPromptMsg {
+    ChangePress,
+    CancelPress,
+
+    Show,
+}
+
+#[derive(Debug)]
+pub enum ChangePasswordCommandOutput {
+    ChangeFinished(Result<PasswordChange, Error>),
+}
+
+#[derive(Debug)]
+pub enum ChangePasswordPromptOutput {
+    SessionExpired,
+}
+
+#[relm4::component(pub)]
+impl Component for ChangePasswordPrompt {
+    type Init = Rc<RefCell<AppState>>;
+    type Input = ChangePasswordPromptMsg;
+    type Output = ChangePasswordPromptOutput;
+    type CommandOutput = ChangePasswordCommandOutput;
+
+    view! {
+        adw::ApplicationWindow {
+            set_title: Some("Change Master Password"),
+            set_modal: true,
+            set_resizable: false,
+            set_default_size: (500, 300),
+            set_css_classes: &["background", "csd"],
+            set_hide_on_close: true,
+
+            #[watch]
+            set_visible: model.is_active,
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+
+                adw::HeaderBar {
+                    set_show_end_title_buttons: true,
+                },
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_margin_all: 10,
+                    set_spacing: 10,
+
+                    gtk::Label {
+                        set_label: "Current Password",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.current_password,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                    },
+
+                    gtk::Label {
+                        set_label: "New Password",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.new_password1,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                    },
+
+                    gtk::Label {
+                        set_label: "Confirm New Password",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.new_password2,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                    },
+
+                    gtk::Button {
+                        set_margin_all: 40,
+                        set_label: "Change Password",
+                        add_css_class: "destructive-action",
+                        #[watch]
+                        set_sensitive: !model.is_busy,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(ChangePasswordPromptMsg::ChangePress);
+                        }
+                    }
+                },
+
+                // Shown while the password is being changed
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_halign: gtk::Align::Center,
+                    set_margin_bottom: 20,
+                    set_spacing: 10,
+
+                    #[watch]
+                    set_visible: model.is_busy,
+
+                    gtk::Spinner {
+                        #[watch]
+                        set_spinning: model.is_busy,
+                    },
+                    gtk::Button {
+                        set_label: "Cancel",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(ChangePasswordPromptMsg::CancelPress);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn init(
+        state: Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = ChangePasswordPrompt {
+            is_active: false,
+
+            is_busy: false,
+            cancellation: Cancellation::default(),
+
+            current_password: gtk::EntryBuffer::default(),
+            new_password1: gtk::EntryBuffer::default(),
+            new_password2: gtk::EntryBuffer::default(),
+
+            response_dialog: ChangePasswordResponseDialog::builder()
+                .transient_for(&root)
+                .launch(()),
+
+            app_state: state,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
+        match msg {
+            ChangePasswordPromptMsg::ChangePress => {
+                let current_password = Zeroizing::new(self.current_password.text().to_string());
+                let new_password1 = self.new_password1.text();
+                let new_password2 = self.new_password2.text();
+
+                let app_state = self.app_state.borrow();
+
+                // Re-encrypting the entries needs the vault, so it happens before going off thread
+                let password_change =
+                    prepare_password_change(&new_password1, &new_password2, &app_state);
+
+                let connection = app_state.connection.clone();
+                drop(app_state);
+
+                let password_change = match password_change {
+                    Ok(password_change) => password_change,
+                    Err(e) => {
+                        self.handle_error(e, &sender);
+                        return;
+                    }
+                };
+
+                let new_password = Zeroizing::new(new_password1.to_string());
+
+                self.is_busy = true;
+                self.cancellation = Cancellation::default();
+                let cancellation = self.cancellation.clone();
+
+                sender.spawn_oneshot_command(move || {
+                    ChangePasswordCommandOutput::ChangeFinished(change_password_action(
+                        &current_password,
+                        &new_password,
+                        password_change,
+                        &connection,
+                        &cancellation,
+                    ))
+                });
+            }
+
+            ChangePasswordPromptMsg::CancelPress => {
+                self.cancellation.cancel();
+            }
+
+            ChangePasswordPromptMsg::Show => {
+                self.is_active = true;
+            }
+        }
+    }
+
+    fn update_cmd(
+        &mut self,
+        msg: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        self.is_busy = false;
+
+        match msg {
+            ChangePasswordCommandOutput::ChangeFinished(Ok(password_change)) => {
+                let is_confirmed = password_change.is_confirmed;
+
+                let result =
+                    apply_password_change(password_change, &mut self.app_state.borrow_mut());
+
+                match result {
+                    Ok(()) => {
+                        self.current_password.set_text("");
+                        self.new_password1.set_text("");
+                        self.new_password2.set_text("");
+
+                        if is_confirmed {
+                            self.response_dialog
+                                .emit(ChangePasswordResponseDialogMsg::ChangePasswordSuccess);
+                        } else {
+                            self.response_dialog
+                                .emit(ChangePasswordResponseDialogMsg::ChangePasswordUnconfirmed);
+                        }
+                    }
+                    Err(e) => self.handle_error(e, &sender),
+                }
+            }
+            ChangePasswordCommandOutput::ChangeFinished(Err(e)) => self.handle_error(e, &sender),
+        }
+    }
+}
+
+impl ChangePasswordPrompt {
+    fn handle_error(&mut self, error: Error, sender: &ComponentSender<Self>) {
+        match error {
+            Error::Cancelled => {}
+            e if e.is_unauthorized() => {
+                self.is_active = false;
+                sender
+                    .output(ChangePasswordPromptOutput::SessionExpired)
+                    .unwrap();
+            }
+            e => self
+                .response_dialog
+                .emit(ChangePasswordResponseDialogMsg::ChangePasswordFail(e)),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use adw::prelude::*;
+use relm4::prelude::*;
+
+use crate::error::Error;
+
+pub struct ChangePasswordResponseDialog {
+    pub error_text: String,
+    is_active: bool,
+}
+
+#[derive(Debug)]
+pub enum ChangePasswordResponseDialogMsg {
+    ChangePasswordFail(Error),
+    ChangePasswordSuccess,
+    // Sent but not answered, so either password may be the one the server expects
+    ChangePasswordUnconfirmed,
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for ChangePasswordResponseDialog {
+    type Init = ();
+    type Input = ChangePasswordResponseDialogMsg;
+    type Output = ();
+
+    view! {
+        #[name = "dialog"]
+        adw::MessageDialog {
+            #[watch]
+            set_visible: model.is_active,
+            #[watch]
+            set_heading: Some(&model.error_text),
+            add_response: ("close", "Close"),
+            set_hide_on_close: true,
+        }
+    }
+
+    fn init(
+        (): Self::Init,
+        root: Self::Root,
+        _sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = ChangePasswordResponseDialog {
+            error_text: String::new(),
+            is_active: false,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
+        match msg {
+            ChangePasswordResponseDialogMsg::ChangePasswordFail(error) => {
+                self.error_text = error.to_string();
+                self.is_active = true;
+            }
+
+            ChangePasswordResponseDialogMsg::ChangePasswordSuccess => {
+                self.error_text = "Password changed successfully".to_string();
+                self.is_active = true;
+            }
+
+            ChangePasswordResponseDialogMsg::ChangePasswordUnconfirmed => {
+                self.error_text = "The server did not confirm the new password, log in with it next time or with the current one if it is refused".to_string();
+                self.is_active = true;
+            }
+        }
+    }
+}
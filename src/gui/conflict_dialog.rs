@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use std::{cell::RefCell, rc::Rc};
+
+use super::actions::resolve_conflict;
+use crate::entries::describe_data_entry;
+use crate::model::{Ciphers, EncryptedDataEntry};
+use crate::outbox::SyncConflict;
+use crate::AppState;
+
+use adw::prelude::*;
+use relm4::prelude::*;
+
+// Shows both versions of an entry that changed here and on the server, one conflict at a time
+pub struct ConflictDialog {
+    is_active: bool,
+
+    conflicts: VecDeque<SyncConflict>,
+    heading: String,
+
+    local_fields: gtk::ListBox,
+    remote_fields: gtk::ListBox,
+
+    error_text: String,
+
+    app_state: Rc<RefCell<AppState>>,
+}
+
+#[derive(Debug)]
+pub enum ConflictDialogMsg {
+    Show(Vec<SyncConflict>),
+
+    KeepLocalPress,
+    KeepRemotePress,
+}
+
+#[derive(Debug)]
+pub enum ConflictDialogOutput {
+    // The vault changed if the server version was kept
+    Resolved,
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for ConflictDialog {
+    type Init = Rc<RefCell<AppState>>;
+    type Input = ConflictDialogMsg;
+    type Output = ConflictDialogOutput;
+
+    view! {
+        adw::ApplicationWindow {
+            set_title: Some("Sync Conflict"),
+            set_modal: true,
+            set_deletable: false,
+            set_default_size: (800, 400),
+            set_css_classes: &["background", "csd"],
+
+            #[watch]
+            set_visible: model.is_active,
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+
+                adw::HeaderBar {
+                    set_show_end_title_buttons: false,
+                },
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_margin_all: 10,
+                    set_spacing: 10,
+
+                    gtk::Label {
+                        set_wrap: true,
+                        #[watch]
+                        set_label: &model.heading,
+                    },
+
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Horizontal,
+                        set_homogeneous: true,
+                        set_spacing: 20,
+
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Vertical,
+                            set_spacing: 10,
+
+                            gtk::Label {
+                                add_css_class: "heading",
+                                set_label: "This Device",
+                            },
+
+                            #[local_ref]
+                            local_fields -> gtk::ListBox {
+                                add_css_class: "boxed-list",
+                                set_selection_mode: gtk::SelectionMode::None,
+                            },
+                        },
+
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Vertical,
+                            set_spacing: 10,
+
+                            gtk::Label {
+                                add_css_class: "heading",
+                                set_label: "Server",
+                            },
+
+                            #[local_ref]
+                            remote_fields -> gtk::ListBox {
+                                add_css_class: "boxed-list",
+                                set_selection_mode: gtk::SelectionMode::None,
+                            },
+                        },
+                    },
+
+                    gtk::Label {
+                        add_css_class: "error",
+                        set_wrap: true,
+                        #[watch]
+                        set_visible: !model.error_text.is_empty(),
+                        #[watch]
+                        set_label: &model.error_text,
+                    },
+
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Horizontal,
+                        set_halign: gtk::Align::Center,
+                        set_margin_all: 20,
+                        set_spacing: 10,
+
+                        gtk::Button {
+                            set_label: "Keep This Device's Version",
+                            connect_clicked[sender] => move |_| {
+                                sender.input(ConflictDialogMsg::KeepLocalPress);
+                            }
+                        },
+                        gtk::Button {
+                            set_label: "Keep Server Version",
+                            connect_clicked[sender] => move |_| {
+                                sender.input(ConflictDialogMsg::KeepRemotePress);
+                            }
+                        },
+                    },
+                },
+            }
+        }
+    }
+
+    fn init(
+        state: Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = ConflictDialog {
+            is_active: false,
+
+            conflicts: VecDeque::new(),
+            heading: String::new(),
+
+            local_fields: gtk::ListBox::default(),
+            remote_fields: gtk::ListBox::default(),
+
+            error_text: String::new(),
+
+            app_state: state,
+        };
+
+        let local_fields = &model.local_fields;
+        let remote_fields = &model.remote_fields;
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+        match msg {
+            ConflictDialogMsg::Show(conflicts) => {
+                self.conflicts.extend(conflicts);
+
+                if !self.is_active {
+                    self.show_next_conflict();
+                }
+            }
+
+            ConflictDialogMsg::KeepLocalPress => self.resolve(true, &sender),
+
+            ConflictDialogMsg::KeepRemotePress => self.resolve(false, &sender),
+        }
+    }
+}
+
+impl ConflictDialog {
+    fn show_next_conflict(&mut self) {
+        // Wiped along with the rows, so no secret stays in a hidden widget
+        self.local_fields.remove_all();
+        self.remote_fields.remove_all();
+        self.error_text.clear();
+
+        let conflict = match self.conflicts.front() {
+            Some(conflict) => conflict,
+            None => {
+                self.is_active = false;
+                return;
+            }
+        };
+
+        self.heading = format!(
+            "{} was changed both on this device and on the server, which version should be kept?",
+            conflict.change.name()
+        );
+
+        match self.app_state.borrow().vault.as_ref() {
+            Some(data_vault) => {
+                fill_fields(
+                    &self.local_fields,
+                    conflict.change.local_entry(),
+                    &data_vault.ciphers,
+                );
+                fill_fields(
+                    &self.remote_fields,
+                    conflict.remote.as_ref(),
+                    &data_vault.ciphers,
+                );
+            }
+            None => {
+                panic!("Failed to get reference to data vault");
+            }
+        }
+
+        self.is_active = true;
+    }
+
+    fn resolve(&mut self, keep_local: bool, sender: &ComponentSender<Self>) {
+        let conflict = match self.conflicts.front() {
+            Some(conflict) => conflict,
+            None => return,
+        };
+
+        if let Err(e) = resolve_conflict(conflict, keep_local, &mut self.app_state.borrow_mut()) {
+            println!("Failed to resolve conflict: {}", e);
+            self.error_text = e.to_string();
+            return;
+        }
+
+        self.conflicts.pop_front();
+        sender.output(ConflictDialogOutput::Resolved).unwrap();
+
+        self.show_next_conflict();
+    }
+}
+
+fn fill_fields(
+    list_box: &gtk::ListBox,
+    encrypted_data_entry: Option<&EncryptedDataEntry>,
+    ciphers: &Ciphers,
+) {
+    let encrypted_data_entry = match encrypted_data_entry {
+        Some(encrypted_data_entry) => encrypted_data_entry,
+        None => {
+            list_box.append(&adw::ActionRow::builder().title("Deleted").build());
+            return;
+        }
+    };
+
+    let fields = match describe_data_entry(encrypted_data_entry, ciphers) {
+        Ok(fields) => fields,
+        Err(e) => {
+            list_box.append(
+                &adw::ActionRow::builder()
+                    .title("Cannot be shown")
+                    .subtitle(e.to_string())
+                    .build(),
+            );
+            return;
+        }
+    };
+
+    for field in fields.iter() {
+        if field.is_secret {
+            let row = adw::PasswordEntryRow::builder()
+                .title(field.label)
+                .editable(false)
+                .build();
+            row.set_text(&field.value);
+            list_box.append(&row);
+        } else {
+            let row = adw::EntryRow::builder()
+                .title(field.label)
+                .editable(false)
+                .build();
+            row.set_text(&field.value);
+            list_box.append(&row);
+        }
+    }
+}
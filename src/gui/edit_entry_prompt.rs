@@ -0,0 +1,708 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{
+    actions::*,
+    add_entry_response_dialog::{
+        AddEntryResponseDialog, AddEntryResponseDialogMsg, AddEntryResponseDialogOutput,
+    },
+    entry_list_item::EntryListItem,
+};
+use crate::error::Error;
+use crate::model::entry_key;
+use crate::requests::Cancellation;
+use crate::vault_entry::VaultEntry;
+use crate::AppState;
+
+use adw::prelude::*;
+use relm4::{prelude::*, Controller};
+
+pub struct EditPassword {
+    name: gtk::EntryBuffer,
+    username: gtk::EntryBuffer,
+    password: gtk::EntryBuffer,
+    url: gtk::EntryBuffer,
+    expiration_date: gtk::EntryBuffer,
+}
+
+pub struct EditNote {
+    name: gtk::EntryBuffer,
+    content: gtk::TextBuffer,
+}
+
+pub struct EditCard {
+    name: gtk::EntryBuffer,
+    cardholder_name: gtk::EntryBuffer,
+    card_number: gtk::EntryBuffer,
+    security_code: gtk::EntryBuffer,
+    expiration_date: gtk::EntryBuffer,
+}
+
+pub struct EditTOTPEntry {
+    name: gtk::EntryBuffer,
+    algorithm: gtk::EntryBuffer,
+    secret: gtk::EntryBuffer,
+    digits: gtk::EntryBuffer,
+    skew: gtk::EntryBuffer,
+    period: gtk::EntryBuffer,
+}
+
+pub struct EditEntryPrompt {
+    is_active: bool,
+
+    // The entry as it was when the edit started, None while the prompt is hidden
+    entry: Option<VaultEntry>,
+    base_revision: Option<u64>,
+
+    // Set while the change is uploaded in the background
+    is_busy: bool,
+    cancellation: Cancellation,
+    // Bumped by every save and every cancel, so a result for an older save is known to be one
+    // the user walked away from
+    save_generation: u64,
+    // Hidden with the fields kept while the user logs in again
+    is_waiting_for_session: bool,
+
+    edit_password: EditPassword,
+    edit_note: EditNote,
+    edit_card: EditCard,
+    edit_totp: EditTOTPEntry,
+
+    pub response_dialog: Controller<AddEntryResponseDialog>,
+
+    pub app_state: Rc<RefCell<AppState>>,
+}
+
+#[derive(Debug)]
+pub enum EditEntryPromptMsg {
+    Show(VaultEntry),
+
+    SavePress,
+    CancelPress,
+    DiscardPress,
+    SessionRenewed,
+}
+
+#[derive(Debug)]
+pub enum EditEntryCommandOutput {
+    UpdateFinished(u64, Result<PendingUpdate, Error>),
+}
+
+#[derive(Debug)]
+pub enum EditEntryPromptOutput {
+    UpdatedEntryListItem(EntryListItem),
+    SessionExpired,
+}
+
+#[relm4::component(pub)]
+impl Component for EditEntryPrompt {
+    type Init = Rc<RefCell<AppState>>;
+    type Input = EditEntryPromptMsg;
+    type Output = EditEntryPromptOutput;
+    type CommandOutput = EditEntryCommandOutput;
+
+    view! {
+        adw::ApplicationWindow {
+            set_title: Some("Edit Entry"),
+            set_modal: true,
+            set_css_classes: &["background", "csd"],
+
+            #[watch]
+            set_visible: model.is_active,
+
+            // Closing the window throws the changes away like the discard button
+            connect_close_request[sender] => move |_| {
+                sender.input(EditEntryPromptMsg::DiscardPress);
+                gtk::glib::Propagation::Stop
+            },
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+                set_spacing: 20,
+
+                adw::HeaderBar {
+                    set_show_end_title_buttons: true,
+                },
+
+                // Edit Password Box
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_spacing: 10,
+                    set_margin_all: 10,
+
+                    #[watch]
+                    set_visible: matches!(model.entry, Some(VaultEntry::Password(_))),
+
+                    gtk::Label {
+                        set_label: "Name",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_password.name,
+                    },
+
+                    gtk::Label {
+                        set_label: "Username",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_password.username,
+                    },
+
+                    gtk::Label {
+                        set_label: "Password",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_password.password,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                    },
+
+                    gtk::Label {
+                        set_label: "URL",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_password.url,
+                    },
+
+                    gtk::Label {
+                        set_label: "Expiration Date",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_password.expiration_date,
+                    },
+                },
+
+                // Edit Note Box
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_spacing: 10,
+                    set_margin_all: 10,
+
+                    #[watch]
+                    set_visible: matches!(model.entry, Some(VaultEntry::Note(_))),
+
+                    gtk::Label {
+                        set_label: "Name",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_note.name,
+                    },
+
+                    gtk::Label {
+                        set_label: "Content",
+                    },
+                    gtk::TextView {
+                        set_buffer: Some(&model.edit_note.content),
+                        set_height_request: 100,
+                        inline_css: "border: 1px; border-radius: 6px; background-color: #3a3a3a;",
+                        set_top_margin: 10,
+                        set_bottom_margin: 10,
+                        set_left_margin: 10,
+                        set_right_margin: 10,
+                    },
+                },
+
+                // Edit Card Box
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_spacing: 10,
+                    set_margin_all: 10,
+
+                    #[watch]
+                    set_visible: matches!(model.entry, Some(VaultEntry::Card(_))),
+
+                    gtk::Label {
+                        set_label: "Name",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_card.name,
+                    },
+
+                    gtk::Label {
+                        set_label: "Cardholder Name",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_card.cardholder_name,
+                    },
+
+                    gtk::Label {
+                        set_label: "Card Number",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_card.card_number,
+                        set_visibility: false,
+                    },
+
+                    gtk::Label {
+                        set_label: "Security Code",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_card.security_code,
+                        set_visibility: false,
+                    },
+
+                    gtk::Label {
+                        set_label: "Expiration Date",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_card.expiration_date,
+                    },
+                },
+
+                // Edit TOTP Entry Box
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_spacing: 10,
+                    set_margin_all: 10,
+
+                    #[watch]
+                    set_visible: matches!(model.entry, Some(VaultEntry::TOTP(_))),
+
+                    gtk::Label {
+                        set_label: "Name",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_totp.name,
+                    },
+
+                    gtk::Label {
+                        set_label: "Algorithm",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_totp.algorithm,
+                    },
+
+                    gtk::Label {
+                        set_label: "Secret",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_totp.secret,
+                        set_visibility: false,
+                    },
+
+                    gtk::Label {
+                        set_label: "Digits",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_totp.digits,
+                    },
+
+                    gtk::Label {
+                        set_label: "Skew",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_totp.skew,
+                    },
+
+                    gtk::Label {
+                        set_label: "Period",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.edit_totp.period,
+                    },
+                },
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_halign: gtk::Align::Center,
+                    set_margin_all: 40,
+                    set_spacing: 20,
+
+                    gtk::Button {
+                        set_label: "Discard",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(EditEntryPromptMsg::DiscardPress);
+                        }
+                    },
+                    gtk::Button {
+                        set_label: "Save",
+                        add_css_class: "suggested-action",
+                        #[watch]
+                        set_sensitive: !model.is_busy,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(EditEntryPromptMsg::SavePress);
+                        }
+                    },
+                },
+
+                // Shown while the change is uploaded
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_halign: gtk::Align::Center,
+                    set_margin_bottom: 20,
+                    set_spacing: 10,
+
+                    #[watch]
+                    set_visible: model.is_busy,
+
+                    gtk::Spinner {
+                        #[watch]
+                        set_spinning: model.is_busy,
+                    },
+                    gtk::Button {
+                        set_label: "Cancel",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(EditEntryPromptMsg::CancelPress);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn init(
+        state: Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = EditEntryPrompt {
+            is_active: false,
+
+            entry: None,
+            base_revision: None,
+
+            is_busy: false,
+            cancellation: Cancellation::default(),
+            save_generation: 0,
+            is_waiting_for_session: false,
+
+            edit_password: EditPassword {
+                name: gtk::EntryBuffer::default(),
+                username: gtk::EntryBuffer::default(),
+                password: gtk::EntryBuffer::default(),
+                url: gtk::EntryBuffer::default(),
+                expiration_date: gtk::EntryBuffer::default(),
+            },
+            edit_note: EditNote {
+                name: gtk::EntryBuffer::default(),
+                content: gtk::TextBuffer::default(),
+            },
+            edit_card: EditCard {
+                name: gtk::EntryBuffer::default(),
+                cardholder_name: gtk::EntryBuffer::default(),
+                card_number: gtk::EntryBuffer::default(),
+                security_code: gtk::EntryBuffer::default(),
+                expiration_date: gtk::EntryBuffer::default(),
+            },
+            edit_totp: EditTOTPEntry {
+                name: gtk::EntryBuffer::default(),
+                algorithm: gtk::EntryBuffer::default(),
+                secret: gtk::EntryBuffer::default(),
+                digits: gtk::EntryBuffer::default(),
+                skew: gtk::EntryBuffer::default(),
+                period: gtk::EntryBuffer::default(),
+            },
+
+            response_dialog: AddEntryResponseDialog::builder()
+                .transient_for(&root)
+                .launch(())
+                .forward(sender.input_sender(), |msg| match msg {
+                    AddEntryResponseDialogOutput::Retry => EditEntryPromptMsg::SavePress,
+                }),
+
+            app_state: state,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
+        match msg {
+            // The revision is taken now, a change made elsewhere during the edit is a conflict
+            EditEntryPromptMsg::Show(entry) => {
+                let key = entry_key(entry.id(), entry.name(), entry.content_type());
+
+                self.base_revision = match self.app_state.borrow().vault.as_ref() {
+                    Some(data_vault) => data_vault.revision(&key),
+                    None => None,
+                };
+
+                self.fill_fields(&entry);
+                self.entry = Some(entry);
+                self.is_active = true;
+            }
+
+            EditEntryPromptMsg::SavePress => {
+                let edited_entry = match self.read_fields() {
+                    Some(Ok(edited_entry)) => edited_entry,
+                    Some(Err(e)) => {
+                        self.handle_error(e, &sender);
+                        return;
+                    }
+                    None => return,
+                };
+
+                let app_state = self.app_state.borrow();
+
+                // Encrypting needs the vault, so it happens here and only the upload is moved away
+                let pending_update = prepare_update(edited_entry, self.base_revision, &app_state);
+
+                let connection = app_state.connection.clone();
+                drop(app_state);
+
+                let pending_update = match pending_update {
+                    Ok(pending_update) => pending_update,
+                    Err(e) => {
+                        self.handle_error(e, &sender);
+                        return;
+                    }
+                };
+
+                self.is_busy = true;
+                self.cancellation = Cancellation::default();
+                self.save_generation += 1;
+
+                let cancellation = self.cancellation.clone();
+                let save_generation = self.save_generation;
+
+                sender.spawn_oneshot_command(move || {
+                    EditEntryCommandOutput::UpdateFinished(
+                        save_generation,
+                        update_entry_action(pending_update, &connection, &cancellation),
+                    )
+                });
+            }
+
+            // The fields stay as they are so the save can be tried again
+            EditEntryPromptMsg::CancelPress => {
+                self.abandon_save();
+            }
+
+            EditEntryPromptMsg::DiscardPress => {
+                self.abandon_save();
+                self.close();
+            }
+
+            EditEntryPromptMsg::SessionRenewed => {
+                if self.is_waiting_for_session {
+                    self.is_waiting_for_session = false;
+                    self.is_active = self.entry.is_some();
+                }
+            }
+        }
+    }
+
+    fn update_cmd(
+        &mut self,
+        msg: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        let EditEntryCommandOutput::UpdateFinished(save_generation, result) = msg;
+
+        if save_generation != self.save_generation {
+            self.finish_abandoned_save(result, &sender);
+            return;
+        }
+
+        self.is_busy = false;
+
+        match result {
+            Ok(pending_update) => {
+                let result = apply_update(pending_update, &mut self.app_state.borrow_mut());
+
+                match result {
+                    Ok(entry_list_item) => {
+                        self.close();
+
+                        sender
+                            .output(EditEntryPromptOutput::UpdatedEntryListItem(entry_list_item))
+                            .unwrap()
+                    }
+                    Err(e) => self.handle_error(e, &sender),
+                }
+            }
+            Err(e) => self.handle_error(e, &sender),
+        }
+    }
+}
+
+impl EditEntryPrompt {
+    fn fill_fields(&mut self, entry: &VaultEntry) {
+        match entry {
+            VaultEntry::Password(password) => {
+                self.edit_password.name.set_text(&password.name);
+                self.edit_password.username.set_text(&password.username);
+                self.edit_password.password.set_text(&password.password);
+                self.edit_password.url.set_text(&password.url);
+                self.edit_password
+                    .expiration_date
+                    .set_text(&password.expiration_date);
+            }
+            VaultEntry::Note(note) => {
+                self.edit_note.name.set_text(&note.name);
+                self.edit_note.content.set_text(&note.content);
+            }
+            VaultEntry::Card(card) => {
+                self.edit_card.name.set_text(&card.name);
+                self.edit_card
+                    .cardholder_name
+                    .set_text(&card.cardholder_name);
+                self.edit_card.card_number.set_text(&card.card_number);
+                self.edit_card.security_code.set_text(&card.security_code);
+                self.edit_card
+                    .expiration_date
+                    .set_text(&card.expiration_date);
+            }
+            VaultEntry::TOTP(totp_entry) => {
+                self.edit_totp.name.set_text(&totp_entry.name);
+                self.edit_totp.algorithm.set_text(&totp_entry.algorithm);
+                self.edit_totp.secret.set_text(&totp_entry.secret);
+                self.edit_totp
+                    .digits
+                    .set_text(totp_entry.digits.to_string());
+                self.edit_totp.skew.set_text(totp_entry.skew.to_string());
+                self.edit_totp
+                    .period
+                    .set_text(totp_entry.period.to_string());
+            }
+        }
+    }
+
+    // Fields the prompt does not show, like the id and creation date, are kept
+    fn read_fields(&self) -> Option<Result<VaultEntry, Error>> {
+        let edited_entry = match self.entry.as_ref()? {
+            VaultEntry::Password(password) => {
+                let mut password = password.clone();
+                password.name = self.edit_password.name.text().to_string();
+                password.username = self.edit_password.username.text().to_string();
+                password.password = self.edit_password.password.text().to_string();
+                password.url = self.edit_password.url.text().to_string();
+                password.expiration_date = self.edit_password.expiration_date.text().to_string();
+
+                VaultEntry::Password(password)
+            }
+            VaultEntry::Note(note) => {
+                let mut note = note.clone();
+                note.name = self.edit_note.name.text().to_string();
+                note.content = self
+                    .edit_note
+                    .content
+                    .text(
+                        &self.edit_note.content.start_iter(),
+                        &self.edit_note.content.end_iter(),
+                        false,
+                    )
+                    .to_string();
+
+                VaultEntry::Note(note)
+            }
+            VaultEntry::Card(card) => {
+                let mut card = card.clone();
+                card.name = self.edit_card.name.text().to_string();
+                card.cardholder_name = self.edit_card.cardholder_name.text().to_string();
+                card.card_number = self.edit_card.card_number.text().to_string();
+                card.security_code = self.edit_card.security_code.text().to_string();
+                card.expiration_date = self.edit_card.expiration_date.text().to_string();
+
+                VaultEntry::Card(card)
+            }
+            VaultEntry::TOTP(totp_entry) => {
+                let algorithm = self.edit_totp.algorithm.text();
+
+                let (digits, skew, period) = match parse_totp_settings(
+                    &algorithm,
+                    &self.edit_totp.digits.text(),
+                    &self.edit_totp.skew.text(),
+                    &self.edit_totp.period.text(),
+                ) {
+                    Ok(settings) => settings,
+                    Err(e) => return Some(Err(e)),
+                };
+
+                let mut totp_entry = totp_entry.clone();
+                totp_entry.name = self.edit_totp.name.text().to_string();
+                totp_entry.algorithm = algorithm.to_string();
+                totp_entry.secret = self.edit_totp.secret.text().to_string();
+                totp_entry.digits = digits;
+                totp_entry.skew = skew;
+                totp_entry.period = period;
+
+                VaultEntry::TOTP(totp_entry)
+            }
+        };
+
+        Some(Ok(edited_entry))
+    }
+
+    // The request may already have reached the server, its result is dealt with when it comes
+    fn abandon_save(&mut self) {
+        if self.is_busy {
+            self.cancellation.cancel();
+            self.save_generation += 1;
+            self.is_busy = false;
+        }
+    }
+
+    // A change the server took despite the cancel is kept, the prompt is left as the user has it
+    fn finish_abandoned_save(
+        &mut self,
+        result: Result<PendingUpdate, Error>,
+        sender: &ComponentSender<Self>,
+    ) {
+        let pending_update = match result {
+            Ok(pending_update) => pending_update,
+            Err(e) => {
+                println!("Cancelled update ended with: {}", e);
+                return;
+            }
+        };
+
+        match apply_cancelled_update(pending_update, &mut self.app_state.borrow_mut()) {
+            Ok(Some(entry_list_item)) => sender
+                .output(EditEntryPromptOutput::UpdatedEntryListItem(entry_list_item))
+                .unwrap(),
+            Ok(None) => {}
+            Err(e) => println!("Failed to apply cancelled update: {}", e),
+        }
+    }
+
+    // The fields are emptied so that no secret is left behind in the hidden window
+    fn close(&mut self) {
+        self.is_active = false;
+        self.is_waiting_for_session = false;
+        self.entry = None;
+        self.base_revision = None;
+
+        for buffer in [
+            &self.edit_password.name,
+            &self.edit_password.username,
+            &self.edit_password.password,
+            &self.edit_password.url,
+            &self.edit_password.expiration_date,
+            &self.edit_note.name,
+            &self.edit_card.name,
+            &self.edit_card.cardholder_name,
+            &self.edit_card.card_number,
+            &self.edit_card.security_code,
+            &self.edit_card.expiration_date,
+            &self.edit_totp.name,
+            &self.edit_totp.algorithm,
+            &self.edit_totp.secret,
+            &self.edit_totp.digits,
+            &self.edit_totp.skew,
+            &self.edit_totp.period,
+        ] {
+            buffer.set_text("");
+        }
+
+        self.edit_note.content.set_text("");
+    }
+
+    fn handle_error(&mut self, error: Error, sender: &ComponentSender<Self>) {
+        match error {
+            Error::Cancelled => {}
+            // The prompt comes back with the edit once the user logged in again
+            e if e.is_unauthorized() => {
+                self.is_active = false;
+                self.is_waiting_for_session = true;
+                sender
+                    .output(EditEntryPromptOutput::SessionExpired)
+                    .unwrap();
+            }
+            e => self
+                .response_dialog
+                .emit(AddEntryResponseDialogMsg::EditEntryFail(e)),
+        }
+    }
+}
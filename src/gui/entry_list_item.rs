@@ -1,6 +1,8 @@
 use adw::prelude::*;
 use relm4::{prelude::*, typed_view::list::RelmListItem};
 
+use crate::vault_entry::VaultEntry;
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum EntryType {
     Password,
@@ -11,6 +13,7 @@ pub enum EntryType {
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct EntryListItem {
+    pub id: String,
     pub name: String,
     pub subtext: String,
     pub entry_type: EntryType,
@@ -18,14 +21,31 @@ pub struct EntryListItem {
 }
 
 impl EntryListItem {
-    pub fn new(name: &str, subtext: &str, entry_type: EntryType) -> EntryListItem {
+    pub fn new(id: &str, name: &str, subtext: &str, entry_type: EntryType) -> EntryListItem {
         EntryListItem {
+            id: id.to_string(),
             name: name.to_string(),
             subtext: subtext.to_string(),
             entry_type,
             is_selected: false,
         }
     }
+
+    pub fn from_vault_entry(vault_entry: &VaultEntry) -> EntryListItem {
+        let entry_type = match vault_entry {
+            VaultEntry::Password(_) => EntryType::Password,
+            VaultEntry::Note(_) => EntryType::Note,
+            VaultEntry::Card(_) => EntryType::Card,
+            VaultEntry::TOTP(_) => EntryType::TOTP,
+        };
+
+        EntryListItem::new(
+            vault_entry.id(),
+            vault_entry.name(),
+            vault_entry.subtext(),
+            entry_type,
+        )
+    }
 }
 
 pub struct Widgets {
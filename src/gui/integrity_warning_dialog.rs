@@ -0,0 +1,75 @@
+use adw::prelude::*;
+use relm4::prelude::*;
+
+pub struct IntegrityWarningDialog {
+    pub warning_text: String,
+    is_active: bool,
+}
+
+#[derive(Debug)]
+pub enum IntegrityWarningDialogMsg {
+    Show(Vec<String>),
+    Accept,
+}
+
+#[derive(Debug)]
+pub enum IntegrityWarningDialogOutput {
+    // The user checked the vault and takes what the server returned as the new baseline
+    AcceptServerState,
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for IntegrityWarningDialog {
+    type Init = ();
+    type Input = IntegrityWarningDialogMsg;
+    type Output = IntegrityWarningDialogOutput;
+
+    view! {
+        #[name = "dialog"]
+        adw::MessageDialog {
+            #[watch]
+            set_visible: model.is_active,
+            set_heading: Some("The server returned unexpected vault contents"),
+            #[watch]
+            set_body: &model.warning_text,
+            add_response: ("close", "Close"),
+            add_response: ("accept", "Accept Server State"),
+            set_hide_on_close: true,
+
+            connect_response: (Some("accept"), move |_, _| {
+                sender.input(IntegrityWarningDialogMsg::Accept);
+            }),
+        }
+    }
+
+    fn init(
+        (): Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = IntegrityWarningDialog {
+            warning_text: String::new(),
+            is_active: false,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+        match msg {
+            IntegrityWarningDialogMsg::Show(warnings) => {
+                self.warning_text = warnings.join("\n");
+                self.is_active = true;
+            }
+            IntegrityWarningDialogMsg::Accept => {
+                self.is_active = false;
+
+                sender
+                    .output(IntegrityWarningDialogOutput::AcceptServerState)
+                    .unwrap();
+            }
+        }
+    }
+}
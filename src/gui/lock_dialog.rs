@@ -0,0 +1,256 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::actions::reauthenticate_action;
+use crate::error::Error;
+use crate::requests::Cancellation;
+use crate::AppState;
+
+use adw::prelude::*;
+use relm4::prelude::*;
+use zeroize::Zeroizing;
+
+// Asks for the master password again when the server session ends, the vault stays unlocked
+pub struct LockDialog {
+    is_active: bool,
+
+    // Set while the login runs in the background
+    is_busy: bool,
+    cancellation: Cancellation,
+
+    reason: String,
+    email: String,
+    password: gtk::EntryBuffer,
+
+    error_text: String,
+
+    app_state: Rc<RefCell<AppState>>,
+}
+
+#[derive(Debug)]
+pub enum LockDialogMsg {
+    Show(String),
+
+    UnlockPress,
+    CancelPress,
+    LogoutPress,
+}
+
+#[derive(Debug)]
+pub enum LockDialogCommandOutput {
+    UnlockFinished(Result<(), Error>),
+}
+
+#[derive(Debug)]
+pub enum LockDialogOutput {
+    Unlocked,
+    LoggedOut,
+}
+
+#[relm4::component(pub)]
+impl Component for LockDialog {
+    type Init = Rc<RefCell<AppState>>;
+    type Input = LockDialogMsg;
+    type Output = LockDialogOutput;
+    type CommandOutput = LockDialogCommandOutput;
+
+    view! {
+        adw::ApplicationWindow {
+            set_title: Some("Session Expired"),
+            set_modal: true,
+            set_resizable: false,
+            set_deletable: false,
+            set_default_size: (500, 200),
+            set_css_classes: &["background", "csd"],
+
+            #[watch]
+            set_visible: model.is_active,
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+
+                adw::HeaderBar {
+                    set_show_end_title_buttons: false,
+                },
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_margin_all: 10,
+                    set_spacing: 10,
+
+                    gtk::Label {
+                        set_wrap: true,
+                        #[watch]
+                        set_label: &model.reason,
+                    },
+
+                    gtk::Label {
+                        #[watch]
+                        set_label: &format!("Master password for {}", model.email),
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.password,
+                        set_input_purpose: gtk::InputPurpose::Password,
+                        set_visibility: false,
+                        #[watch]
+                        set_sensitive: !model.is_busy,
+                        connect_activate[sender] => move |_| {
+                            sender.input(LockDialogMsg::UnlockPress);
+                        }
+                    },
+
+                    gtk::Label {
+                        add_css_class: "error",
+                        set_wrap: true,
+                        #[watch]
+                        set_visible: !model.error_text.is_empty(),
+                        #[watch]
+                        set_label: &model.error_text,
+                    },
+
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Horizontal,
+                        set_halign: gtk::Align::Center,
+                        set_margin_all: 20,
+                        set_spacing: 10,
+                        #[watch]
+                        set_sensitive: !model.is_busy,
+
+                        gtk::Button {
+                            set_label: "Log Out",
+                            add_css_class: "destructive-action",
+                            connect_clicked[sender] => move |_| {
+                                sender.input(LockDialogMsg::LogoutPress);
+                            }
+                        },
+                        gtk::Button {
+                            set_label: "Log In",
+                            add_css_class: "suggested-action",
+                            connect_clicked[sender] => move |_| {
+                                sender.input(LockDialogMsg::UnlockPress);
+                            }
+                        },
+                    },
+                },
+
+                // Shown while logging in
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_halign: gtk::Align::Center,
+                    set_margin_bottom: 20,
+                    set_spacing: 10,
+
+                    #[watch]
+                    set_visible: model.is_busy,
+
+                    gtk::Spinner {
+                        #[watch]
+                        set_spinning: model.is_busy,
+                    },
+                    gtk::Button {
+                        set_label: "Cancel",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(LockDialogMsg::CancelPress);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn init(
+        state: Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = LockDialog {
+            is_active: false,
+
+            is_busy: false,
+            cancellation: Cancellation::default(),
+
+            reason: String::new(),
+            email: String::new(),
+            password: gtk::EntryBuffer::default(),
+
+            error_text: String::new(),
+
+            app_state: state,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
+        match msg {
+            LockDialogMsg::Show(reason) => {
+                if let Some(data_vault) = &self.app_state.borrow().vault {
+                    self.email = data_vault.email.clone();
+                }
+
+                self.reason = reason;
+                self.error_text.clear();
+                self.is_active = true;
+            }
+
+            LockDialogMsg::UnlockPress => {
+                if self.is_busy {
+                    return;
+                }
+
+                let email = self.email.clone();
+                let password = Zeroizing::new(self.password.text().to_string());
+
+                let connection = self.app_state.borrow().connection.clone();
+
+                self.is_busy = true;
+                self.cancellation = Cancellation::default();
+                let cancellation = self.cancellation.clone();
+
+                sender.spawn_oneshot_command(move || {
+                    LockDialogCommandOutput::UnlockFinished(reauthenticate_action(
+                        &email,
+                        &password,
+                        &connection,
+                        &cancellation,
+                    ))
+                });
+            }
+
+            LockDialogMsg::CancelPress => {
+                self.cancellation.cancel();
+            }
+
+            LockDialogMsg::LogoutPress => {
+                self.password.set_text("");
+                self.is_active = false;
+
+                sender.output(LockDialogOutput::LoggedOut).unwrap();
+            }
+        }
+    }
+
+    fn update_cmd(
+        &mut self,
+        msg: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        self.is_busy = false;
+
+        match msg {
+            LockDialogCommandOutput::UnlockFinished(Ok(())) => {
+                self.password.set_text("");
+                self.is_active = false;
+
+                sender.output(LockDialogOutput::Unlocked).unwrap();
+            }
+            LockDialogCommandOutput::UnlockFinished(Err(Error::Cancelled)) => {}
+            LockDialogCommandOutput::UnlockFinished(Err(e)) => {
+                println!("{}", e);
+                self.error_text = e.to_string();
+            }
+        }
+    }
+}
@@ -1,15 +1,51 @@
+use crate::error::Error;
 use crate::gui::entry_list_item::{EntryListItem, EntryType};
-use crate::gui::utils::{generate_random_password, make_list_view_wrapper_from_data_vault};
+use crate::gui::utils::{
+    copy_secret_to_clipboard, generate_random_password, make_list_view_wrapper_from_data_vault,
+};
+use crate::model::{Card, Note, Password, TOTPEntry};
+use crate::vault_entry::EntryData;
 use crate::AppState;
 use adw::prelude::*;
+use chrono::{DateTime, Local, TimeDelta, Utc};
 use relm4::{prelude::*, typed_view::list::TypedListView};
 use std::borrow::BorrowMut;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-
-use super::actions::{delete_entry_action, logout_action};
+use std::time::{Duration, Instant};
+
+use super::actions::{
+    accept_server_state, apply_deletion, apply_outbox_sync, apply_refresh, delete_entry_action,
+    logout_action, prepare_deletion, prepare_outbox_sync, prepare_refresh, refresh_action,
+    sync_outbox_action, EntryListChange, PendingDeletion, RefreshOutcome, RefreshedVault,
+    SyncReport,
+};
 use super::add_entry_prompt::{AddEntryPrompt, AddEntryPromptMsg, AddEntryPromptOutput};
-use super::utils::{get_list_view_item_index, make_active_entries_data, ActiveEntriesData};
+use super::change_password_prompt::{
+    ChangePasswordPrompt, ChangePasswordPromptMsg, ChangePasswordPromptOutput,
+};
+use super::conflict_dialog::{ConflictDialog, ConflictDialogMsg, ConflictDialogOutput};
+use super::edit_entry_prompt::{EditEntryPrompt, EditEntryPromptMsg, EditEntryPromptOutput};
+use super::integrity_warning_dialog::{
+    IntegrityWarningDialog, IntegrityWarningDialogMsg, IntegrityWarningDialogOutput,
+};
+use super::lock_dialog::{LockDialog, LockDialogMsg, LockDialogOutput};
+use super::preferences_prompt::{PreferencesPrompt, PreferencesPromptMsg};
+use super::utils::{
+    fill_list_view_wrapper, get_list_view_item_index, make_active_entries_data, ActiveEntriesData,
+};
+
+// How often the inactivity delay is compared against the last input
+const AUTO_LOCK_CHECK_SECS: u32 = 10;
+
+// How long before the server session ends the banner asks to log in again
+const SESSION_WARNING_SECS: i64 = 300;
+
+// How often changes made while the server was unreachable are sent again
+const OUTBOX_SYNC_SECS: u32 = 60;
+
+// How often changes made on other devices are fetched
+const REFRESH_SECS: u32 = 300;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum EntryTypeView {
@@ -23,11 +59,42 @@ pub struct MainWindow {
     is_active: bool,
 
     entry_view: EntryTypeView,
+
+    // Set while a deletion runs in the background
+    is_busy: bool,
+
+    // Set while the queued changes are sent in the background
+    is_syncing: bool,
+
+    // Set while changes from the server are fetched in the background
+    is_refreshing: bool,
+
+    // Set while the lock dialog waits for the master password
+    is_locked: bool,
+    session_warning: String,
+
+    // Set when the vault was opened from the offline copy
+    offline_notice: String,
+
     list_view_wrapper: TypedListView<EntryListItem, gtk::SingleSelection>,
 
     active_entries_data: ActiveEntriesData,
 
     add_entry_prompt: Controller<AddEntryPrompt>,
+    edit_entry_prompt: Controller<EditEntryPrompt>,
+    change_password_prompt: Controller<ChangePasswordPrompt>,
+    integrity_warning_dialog: Controller<IntegrityWarningDialog>,
+    preferences_prompt: Controller<PreferencesPrompt>,
+    lock_dialog: Controller<LockDialog>,
+    conflict_dialog: Controller<ConflictDialog>,
+
+    profile_drop_down: gtk::DropDown,
+
+    // Updated by the input controllers of the window, checked by the auto-lock timer
+    last_activity: Rc<Cell<Instant>>,
+    auto_lock_timer: Option<gtk::glib::SourceId>,
+    outbox_sync_timer: Option<gtk::glib::SourceId>,
+    refresh_timer: Option<gtk::glib::SourceId>,
 
     app_state: Rc<RefCell<AppState>>,
 }
@@ -37,26 +104,60 @@ pub enum MainWindowMsg {
     SetMode(EntryTypeView),
 
     NewEntryListItem(EntryListItem),
+    UpdatedEntryListItem(EntryListItem),
 
     SetActiveIndex(u32),
 
     ShowAddEntryPrompt,
+    ShowEditEntryPrompt,
+
+    ShowChangePasswordPrompt,
+
+    ShowPreferences,
 
     DeleteEntry,
 
     GenerateRandomPassword,
 
     LogoutPress,
+
+    SwitchProfile(String),
+
+    CheckAutoLock,
+
+    CheckSession,
+    RenewSession,
+    SessionRenewed,
+
+    SessionExpired,
+
+    SyncOutbox,
+    ConflictResolved,
+
+    AcceptServerState,
+
+    SyncNow,
+    Refresh,
+}
+
+#[derive(Debug)]
+pub enum MainWindowCommandOutput {
+    DeleteFinished(Result<PendingDeletion, Error>),
+    SyncFinished(SyncReport),
+    RefreshFinished(Result<RefreshedVault, Error>),
 }
 
 #[derive(Debug)]
 pub enum LoggedOutMsg {
     LoggedOut,
+    // The session stays unlocked in the background
+    SwitchProfile(String),
 }
 
 #[relm4::component(pub)]
-impl SimpleComponent for MainWindow {
+impl Component for MainWindow {
     type Init = Rc<RefCell<AppState>>;
+    type CommandOutput = MainWindowCommandOutput;
     type Input = MainWindowMsg;
     type Output = LoggedOutMsg;
 
@@ -93,17 +194,50 @@ impl SimpleComponent for MainWindow {
                             }
                         },
 
+                        // Edit Entry Button
+                        gtk::Button {
+                            set_has_frame: true,
+                            set_icon_name: "document-edit-symbolic",
+                            set_tooltip_text: Some("Edit selected entry"),
+
+                            connect_clicked[sender] => move |_| {
+                                sender.input(MainWindowMsg::ShowEditEntryPrompt);
+                            }
+                        },
+
                         // Delete Entry Button
                         gtk::Button {
                             set_has_frame: true,
                             set_icon_name: "user-trash",
                             add_css_class: "destructive-action",
                             set_tooltip_text: Some("Delete selected entry"),
+                            #[watch]
+                            set_sensitive: !model.is_busy,
 
                             connect_clicked[sender] => move |_| {
                                 sender.input(MainWindowMsg::DeleteEntry);
                             }
                         },
+
+                        // Sync Now Button
+                        gtk::Button {
+                            set_has_frame: true,
+                            set_icon_name: "view-refresh-symbolic",
+                            set_tooltip_text: Some("Sync now"),
+                            #[watch]
+                            set_sensitive: !model.is_syncing && !model.is_refreshing,
+
+                            connect_clicked[sender] => move |_| {
+                                sender.input(MainWindowMsg::SyncNow);
+                            }
+                        },
+
+                        gtk::Spinner {
+                            #[watch]
+                            set_visible: model.is_busy || model.is_syncing || model.is_refreshing,
+                            #[watch]
+                            set_spinning: model.is_busy || model.is_syncing || model.is_refreshing,
+                        },
                     },
 
                     #[wrap(Some)]
@@ -165,6 +299,32 @@ impl SimpleComponent for MainWindow {
                     pack_end = &gtk::Box {
                         set_spacing: 10,
 
+                        #[local_ref]
+                        profile_drop_down -> gtk::DropDown {
+                            set_tooltip_text: Some("Switch profile"),
+                        },
+
+                        // Change Master Password Button
+                        gtk::Button {
+                            set_has_frame: true,
+                            set_icon_name: "key2",
+                            set_tooltip_text: Some("Change master password"),
+
+                            connect_clicked[sender] => move |_| {
+                                sender.input(MainWindowMsg::ShowChangePasswordPrompt);
+                            }
+                        },
+
+                        gtk::Button {
+                            set_has_frame: true,
+                            set_icon_name: "settings",
+                            set_tooltip_text: Some("Preferences"),
+
+                            connect_clicked[sender] => move |_| {
+                                sender.input(MainWindowMsg::ShowPreferences);
+                            }
+                        },
+
                         gtk::Button {
                             set_has_frame: true,
                             set_icon_name: "log-out",
@@ -177,6 +337,25 @@ impl SimpleComponent for MainWindow {
                     },
                 },
 
+                adw::Banner {
+                    set_button_label: Some("Log In Again"),
+                    #[watch]
+                    set_title: &model.session_warning,
+                    #[watch]
+                    set_revealed: !model.session_warning.is_empty(),
+
+                    connect_button_clicked[sender] => move |_| {
+                        sender.input(MainWindowMsg::RenewSession);
+                    }
+                },
+
+                adw::Banner {
+                    #[watch]
+                    set_title: &model.offline_notice,
+                    #[watch]
+                    set_revealed: !model.offline_notice.is_empty(),
+                },
+
                 adw::OverlaySplitView {
                     set_sidebar_width_fraction: 0.40,
 
@@ -469,29 +648,146 @@ impl SimpleComponent for MainWindow {
                 AddEntryPromptOutput::NewEntryListItem(new_entry_list_item) => {
                     MainWindowMsg::NewEntryListItem(new_entry_list_item)
                 }
+                AddEntryPromptOutput::SessionExpired => MainWindowMsg::SessionExpired,
             });
 
-        let model = MainWindow {
+        let edit_entry_prompt: Controller<EditEntryPrompt> = EditEntryPrompt::builder()
+            .launch(state.clone())
+            .forward(sender.input_sender(), |msg| match msg {
+                EditEntryPromptOutput::UpdatedEntryListItem(updated_entry_list_item) => {
+                    MainWindowMsg::UpdatedEntryListItem(updated_entry_list_item)
+                }
+                EditEntryPromptOutput::SessionExpired => MainWindowMsg::SessionExpired,
+            });
+
+        let change_password_prompt: Controller<ChangePasswordPrompt> =
+            ChangePasswordPrompt::builder()
+                .launch(state.clone())
+                .forward(sender.input_sender(), |msg| match msg {
+                    ChangePasswordPromptOutput::SessionExpired => MainWindowMsg::SessionExpired,
+                });
+
+        // Warnings from the integrity check done while unlocking are only shown once
+        let integrity_warnings = match (*state).borrow_mut().vault.as_mut() {
+            Some(data_vault) => std::mem::take(&mut data_vault.integrity_warnings),
+            None => Vec::new(),
+        };
+
+        // Conflicts left unresolved when the app was last closed
+        let saved_conflicts = match state.borrow().vault.as_ref() {
+            Some(data_vault) => data_vault.outbox.conflicts.clone(),
+            None => Vec::new(),
+        };
+
+        let offline_notice = match state.borrow().vault.as_ref() {
+            Some(data_vault) => make_offline_notice(data_vault.offline_synced_at),
+            None => String::new(),
+        };
+
+        let mut model = MainWindow {
             is_active: true,
 
             entry_view: EntryTypeView::Password,
+
+            is_busy: false,
+
+            is_syncing: false,
+
+            is_refreshing: false,
+
+            is_locked: false,
+            session_warning: String::new(),
+
+            offline_notice,
+
             list_view_wrapper,
 
             active_entries_data: make_active_entries_data(state.clone()),
 
             add_entry_prompt,
+            edit_entry_prompt,
+            change_password_prompt,
+            integrity_warning_dialog: IntegrityWarningDialog::builder()
+                .transient_for(&root)
+                .launch(())
+                .forward(sender.input_sender(), |msg| match msg {
+                    IntegrityWarningDialogOutput::AcceptServerState => {
+                        MainWindowMsg::AcceptServerState
+                    }
+                }),
+            preferences_prompt: PreferencesPrompt::builder()
+                .transient_for(&root)
+                .launch(state.clone())
+                .detach(),
+            lock_dialog: LockDialog::builder()
+                .transient_for(&root)
+                .launch(state.clone())
+                .forward(sender.input_sender(), |msg| match msg {
+                    LockDialogOutput::Unlocked => MainWindowMsg::SessionRenewed,
+                    LockDialogOutput::LoggedOut => MainWindowMsg::LogoutPress,
+                }),
+            conflict_dialog: ConflictDialog::builder()
+                .transient_for(&root)
+                .launch(state.clone())
+                .forward(sender.input_sender(), |msg| match msg {
+                    ConflictDialogOutput::Resolved => MainWindowMsg::ConflictResolved,
+                }),
+
+            profile_drop_down: make_profile_drop_down(&state, &sender),
+
+            last_activity: Rc::new(Cell::new(Instant::now())),
+            auto_lock_timer: None,
+            outbox_sync_timer: None,
+            refresh_timer: None,
 
             app_state: state,
         };
 
+        if !integrity_warnings.is_empty() {
+            model
+                .integrity_warning_dialog
+                .emit(IntegrityWarningDialogMsg::Show(integrity_warnings));
+        }
+
+        if !saved_conflicts.is_empty() {
+            model
+                .conflict_dialog
+                .emit(ConflictDialogMsg::Show(saved_conflicts));
+        }
+
+        model.watch_activity(&root, &sender);
+
+        // Changes queued in an earlier session go out as soon as the window opens
+        let outbox_sender = sender.clone();
+        model.outbox_sync_timer = Some(gtk::glib::timeout_add_seconds_local(
+            OUTBOX_SYNC_SECS,
+            move || {
+                outbox_sender.input(MainWindowMsg::SyncOutbox);
+                gtk::glib::ControlFlow::Continue
+            },
+        ));
+        sender.input(MainWindowMsg::SyncOutbox);
+
+        // Also picks up the revisions of the entries migrated while unlocking
+        let refresh_sender = sender.clone();
+        model.refresh_timer = Some(gtk::glib::timeout_add_seconds_local(
+            REFRESH_SECS,
+            move || {
+                refresh_sender.input(MainWindowMsg::Refresh);
+                gtk::glib::ControlFlow::Continue
+            },
+        ));
+        sender.input(MainWindowMsg::Refresh);
+
         let list_view = &model.list_view_wrapper.view;
+        let profile_drop_down = &model.profile_drop_down;
 
         let widgets = view_output!();
 
         ComponentParts { model, widgets }
     }
 
-    fn update(&mut self, message: Self::Input, sender: ComponentSender<Self>) {
+    fn update(&mut self, message: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
         match message {
             MainWindowMsg::SetMode(mode) => {
                 self.entry_view = mode;
@@ -515,8 +811,26 @@ impl SimpleComponent for MainWindow {
             MainWindowMsg::NewEntryListItem(new_entry_list_item) => {
                 self.list_view_wrapper.append(new_entry_list_item);
 
-                self.active_entries_data
-                    .update_vault_data(self.app_state.clone());
+                sender.input(MainWindowMsg::SyncOutbox);
+            }
+
+            // The details show the entry as it was saved
+            MainWindowMsg::UpdatedEntryListItem(updated_entry_list_item) => {
+                let updated_entry = match self.app_state.borrow().vault.as_ref() {
+                    Some(data_vault) => data_vault
+                        .entries_vault
+                        .get(&updated_entry_list_item.id)
+                        .cloned(),
+                    None => None,
+                };
+
+                if let Some(updated_entry) = updated_entry {
+                    self.active_entries_data.set_active_entry(updated_entry);
+                }
+
+                self.apply_list_changes(vec![EntryListChange::Updated(updated_entry_list_item)]);
+
+                sender.input(MainWindowMsg::SyncOutbox);
             }
 
             MainWindowMsg::SetActiveIndex(index) => match self.entry_view {
@@ -538,95 +852,537 @@ impl SimpleComponent for MainWindow {
                 self.add_entry_prompt.emit(AddEntryPromptMsg::Show);
             }
 
+            // Edited from the vault, which a refresh may have changed since it was selected
+            MainWindowMsg::ShowEditEntryPrompt => {
+                let active_id = match self.entry_view {
+                    EntryTypeView::Password => self
+                        .active_entries_data
+                        .active_password_data
+                        .as_ref()
+                        .map(|password| password.id.clone()),
+                    EntryTypeView::Note => self
+                        .active_entries_data
+                        .active_note_data
+                        .as_ref()
+                        .map(|note| note.id.clone()),
+                    EntryTypeView::Card => self
+                        .active_entries_data
+                        .active_card_data
+                        .as_ref()
+                        .map(|card| card.id.clone()),
+                    EntryTypeView::TOTP => self
+                        .active_entries_data
+                        .active_totp_data
+                        .as_ref()
+                        .map(|totp_entry| totp_entry.id.clone()),
+                };
+
+                // Nothing is selected in the current view
+                let active_id = match active_id {
+                    Some(active_id) => active_id,
+                    None => return,
+                };
+
+                let active_entry = match self.app_state.borrow().vault.as_ref() {
+                    Some(data_vault) => data_vault.entries_vault.get(&active_id).cloned(),
+                    None => None,
+                };
+
+                if let Some(active_entry) = active_entry {
+                    self.edit_entry_prompt
+                        .emit(EditEntryPromptMsg::Show(active_entry));
+                }
+            }
+
+            MainWindowMsg::ShowPreferences => {
+                self.preferences_prompt.emit(PreferencesPromptMsg::Show);
+            }
+
+            MainWindowMsg::ShowChangePasswordPrompt => {
+                self.change_password_prompt
+                    .emit(ChangePasswordPromptMsg::Show);
+            }
+
             MainWindowMsg::DeleteEntry => {
-                let name;
-                let content_type;
-
-                match self.entry_view {
-                    EntryTypeView::Password => {
-                        name = self
-                            .active_entries_data
-                            .active_password_data
+                let active_entry = match self.entry_view {
+                    EntryTypeView::Password => self
+                        .active_entries_data
+                        .active_password_data
+                        .as_ref()
+                        .map(|password| {
+                            (
+                                password.id.clone(),
+                                password.name.clone(),
+                                Password::CONTENT_TYPE,
+                            )
+                        }),
+                    EntryTypeView::Note => self
+                        .active_entries_data
+                        .active_note_data
+                        .as_ref()
+                        .map(|note| (note.id.clone(), note.name.clone(), Note::CONTENT_TYPE)),
+                    EntryTypeView::Card => self
+                        .active_entries_data
+                        .active_card_data
+                        .as_ref()
+                        .map(|card| (card.id.clone(), card.name.clone(), Card::CONTENT_TYPE)),
+                    EntryTypeView::TOTP => {
+                        self.active_entries_data
+                            .active_totp_data
                             .as_ref()
-                            .unwrap()
-                            .name
-                            .clone();
-                        content_type = "password".to_string();
+                            .map(|totp_entry| {
+                                (
+                                    totp_entry.id.clone(),
+                                    totp_entry.name.clone(),
+                                    TOTPEntry::CONTENT_TYPE,
+                                )
+                            })
                     }
-                    EntryTypeView::Note => {
-                        name = self
-                            .active_entries_data
-                            .active_note_data
-                            .as_ref()
-                            .unwrap()
-                            .name
-                            .clone();
-                        content_type = "note".to_string();
+                };
+
+                // Nothing is selected in the current view
+                let (id, name, content_type) = match active_entry {
+                    Some(active_entry) => active_entry,
+                    None => return,
+                };
+
+                let app_state = self.app_state.borrow();
+
+                let pending_deletion = prepare_deletion(&id, &name, content_type, &app_state);
+
+                let connection = app_state.connection.clone();
+                drop(app_state);
+
+                match pending_deletion {
+                    Ok(pending_deletion) => {
+                        self.is_busy = true;
+
+                        sender.spawn_oneshot_command(move || {
+                            MainWindowCommandOutput::DeleteFinished(delete_entry_action(
+                                pending_deletion,
+                                &connection,
+                            ))
+                        });
                     }
-                    EntryTypeView::Card => {
-                        name = self
-                            .active_entries_data
-                            .active_card_data
-                            .as_ref()
-                            .unwrap()
-                            .name
-                            .clone();
-                        content_type = "card".to_string();
+                    Err(e) => self.handle_delete_error(e, &sender),
+                }
+            }
+
+            MainWindowMsg::GenerateRandomPassword => {
+                let config = self.app_state.borrow().settings.effective();
+                let gen_pass = generate_random_password(&config.generator);
+
+                copy_secret_to_clipboard(&gen_pass, &config);
+            }
+
+            MainWindowMsg::LogoutPress => {
+                self.is_active = false;
+                logout_action(&self.app_state.borrow().connection);
+                sender.output(LoggedOutMsg::LoggedOut).unwrap();
+            }
+
+            MainWindowMsg::SwitchProfile(name) => {
+                if self.app_state.borrow().settings.active_profile == name {
+                    return;
+                }
+
+                self.is_active = false;
+                sender.output(LoggedOutMsg::SwitchProfile(name)).unwrap();
+            }
+
+            // Read on every check so a change in the preferences applies right away
+            MainWindowMsg::CheckAutoLock => {
+                let mut app_state = (*self.app_state).borrow_mut();
+                app_state.lock_idle_sessions();
+
+                let auto_lock_minutes = app_state.settings.effective().auto_lock_minutes;
+                drop(app_state);
+
+                if auto_lock_minutes > 0
+                    && self.last_activity.get().elapsed()
+                        >= Duration::from_secs(auto_lock_minutes * 60)
+                {
+                    println!("Locking after {} minutes of inactivity", auto_lock_minutes);
+                    sender.input(MainWindowMsg::LogoutPress);
+                }
+            }
+
+            // Warns ahead of the expiry in the session token, and locks once it has passed
+            MainWindowMsg::CheckSession => {
+                if self.is_locked {
+                    return;
+                }
+
+                let session_expiry = self.app_state.borrow().connection.session_expiry();
+
+                let remaining = match session_expiry {
+                    Some(session_expiry) => session_expiry - Utc::now(),
+                    None => {
+                        self.session_warning.clear();
+                        return;
                     }
-                    EntryTypeView::TOTP => {
-                        name = self
-                            .active_entries_data
-                            .active_totp_data
-                            .as_ref()
-                            .unwrap()
-                            .name
-                            .clone();
-                        content_type = "totp".to_string();
+                };
+
+                if remaining <= TimeDelta::zero() {
+                    sender.input(MainWindowMsg::SessionExpired);
+                } else if remaining.num_seconds() <= SESSION_WARNING_SECS {
+                    self.session_warning = format!(
+                        "Your session expires in {} minutes",
+                        remaining.num_minutes() + 1
+                    );
+                } else {
+                    self.session_warning.clear();
+                }
+            }
+
+            MainWindowMsg::RenewSession => {
+                self.is_locked = true;
+                self.lock_dialog.emit(LockDialogMsg::Show(
+                    "Log in again to extend your session".to_string(),
+                ));
+            }
+
+            MainWindowMsg::SessionRenewed => {
+                self.is_locked = false;
+                self.session_warning.clear();
+
+                self.edit_entry_prompt
+                    .emit(EditEntryPromptMsg::SessionRenewed);
+
+                sender.input(MainWindowMsg::SyncOutbox);
+            }
+
+            // Every request rejected with 401 ends up here, the vault stays open behind the dialog
+            MainWindowMsg::SessionExpired => {
+                if self.is_locked {
+                    return;
+                }
+
+                self.is_locked = true;
+                self.session_warning.clear();
+                self.lock_dialog.emit(LockDialogMsg::Show(
+                    "Your session has expired, log in again to keep using the vault".to_string(),
+                ));
+            }
+
+            // Never while refreshing, so the refresh does not see entries without their manifest
+            MainWindowMsg::SyncOutbox => {
+                if self.is_syncing || self.is_refreshing || self.is_locked {
+                    return;
+                }
+
+                let app_state = self.app_state.borrow();
+
+                let pending_sync = match prepare_outbox_sync(&app_state) {
+                    Ok(Some(pending_sync)) => pending_sync,
+                    Ok(None) => return,
+                    Err(e) => {
+                        println!("Failed to prepare sync: {}", e);
+                        return;
                     }
+                };
+
+                let connection = app_state.connection.clone();
+                drop(app_state);
+
+                self.is_syncing = true;
+
+                sender.spawn_oneshot_command(move || {
+                    MainWindowCommandOutput::SyncFinished(sync_outbox_action(
+                        pending_sync,
+                        &connection,
+                    ))
+                });
+            }
+
+            // Keeping the server version changes the vault, so the list is filled again
+            MainWindowMsg::ConflictResolved => {
+                self.list_view_wrapper.clear();
+
+                if let Some(data_vault) = self.app_state.borrow().vault.as_ref() {
+                    fill_list_view_wrapper(&mut self.list_view_wrapper, &data_vault.entries_vault);
+                }
+
+                self.active_entries_data = make_active_entries_data(self.app_state.clone());
+
+                sender.input(MainWindowMsg::SyncOutbox);
+            }
+
+            MainWindowMsg::AcceptServerState => {
+                if let Err(e) = accept_server_state(&mut (*self.app_state).borrow_mut()) {
+                    println!("Failed to accept server state: {}", e);
+                }
+
+                sender.input(MainWindowMsg::SyncOutbox);
+            }
+
+            // A refresh asked for while syncing follows once the sync is done
+            MainWindowMsg::SyncNow => {
+                sender.input(MainWindowMsg::SyncOutbox);
+                sender.input(MainWindowMsg::Refresh);
+            }
+
+            MainWindowMsg::Refresh => {
+                if self.is_refreshing || self.is_syncing || self.is_locked {
+                    return;
                 }
 
-                match delete_entry_action(
-                    name.as_str(),
-                    content_type.as_str(),
-                    self.app_state.clone(),
+                let app_state = self.app_state.borrow();
+
+                let pending_refresh = match prepare_refresh(&app_state) {
+                    Ok(pending_refresh) => pending_refresh,
+                    Err(e) => {
+                        println!("Failed to prepare refresh: {}", e);
+                        return;
+                    }
+                };
+
+                let connection = app_state.connection.clone();
+                drop(app_state);
+
+                self.is_refreshing = true;
+
+                sender.spawn_oneshot_command(move || {
+                    MainWindowCommandOutput::RefreshFinished(refresh_action(
+                        pending_refresh,
+                        &connection,
+                    ))
+                });
+            }
+        }
+    }
+
+    fn update_cmd(
+        &mut self,
+        message: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        match message {
+            MainWindowCommandOutput::DeleteFinished(Ok(pending_deletion)) => {
+                self.is_busy = false;
+
+                // The vault is gone when the window was logged out while the request ran
+                let result = apply_deletion(&pending_deletion, &mut (*self.app_state).borrow_mut());
+
+                if let Err(e) = result {
+                    self.handle_delete_error(e, &sender);
+                    return;
+                }
+
+                self.active_entries_data = make_active_entries_data(self.app_state.clone());
+
+                match get_list_view_item_index(
+                    pending_deletion.id.as_str(),
+                    self.list_view_wrapper.borrow_mut(),
                 ) {
-                    Ok(_) => {
-                        self.active_entries_data = make_active_entries_data(self.app_state.clone());
-
-                        match get_list_view_item_index(
-                            name.as_str(),
-                            content_type.as_str(),
-                            self.list_view_wrapper.borrow_mut(),
-                        ) {
-                            Ok(index) => {
-                                self.list_view_wrapper.remove(index);
-                            }
-                            Err(e) => {
-                                panic!("{}", e);
-                            }
-                        }
+                    Ok(index) => {
+                        self.list_view_wrapper.remove(index);
                     }
+                    // The entry is gone from the vault either way, only the list is stale
                     Err(e) => {
-                        panic!("Failed to delete entry: {}", e);
+                        println!("Failed to remove deleted entry from the list: {}", e);
                     }
                 }
+
+                sender.input(MainWindowMsg::SyncOutbox);
             }
+            MainWindowCommandOutput::DeleteFinished(Err(e)) => {
+                self.is_busy = false;
 
-            MainWindowMsg::GenerateRandomPassword => {
-                let gen_pass = generate_random_password();
+                self.handle_delete_error(e, &sender);
+            }
+            MainWindowCommandOutput::SyncFinished(report) => {
+                self.is_syncing = false;
 
-                // lol
-                let button = gtk::Button::builder().build();
-                let clipboard = button.clipboard();
+                let error = report.error.clone();
 
-                clipboard.set_text(&gen_pass);
+                match apply_outbox_sync(report, &mut (*self.app_state).borrow_mut()) {
+                    Ok(conflicts) if !conflicts.is_empty() => {
+                        self.conflict_dialog
+                            .emit(ConflictDialogMsg::Show(conflicts));
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("Failed to apply sync: {}", e),
+                }
+
+                // The remaining changes stay queued for the next attempt
+                match error {
+                    Some(e) if e.is_unauthorized() => sender.input(MainWindowMsg::SessionExpired),
+                    Some(e) => println!("Sync stopped: {}", e),
+                    None => {}
+                }
+
+                // Picks up what was skipped while syncing
+                sender.input(MainWindowMsg::Refresh);
             }
+            MainWindowCommandOutput::RefreshFinished(Ok(refreshed_vault)) => {
+                self.is_refreshing = false;
+
+                let outcome = apply_refresh(refreshed_vault, &mut (*self.app_state).borrow_mut());
+
+                match outcome {
+                    Ok(RefreshOutcome::Applied {
+                        list_changes,
+                        warnings,
+                    }) => {
+                        self.offline_notice.clear();
+
+                        if !list_changes.is_empty() {
+                            self.apply_list_changes(list_changes);
+                            self.active_entries_data =
+                                make_active_entries_data(self.app_state.clone());
+                        }
 
-            MainWindowMsg::LogoutPress => {
-                self.is_active = false;
-                logout_action(&self.app_state);
-                sender.output(LoggedOutMsg::LoggedOut).unwrap();
+                        if !warnings.is_empty() {
+                            self.integrity_warning_dialog
+                                .emit(IntegrityWarningDialogMsg::Show(warnings));
+                        }
+                    }
+                    Ok(RefreshOutcome::VaultKeyChanged) => {
+                        println!("The vault key changed on another device, logging out");
+                        sender.input(MainWindowMsg::LogoutPress);
+                    }
+                    Err(e) => println!("Failed to apply refresh: {}", e),
+                }
+            }
+            MainWindowCommandOutput::RefreshFinished(Err(e)) => {
+                self.is_refreshing = false;
+
+                if e.is_unauthorized() {
+                    sender.input(MainWindowMsg::SessionExpired);
+                } else {
+                    println!("Refresh failed: {}", e);
+                }
+            }
+        }
+    }
+    fn shutdown(&mut self, _widgets: &mut Self::Widgets, _output: relm4::Sender<Self::Output>) {
+        if let Some(auto_lock_timer) = self.auto_lock_timer.take() {
+            auto_lock_timer.remove();
+        }
+
+        if let Some(outbox_sync_timer) = self.outbox_sync_timer.take() {
+            outbox_sync_timer.remove();
+        }
+
+        if let Some(refresh_timer) = self.refresh_timer.take() {
+            refresh_timer.remove();
+        }
+    }
+}
+
+// Lists every profile, the ones still logged in in the background are marked
+fn make_profile_drop_down(
+    state: &Rc<RefCell<AppState>>,
+    sender: &ComponentSender<MainWindow>,
+) -> gtk::DropDown {
+    let app_state = state.borrow();
+    let config = app_state.settings.effective();
+
+    let profile_labels = gtk::StringList::default();
+    let mut selected = 0;
+
+    for (index, profile) in config.profiles.iter().enumerate() {
+        if profile.name == config.active_profile {
+            selected = index as u32;
+        }
+
+        if profile.name != config.active_profile && app_state.is_profile_unlocked(&profile.name) {
+            profile_labels.append(&format!("{} (unlocked)", profile.name));
+        } else {
+            profile_labels.append(&profile.name);
+        }
+    }
+
+    let profile_names: Vec<String> = config
+        .profiles
+        .iter()
+        .map(|profile| profile.name.clone())
+        .collect();
+
+    let profile_drop_down = gtk::DropDown::new(Some(profile_labels), gtk::Expression::NONE);
+    profile_drop_down.set_selected(selected);
+
+    let sender = sender.clone();
+    profile_drop_down.connect_selected_notify(move |drop_down| {
+        if let Some(name) = profile_names.get(drop_down.selected() as usize) {
+            sender.input(MainWindowMsg::SwitchProfile(name.clone()));
+        }
+    });
+
+    profile_drop_down
+}
+
+impl MainWindow {
+    // Updated items are put back where they were, the order has to keep following the vault
+    fn apply_list_changes(&mut self, list_changes: Vec<EntryListChange>) {
+        for list_change in list_changes {
+            match list_change {
+                EntryListChange::Added(item) => self.list_view_wrapper.append(item),
+                EntryListChange::Updated(item) => {
+                    match get_list_view_item_index(&item.id, &self.list_view_wrapper) {
+                        Ok(index) => {
+                            self.list_view_wrapper.remove(index);
+                            self.list_view_wrapper.insert(index, item);
+                        }
+                        Err(e) => println!("Failed to update entry in the list: {}", e),
+                    }
+                }
+                // The server may report deleting an entry this device never had
+                EntryListChange::Removed { id } => {
+                    if let Ok(index) = get_list_view_item_index(&id, &self.list_view_wrapper) {
+                        self.list_view_wrapper.remove(index);
+                    }
+                }
             }
         }
     }
+
+    // Any key press or pointer movement in the window postpones the auto-lock
+    fn watch_activity(&mut self, root: &adw::ApplicationWindow, sender: &ComponentSender<Self>) {
+        let key_controller = gtk::EventControllerKey::new();
+        key_controller.set_propagation_phase(gtk::PropagationPhase::Capture);
+        let last_activity = self.last_activity.clone();
+        key_controller.connect_key_pressed(move |_, _, _, _| {
+            last_activity.set(Instant::now());
+            gtk::glib::Propagation::Proceed
+        });
+        root.add_controller(key_controller);
+
+        let motion_controller = gtk::EventControllerMotion::new();
+        let last_activity = self.last_activity.clone();
+        motion_controller.connect_motion(move |_, _, _| {
+            last_activity.set(Instant::now());
+        });
+        root.add_controller(motion_controller);
+
+        let sender = sender.clone();
+        self.auto_lock_timer = Some(gtk::glib::timeout_add_seconds_local(
+            AUTO_LOCK_CHECK_SECS,
+            move || {
+                sender.input(MainWindowMsg::CheckAutoLock);
+                sender.input(MainWindowMsg::CheckSession);
+                gtk::glib::ControlFlow::Continue
+            },
+        ));
+    }
+
+    fn handle_delete_error(&mut self, error: Error, sender: &ComponentSender<Self>) {
+        if error.is_unauthorized() {
+            sender.input(MainWindowMsg::SessionExpired);
+        } else {
+            println!("Failed to delete entry: {}", error);
+        }
+    }
+}
+
+fn make_offline_notice(offline_synced_at: Option<DateTime<Utc>>) -> String {
+    match offline_synced_at {
+        Some(synced_at) => format!(
+            "Offline: showing the copy synced on {}, changes are sent once the server is reachable",
+            synced_at.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+        ),
+        None => String::new(),
+    }
 }
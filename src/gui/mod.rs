@@ -4,6 +4,15 @@ pub mod add_entry_response_dialog;
 pub mod app_top_wrapper;
 pub mod auth_prompt;
 pub mod auth_response_dialog;
+pub mod change_password_prompt;
+pub mod change_password_response_dialog;
+pub mod conflict_dialog;
+pub mod edit_entry_prompt;
 pub mod entry_list_item;
+pub mod integrity_warning_dialog;
+pub mod lock_dialog;
 pub mod main_window;
+pub mod preferences_prompt;
+pub mod profile_prompt;
+pub mod recovery_key_dialog;
 pub mod utils;
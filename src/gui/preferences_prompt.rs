@@ -0,0 +1,257 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::config::GeneratorConfig;
+use crate::AppState;
+
+use adw::prelude::*;
+use relm4::prelude::*;
+
+pub struct PreferencesPrompt {
+    is_active: bool,
+
+    profile_name: String,
+    server_url: gtk::EntryBuffer,
+    connect_timeout: gtk::Adjustment,
+    request_timeout: gtk::Adjustment,
+    auto_lock: gtk::Adjustment,
+    clipboard_clear: gtk::Adjustment,
+    generator_length: gtk::Adjustment,
+    generator_use_symbols: bool,
+    require_https: bool,
+
+    error_text: String,
+
+    app_state: Rc<RefCell<AppState>>,
+}
+
+#[derive(Debug)]
+pub enum PreferencesPromptMsg {
+    Show,
+
+    SetGeneratorUseSymbols(bool),
+    SetRequireHttps(bool),
+
+    SavePress,
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for PreferencesPrompt {
+    type Init = Rc<RefCell<AppState>>;
+    type Input = PreferencesPromptMsg;
+    type Output = ();
+
+    view! {
+        adw::ApplicationWindow {
+            set_title: Some("Preferences"),
+            set_modal: true,
+            set_resizable: false,
+            set_default_size: (500, 300),
+            set_css_classes: &["background", "csd"],
+            set_hide_on_close: true,
+
+            #[watch]
+            set_visible: model.is_active,
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+
+                adw::HeaderBar {
+                    set_show_end_title_buttons: true,
+                },
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_margin_all: 10,
+                    set_spacing: 10,
+
+                    gtk::Label {
+                        #[watch]
+                        set_label: &format!("Server URL of profile {}", model.profile_name),
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.server_url,
+                    },
+
+                    gtk::Label {
+                        set_label: "Connection Timeout (seconds)",
+                    },
+                    gtk::SpinButton {
+                        set_adjustment: &model.connect_timeout,
+                    },
+
+                    gtk::Label {
+                        set_label: "Request Timeout (seconds)",
+                    },
+                    gtk::SpinButton {
+                        set_adjustment: &model.request_timeout,
+                    },
+
+                    gtk::Label {
+                        set_label: "Lock After Inactivity (minutes, 0 to disable)",
+                    },
+                    gtk::SpinButton {
+                        set_adjustment: &model.auto_lock,
+                    },
+
+                    gtk::Label {
+                        set_label: "Clear Clipboard After (seconds, 0 to disable)",
+                    },
+                    gtk::SpinButton {
+                        set_adjustment: &model.clipboard_clear,
+                    },
+
+                    gtk::Label {
+                        set_label: "Generated Password Length",
+                    },
+                    gtk::SpinButton {
+                        set_adjustment: &model.generator_length,
+                    },
+
+                    gtk::CheckButton {
+                        set_label: Some("Use symbols in generated passwords"),
+                        #[watch]
+                        set_active: model.generator_use_symbols,
+                        connect_toggled[sender] => move |check_button| {
+                            sender.input(PreferencesPromptMsg::SetGeneratorUseSymbols(check_button.is_active()));
+                        }
+                    },
+
+                    gtk::CheckButton {
+                        set_label: Some("Refuse plain http:// for servers other than this machine"),
+                        #[watch]
+                        set_active: model.require_https,
+                        connect_toggled[sender] => move |check_button| {
+                            sender.input(PreferencesPromptMsg::SetRequireHttps(check_button.is_active()));
+                        }
+                    },
+
+                    gtk::Label {
+                        set_label: "Server and timeout changes take effect at the next login",
+                        add_css_class: "dim-label",
+                    },
+
+                    gtk::Label {
+                        add_css_class: "error",
+                        set_wrap: true,
+                        #[watch]
+                        set_visible: !model.error_text.is_empty(),
+                        #[watch]
+                        set_label: &model.error_text,
+                    },
+
+                    gtk::Button {
+                        set_margin_all: 20,
+                        set_label: "Save",
+                        add_css_class: "suggested-action",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(PreferencesPromptMsg::SavePress);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn init(
+        state: Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = PreferencesPrompt {
+            is_active: false,
+
+            profile_name: String::new(),
+            server_url: gtk::EntryBuffer::default(),
+            connect_timeout: gtk::Adjustment::new(0.0, 1.0, 600.0, 1.0, 10.0, 0.0),
+            request_timeout: gtk::Adjustment::new(0.0, 1.0, 3600.0, 1.0, 10.0, 0.0),
+            auto_lock: gtk::Adjustment::new(0.0, 0.0, 1440.0, 1.0, 10.0, 0.0),
+            clipboard_clear: gtk::Adjustment::new(0.0, 0.0, 3600.0, 1.0, 10.0, 0.0),
+            generator_length: gtk::Adjustment::new(0.0, 8.0, 128.0, 1.0, 10.0, 0.0),
+            generator_use_symbols: true,
+            require_https: false,
+
+            error_text: String::new(),
+
+            app_state: state,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
+        match msg {
+            // The saved values are shown, not the ones overridden for this run
+            PreferencesPromptMsg::Show => {
+                let (config, active_profile) = {
+                    let app_state = self.app_state.borrow();
+                    let settings = &app_state.settings;
+
+                    (settings.saved.clone(), settings.active_profile.clone())
+                };
+
+                let profile = match config.profile(&active_profile) {
+                    Some(profile) => profile,
+                    None => config.active_profile(),
+                };
+
+                self.profile_name = profile.name.clone();
+                self.server_url.set_text(&profile.server_url);
+                self.connect_timeout
+                    .set_value(config.connect_timeout_secs as f64);
+                self.request_timeout
+                    .set_value(config.request_timeout_secs as f64);
+                self.auto_lock.set_value(config.auto_lock_minutes as f64);
+                self.clipboard_clear
+                    .set_value(config.clipboard_clear_secs as f64);
+                self.generator_length
+                    .set_value(config.generator.length as f64);
+                self.generator_use_symbols = config.generator.use_symbols;
+                self.require_https = config.require_https;
+
+                self.error_text.clear();
+                self.is_active = true;
+            }
+
+            PreferencesPromptMsg::SetGeneratorUseSymbols(use_symbols) => {
+                self.generator_use_symbols = use_symbols;
+            }
+
+            PreferencesPromptMsg::SetRequireHttps(require_https) => {
+                self.require_https = require_https;
+            }
+
+            PreferencesPromptMsg::SavePress => {
+                let mut app_state = self.app_state.borrow_mut();
+
+                let mut config = app_state.settings.saved.clone();
+
+                if let Some(profile) = config.profile_mut(&self.profile_name) {
+                    profile.server_url = self.server_url.text().trim().to_string();
+                }
+
+                config.connect_timeout_secs = self.connect_timeout.value() as u64;
+                config.request_timeout_secs = self.request_timeout.value() as u64;
+                config.auto_lock_minutes = self.auto_lock.value() as u64;
+                config.clipboard_clear_secs = self.clipboard_clear.value() as u64;
+                config.generator = GeneratorConfig {
+                    length: self.generator_length.value() as usize,
+                    use_symbols: self.generator_use_symbols,
+                };
+                config.require_https = self.require_https;
+
+                if let Err(e) = app_state.settings.save(config) {
+                    println!("Failed to save preferences: {}", e);
+                    self.error_text = e.to_string();
+                    return;
+                }
+
+                // Nobody is logged in, so there is no session to lose by replacing the client
+                app_state.reconnect();
+
+                self.is_active = false;
+            }
+        }
+    }
+}
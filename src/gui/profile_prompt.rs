@@ -0,0 +1,181 @@
+use std::path::PathBuf;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::config::Profile;
+use crate::AppState;
+
+use adw::prelude::*;
+use relm4::prelude::*;
+
+pub struct ProfilePrompt {
+    is_active: bool,
+
+    name: gtk::EntryBuffer,
+    server_url: gtk::EntryBuffer,
+    ca_bundle: gtk::EntryBuffer,
+    certificate_pin: gtk::EntryBuffer,
+
+    error_text: String,
+
+    app_state: Rc<RefCell<AppState>>,
+}
+
+#[derive(Debug)]
+pub enum ProfilePromptMsg {
+    Show,
+
+    AddPress,
+}
+
+#[derive(Debug)]
+pub enum ProfilePromptOutput {
+    Added(String),
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for ProfilePrompt {
+    type Init = Rc<RefCell<AppState>>;
+    type Input = ProfilePromptMsg;
+    type Output = ProfilePromptOutput;
+
+    view! {
+        adw::ApplicationWindow {
+            set_title: Some("New Profile"),
+            set_modal: true,
+            set_resizable: false,
+            set_default_size: (500, 200),
+            set_css_classes: &["background", "csd"],
+            set_hide_on_close: true,
+
+            #[watch]
+            set_visible: model.is_active,
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+
+                adw::HeaderBar {
+                    set_show_end_title_buttons: true,
+                },
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_margin_all: 10,
+                    set_spacing: 10,
+
+                    gtk::Label {
+                        set_label: "Name",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.name,
+                    },
+
+                    gtk::Label {
+                        set_label: "Server URL",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.server_url,
+                    },
+
+                    gtk::Label {
+                        set_label: "CA Bundle (optional PEM file)",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.ca_bundle,
+                    },
+
+                    gtk::Label {
+                        set_label: "Certificate Pin (optional SHA-256 fingerprint or sha256/BASE64)",
+                    },
+                    gtk::Entry {
+                        set_buffer: &model.certificate_pin,
+                    },
+
+                    gtk::Label {
+                        add_css_class: "error",
+                        set_wrap: true,
+                        #[watch]
+                        set_visible: !model.error_text.is_empty(),
+                        #[watch]
+                        set_label: &model.error_text,
+                    },
+
+                    gtk::Button {
+                        set_margin_all: 20,
+                        set_label: "Add",
+                        add_css_class: "suggested-action",
+                        connect_clicked[sender] => move |_| {
+                            sender.input(ProfilePromptMsg::AddPress);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn init(
+        state: Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = ProfilePrompt {
+            is_active: false,
+
+            name: gtk::EntryBuffer::default(),
+            server_url: gtk::EntryBuffer::default(),
+            ca_bundle: gtk::EntryBuffer::default(),
+            certificate_pin: gtk::EntryBuffer::default(),
+
+            error_text: String::new(),
+
+            app_state: state,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+        match msg {
+            ProfilePromptMsg::Show => {
+                self.name.set_text("");
+                self.server_url.set_text("https://");
+                self.ca_bundle.set_text("");
+                self.certificate_pin.set_text("");
+
+                self.error_text.clear();
+                self.is_active = true;
+            }
+
+            ProfilePromptMsg::AddPress => {
+                let profile = Profile {
+                    name: self.name.text().trim().to_string(),
+                    server_url: self.server_url.text().trim().to_string(),
+                    email: None,
+                    ca_bundle: optional_text(&self.ca_bundle).map(PathBuf::from),
+                    certificate_pin: optional_text(&self.certificate_pin),
+                };
+                let name = profile.name.clone();
+
+                if let Err(e) = self.app_state.borrow_mut().add_profile(profile) {
+                    println!("Failed to add profile: {}", e);
+                    self.error_text = e.to_string();
+                    return;
+                }
+
+                sender.output(ProfilePromptOutput::Added(name)).unwrap();
+                self.is_active = false;
+            }
+        }
+    }
+}
+
+fn optional_text(buffer: &gtk::EntryBuffer) -> Option<String> {
+    let text = buffer.text().trim().to_string();
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
@@ -0,0 +1,109 @@
+use adw::prelude::*;
+use relm4::prelude::*;
+use zeroize::Zeroizing;
+
+// Shows the emergency kit once after registering, so the user can copy or save it
+pub struct RecoveryKeyDialog {
+    emergency_kit: Zeroizing<String>,
+    is_active: bool,
+}
+
+#[derive(Debug)]
+pub enum RecoveryKeyDialogMsg {
+    Show(Zeroizing<String>),
+    Copy,
+    Save,
+    Close,
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for RecoveryKeyDialog {
+    type Init = ();
+    type Input = RecoveryKeyDialogMsg;
+    type Output = ();
+
+    view! {
+        #[name = "dialog"]
+        adw::MessageDialog {
+            #[watch]
+            set_visible: model.is_active,
+            set_heading: Some("Registration successful"),
+            #[watch]
+            set_body: model.emergency_kit.as_str(),
+            add_response: ("copy", "Copy"),
+            add_response: ("save", "Save"),
+            add_response: ("close", "Close"),
+            set_close_response: "close",
+            set_hide_on_close: true,
+
+            connect_response: (None, move |_, response| {
+                match response {
+                    "copy" => sender.input(RecoveryKeyDialogMsg::Copy),
+                    "save" => sender.input(RecoveryKeyDialogMsg::Save),
+                    _ => sender.input(RecoveryKeyDialogMsg::Close),
+                }
+            }),
+        }
+    }
+
+    fn init(
+        (): Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = RecoveryKeyDialog {
+            emergency_kit: Zeroizing::new(String::new()),
+            is_active: false,
+        };
+
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
+        match msg {
+            RecoveryKeyDialogMsg::Show(emergency_kit) => {
+                self.emergency_kit = emergency_kit;
+                self.is_active = true;
+            }
+
+            RecoveryKeyDialogMsg::Copy => {
+                if let Some(display) = gtk::gdk::Display::default() {
+                    display.clipboard().set_text(&self.emergency_kit);
+                }
+
+                // Responses always close the dialog, so show it again for the other actions
+                self.is_active = true;
+            }
+
+            RecoveryKeyDialogMsg::Save => {
+                let emergency_kit = self.emergency_kit.clone();
+
+                gtk::FileDialog::builder()
+                    .title("Save emergency kit")
+                    .initial_name("emergency-kit.txt")
+                    .build()
+                    .save(
+                        None::<&gtk::Window>,
+                        gtk::gio::Cancellable::NONE,
+                        move |result| {
+                            if let Some(path) = result.ok().and_then(|file| file.path()) {
+                                if let Err(e) = std::fs::write(&path, emergency_kit.as_bytes()) {
+                                    println!("Failed to save emergency kit: {}", e);
+                                }
+                            }
+                        },
+                    );
+
+                self.is_active = true;
+            }
+
+            // The kit is only shown once and wiped as soon as the dialog is closed
+            RecoveryKeyDialogMsg::Close => {
+                self.emergency_kit = Zeroizing::new(String::new());
+                self.is_active = false;
+            }
+        }
+    }
+}
@@ -1,12 +1,15 @@
 use std::{cell::RefCell, rc::Rc};
 
+use adw::prelude::*;
 use relm4::{prelude::*, typed_view::list::TypedListView};
 
+use crate::config::{Config, GeneratorConfig};
 use crate::model::{Card, EntriesVault, Note, Password, TOTPEntry};
 use crate::totp::generate_totp_token;
+use crate::vault_entry::{EntryData, VaultEntry, ENTRY_HANDLERS};
 use crate::AppState;
 
-use super::entry_list_item::{EntryListItem, EntryType};
+use super::entry_list_item::EntryListItem;
 
 pub fn make_list_view_wrapper_from_data_vault(
     state: Rc<RefCell<AppState>>,
@@ -16,25 +19,7 @@ pub fn make_list_view_wrapper_from_data_vault(
             let mut list_view_wrapper: TypedListView<EntryListItem, gtk::SingleSelection> =
                 TypedListView::new();
 
-            for password in data_vault.entries_vault.passwords.iter() {
-                list_view_wrapper.append(EntryListItem::new(
-                    &password.name,
-                    &password.username,
-                    EntryType::Password,
-                ));
-            }
-
-            for note in data_vault.entries_vault.notes.iter() {
-                list_view_wrapper.append(EntryListItem::new(&note.name, "", EntryType::Note));
-            }
-
-            for card in data_vault.entries_vault.cards.iter() {
-                list_view_wrapper.append(EntryListItem::new(&card.name, "", EntryType::Card));
-            }
-
-            for totp_entry in data_vault.entries_vault.totp_entries.iter() {
-                list_view_wrapper.append(EntryListItem::new(&totp_entry.name, "", EntryType::TOTP));
-            }
+            fill_list_view_wrapper(&mut list_view_wrapper, &data_vault.entries_vault);
 
             list_view_wrapper
         }
@@ -44,26 +29,29 @@ pub fn make_list_view_wrapper_from_data_vault(
     }
 }
 
+// The items follow the order of the vault, which the active index relies on
+pub fn fill_list_view_wrapper(
+    list_view_wrapper: &mut TypedListView<EntryListItem, gtk::SingleSelection>,
+    entries_vault: &EntriesVault,
+) {
+    for handler in ENTRY_HANDLERS {
+        for vault_entry in entries_vault.of_type(handler.content_type) {
+            list_view_wrapper.append(EntryListItem::from_vault_entry(vault_entry));
+        }
+    }
+}
+
 pub fn get_list_view_item_index(
-    name: &str,
-    content_type: &str,
+    id: &str,
     list_view_wrapper: &TypedListView<EntryListItem, gtk::SingleSelection>,
 ) -> Result<u32, String> {
-    let entry_type = match content_type {
-        "password" => EntryType::Password,
-        "note" => EntryType::Note,
-        "card" => EntryType::Card,
-        "totp" => EntryType::TOTP,
-        _ => panic!("Invalid entry type"),
-    };
-
     let mut i = 0;
 
     while i < list_view_wrapper.len() {
         if let Some(list_item) = list_view_wrapper.get(i) {
             let list_item = list_item.borrow();
 
-            if list_item.name == name && list_item.entry_type == entry_type {
+            if list_item.id == id {
                 return Ok(i);
             }
         }
@@ -74,8 +62,9 @@ pub fn get_list_view_item_index(
     Err("Failed to get list view item index".to_string())
 }
 
+// Only the selected entries are copied out of the vault, the rest stay in the app state
 pub struct ActiveEntriesData {
-    pub entries_vault: EntriesVault,
+    pub app_state: Rc<RefCell<AppState>>,
 
     pub active_password_data: Option<Password>,
     pub active_note_data: Option<Note>,
@@ -86,65 +75,108 @@ pub struct ActiveEntriesData {
 }
 
 impl ActiveEntriesData {
-    pub fn update_vault_data(&mut self, state: Rc<RefCell<AppState>>) {
-        match state.borrow().vault.as_ref() {
-            Some(data_vault) => {
-                self.entries_vault = data_vault.entries_vault.clone();
-            }
+    pub fn set_active_index(&mut self, view: u8, index: u32) {
+        let app_state = self.app_state.clone();
+        let app_state = app_state.borrow();
+
+        let entries_vault = match app_state.vault.as_ref() {
+            Some(data_vault) => &data_vault.entries_vault,
             None => {
                 panic!("Failed to get reference to data vault");
             }
-        }
+        };
+
+        let content_type = match view {
+            0 => Password::CONTENT_TYPE,
+            1 => Note::CONTENT_TYPE,
+            2 => Card::CONTENT_TYPE,
+            3 => TOTPEntry::CONTENT_TYPE,
+            _ => panic!("Invalid view index"),
+        };
+
+        let vault_entry = match entries_vault.nth_of_type(content_type, index as usize) {
+            Some(vault_entry) => vault_entry.clone(),
+            None => panic!("Invalid entry index"),
+        };
+
+        self.set_active_entry(vault_entry);
     }
 
-    pub fn set_active_index(&mut self, view: u8, index: u32) {
-        match view {
-            0 => {
-                self.active_password_data =
-                    Some(self.entries_vault.passwords[index as usize].clone());
+    pub fn set_active_entry(&mut self, vault_entry: VaultEntry) {
+        match vault_entry {
+            VaultEntry::Password(password) => {
+                self.active_password_data = Some(password);
             }
-            1 => {
-                self.active_note_data = Some(self.entries_vault.notes[index as usize].clone());
+            VaultEntry::Note(note) => {
+                self.active_note_data = Some(note);
             }
-            2 => {
-                self.active_card_data = Some(self.entries_vault.cards[index as usize].clone());
+            VaultEntry::Card(card) => {
+                self.active_card_data = Some(card);
             }
-            3 => {
-                self.active_totp_data =
-                    Some(self.entries_vault.totp_entries[index as usize].clone());
+            VaultEntry::TOTP(totp_entry) => {
+                self.active_totp_data = Some(totp_entry);
 
                 self.update_current_totp_token();
             }
-            _ => panic!("Invalid view index"),
         }
     }
 
     pub fn update_current_totp_token(&mut self) {
-        self.current_totp_token =
-            Some(generate_totp_token(self.active_totp_data.clone().unwrap()).unwrap());
+        // A secret that cannot be turned into a token shows the reason instead
+        self.current_totp_token = Some(
+            match generate_totp_token(self.active_totp_data.as_ref().unwrap()) {
+                Ok(token) => token,
+                Err(e) => e.to_string(),
+            },
+        );
     }
 }
 
 pub fn make_active_entries_data(state: Rc<RefCell<AppState>>) -> ActiveEntriesData {
-    match state.borrow().vault.as_ref() {
-        Some(data_vault) => ActiveEntriesData {
-            entries_vault: data_vault.entries_vault.clone(),
+    ActiveEntriesData {
+        app_state: state,
 
-            active_password_data: None,
-            active_note_data: None,
-            active_card_data: None,
-            active_totp_data: None,
+        active_password_data: None,
+        active_note_data: None,
+        active_card_data: None,
+        active_totp_data: None,
 
-            current_totp_token: None,
-        },
-        None => {
-            panic!("Failed to get reference to data vault");
-        }
+        current_totp_token: None,
     }
 }
 
-pub fn generate_random_password() -> String {
-    let charset = random_string::charsets::ALPHANUMERIC.to_string() + "!@#$%^&*()_+-=";
+pub fn generate_random_password(generator: &GeneratorConfig) -> String {
+    let mut charset = random_string::charsets::ALPHANUMERIC.to_string();
+
+    if generator.use_symbols {
+        charset.push_str("!@#$%^&*()_+-=");
+    }
 
-    random_string::generate_rng(12..16, charset)
+    random_string::generate(generator.length, charset)
+}
+
+// Copies a secret and takes it back out of the clipboard after the configured delay,
+// unless something else was copied in the meantime
+pub fn copy_secret_to_clipboard(secret: &str, config: &Config) {
+    let display = match gtk::gdk::Display::default() {
+        Some(display) => display,
+        None => return,
+    };
+
+    let clipboard = display.clipboard();
+    clipboard.set_text(secret);
+
+    if config.clipboard_clear_secs == 0 {
+        return;
+    }
+
+    let clear_after = u32::try_from(config.clipboard_clear_secs).unwrap_or(u32::MAX);
+
+    gtk::glib::timeout_add_seconds_local_once(clear_after, move || {
+        if clipboard.is_local() {
+            clipboard
+                .set_content(None::<&gtk::gdk::ContentProvider>)
+                .unwrap_or_default();
+        }
+    });
 }
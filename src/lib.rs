@@ -0,0 +1,10 @@
+// The vault, its encryption and the offline storage, without the GUI or the server connection
+pub mod cache;
+pub mod encryption;
+pub mod entries;
+pub mod error;
+pub mod manifest;
+pub mod model;
+pub mod outbox;
+pub mod recovery;
+pub mod vault_entry;
@@ -1,47 +1,260 @@
+use chrono::{DateTime, Utc};
+use config::{Config, ConfigOverrides, Profile, Settings, USAGE};
+use error::Error;
+use gui::actions::logout_action;
 use gui::app_top_wrapper::AppTopWrapper;
 use model::DataVault;
 use relm4::RelmApp;
 use relm4_icons::initialize_icons;
 use reqwest::blocking::Client;
+use reqwest::cookie::{CookieStore, Jar};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{cell::RefCell, rc::Rc};
 
+// The vault and its encryption live in the library so the benchmarks can link against them
+use rust_password_manager_client::{
+    cache, encryption, entries, error, manifest, model, outbox, recovery, vault_entry,
+};
+
+pub mod config;
 pub mod constraints;
-pub mod encryption;
-pub mod entries;
 pub mod gui;
-pub mod model;
 pub mod requests;
+pub mod session;
+pub mod tls;
 pub mod totp;
 
-pub struct AppState {
+// Everything a background operation needs to reach the server
+#[derive(Clone)]
+pub struct Connection {
     client: Client,
     base_url: String,
+    // Kept to read the session token, the client sends the cookies by itself
+    cookies: Arc<Jar>,
+}
+
+impl Connection {
+    // A new client also starts with an empty cookie store, so only build one while logged out
+    pub fn new(config: &Config, profile: &Profile) -> Result<Connection, Error> {
+        // Also covers a server URL given on the command line, which never went through the file
+        profile.validate(config.require_https)?;
+
+        let cookies = Arc::new(Jar::default());
+
+        let builder = reqwest::blocking::Client::builder()
+            .cookie_provider(cookies.clone())
+            .connect_timeout(config.connect_timeout())
+            .timeout(config.request_timeout());
+
+        let client = match tls::configure_tls(builder, profile)?.build() {
+            Ok(client) => client,
+            Err(e) => {
+                return Err(Error::Network(format!(
+                    "Failed to create reqwest client: {}",
+                    e
+                )))
+            }
+        };
+
+        Ok(Connection {
+            client,
+            base_url: profile.server_url.trim_end_matches('/').to_string(),
+            cookies,
+        })
+    }
+
+    // None while logged out or when the server does not use a JWT session cookie
+    pub fn session_expiry(&self) -> Option<DateTime<Utc>> {
+        let url = reqwest::Url::parse(&self.base_url).ok()?;
+        let cookie_header = self.cookies.cookies(&url)?;
+
+        session::find_session_expiry(cookie_header.to_str().ok()?)
+    }
+}
+
+// A profile left logged in while another one is in use
+struct Session {
+    connection: Connection,
+    vault: DataVault,
+    parked_at: Instant,
+}
+
+// The connection and vault belong to the active profile of the settings
+pub struct AppState {
+    settings: Settings,
+    connection: Connection,
 
     vault: Option<DataVault>,
+
+    parked_sessions: HashMap<String, Session>,
+}
+
+impl AppState {
+    // Keeps the current session unlocked in the background and resumes the one of the other
+    // profile if it has one, otherwise it starts logged out
+    pub fn switch_profile(&mut self, name: &str) -> Result<(), Error> {
+        let config = self.settings.effective();
+
+        if config.active_profile == name {
+            return Ok(());
+        }
+
+        let profile = match config.profile(name) {
+            Some(profile) => profile.clone(),
+            None => {
+                return Err(Error::Validation(format!(
+                    "There is no profile named {}",
+                    name
+                )))
+            }
+        };
+
+        self.lock_idle_sessions();
+
+        let resumed_session = self.parked_sessions.remove(name);
+
+        let connection = match &resumed_session {
+            Some(session) => session.connection.clone(),
+            None => Connection::new(&config, &profile)?,
+        };
+
+        let previous_connection = std::mem::replace(&mut self.connection, connection);
+
+        if let Some(vault) = self.vault.take() {
+            self.parked_sessions.insert(
+                config.active_profile.clone(),
+                Session {
+                    connection: previous_connection,
+                    vault,
+                    parked_at: Instant::now(),
+                },
+            );
+        }
+
+        self.vault = resumed_session.map(|session| session.vault);
+
+        self.settings.select_profile(name);
+
+        Ok(())
+    }
+
+    pub fn add_profile(&mut self, profile: Profile) -> Result<(), Error> {
+        let mut config = self.settings.saved.clone();
+        config.profiles.push(profile);
+
+        self.settings.save(config)
+    }
+
+    // Only profiles that are not in use can be removed, their parked session is logged out
+    pub fn remove_profile(&mut self, name: &str) -> Result<(), Error> {
+        if self.settings.active_profile == name {
+            return Err(Error::Validation(
+                "Switch to another profile before removing this one".to_string(),
+            ));
+        }
+
+        let mut config = self.settings.saved.clone();
+        config.profiles.retain(|profile| profile.name != name);
+
+        self.settings.save(config)?;
+
+        if let Some(session) = self.parked_sessions.remove(name) {
+            logout_action(&session.connection);
+        }
+
+        Ok(())
+    }
+
+    // Parked sessions follow the same auto-lock delay as the open window
+    pub fn lock_idle_sessions(&mut self) {
+        let auto_lock_minutes = self.settings.effective().auto_lock_minutes;
+
+        if auto_lock_minutes == 0 {
+            return;
+        }
+
+        let auto_lock_delay = Duration::from_secs(auto_lock_minutes * 60);
+
+        self.parked_sessions.retain(|name, session| {
+            if session.parked_at.elapsed() < auto_lock_delay {
+                return true;
+            }
+
+            println!("Locking profile {} after inactivity", name);
+            logout_action(&session.connection);
+
+            false
+        });
+    }
+
+    pub fn is_profile_unlocked(&self, name: &str) -> bool {
+        self.parked_sessions.contains_key(name)
+            || (self.vault.is_some() && self.settings.active_profile == name)
+    }
+
+    // Rebuilds the client of the active profile, only while it is logged out
+    pub fn reconnect(&mut self) {
+        if self.vault.is_some() {
+            return;
+        }
+
+        let config = self.settings.effective();
+
+        match Connection::new(&config, config.active_profile()) {
+            Ok(connection) => self.connection = connection,
+            Err(e) => println!("{}", e),
+        }
+    }
 }
 
 fn main() {
-    // Create a reqwest client with a cookie store
-    let reqwest_client = match reqwest::blocking::Client::builder()
-        .cookie_store(true)
-        .build()
-    {
-        Ok(client) => client,
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let overrides = match ConfigOverrides::from_env_and_args(&args) {
+        Ok(Some(overrides)) => overrides,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let settings = Settings::load(overrides);
+    let config = settings.effective();
+
+    if let Err(e) = config.validate() {
+        eprintln!(
+            "Invalid configuration in {}: {}",
+            settings.path.display(),
+            e
+        );
+        std::process::exit(2);
+    }
+
+    let connection = match Connection::new(&config, config.active_profile()) {
+        Ok(connection) => connection,
         Err(e) => {
-            println!("Failed to create reqwest client: {}", e);
+            println!("{}", e);
             return;
         }
     };
 
     // Initialize the app state as a shared resource
     let state = Rc::new(RefCell::new(AppState {
-        client: reqwest_client,
-        base_url: "http://localhost:8080".to_string(),
+        settings,
+        connection,
         vault: None,
+        parked_sessions: HashMap::new(),
     }));
 
     initialize_icons();
 
-    let app = RelmApp::new("password-manager-client");
+    // The options were handled above, GTK would reject them as unknown
+    let app = RelmApp::new("password-manager-client").with_args(Vec::new());
     app.run::<AppTopWrapper>(state);
 }
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cache::{read_state_file, write_state_file};
+use crate::encryption::{decrypt_data_entry, encrypt_data_entry};
+use crate::entries::is_reserved_content_type;
+use crate::error::Error;
+use crate::model::{EncryptedDataEntry, Manifest, ManifestEntry, VaultCipher};
+
+pub const MANIFEST_CONTENT_TYPE: &str = "manifest";
+const MANIFEST_ANCHOR_SUFFIX: &str = ".manifest.json";
+
+// The newest manifest this device has seen on the server, kept locally so that a server rolling
+// back the whole vault together with its manifest is still noticed. It lives in the state
+// directory, clearing the cache must not reset it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestAnchor {
+    pub email: String,
+    pub version: u64,
+    pub digest: Vec<u8>,
+}
+
+impl ManifestAnchor {
+    pub fn new(email: &str, manifest: &Manifest) -> ManifestAnchor {
+        ManifestAnchor {
+            email: email.to_string(),
+            version: manifest.version,
+            digest: digest_manifest(manifest),
+        }
+    }
+
+    // Returns None on the first login from this device
+    pub fn load(server_url: &str, email: &str) -> Result<Option<ManifestAnchor>, Error> {
+        read_state_file(server_url, email, MANIFEST_ANCHOR_SUFFIX)
+    }
+
+    pub fn save(&self, server_url: &str) -> Result<(), Error> {
+        write_state_file(server_url, &self.email, MANIFEST_ANCHOR_SUFFIX, self)
+    }
+
+    // Returns a warning when the manifest is older than this one or differs at the same version
+    pub fn check(&self, manifest: &Manifest) -> Option<String> {
+        if manifest.version < self.version {
+            return Some(format!(
+                "The vault manifest is at version {} but this device already saw version {}, the server may have rolled back the vault",
+                manifest.version, self.version
+            ));
+        }
+
+        if manifest.version == self.version && digest_manifest(manifest) != self.digest {
+            return Some(format!(
+                "The vault manifest at version {} is not the one this device saw",
+                manifest.version
+            ));
+        }
+
+        None
+    }
+}
+
+// Called once the manifest is known to be on the server, the anchor never moves back so a
+// rolled back manifest keeps being reported
+pub fn remember_manifest(server_url: &str, email: &str, manifest: &Manifest) {
+    match ManifestAnchor::load(server_url, email) {
+        Ok(Some(anchor)) if anchor.version > manifest.version => return,
+        Ok(_) => (),
+        Err(e) => println!("Replacing unreadable manifest anchor: {}", e),
+    }
+
+    if let Err(e) = ManifestAnchor::new(email, manifest).save(server_url) {
+        println!("Failed to save manifest anchor: {}", e);
+    }
+}
+
+fn digest_manifest(manifest: &Manifest) -> Vec<u8> {
+    // The entries are a BTreeMap so the serialization does not depend on insertion order
+    match serde_json::to_vec(manifest) {
+        Ok(serialized) => Sha256::digest(&serialized).to_vec(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn digest_entry(encrypted_data_entry: &EncryptedDataEntry) -> Vec<u8> {
+    Sha256::digest(&encrypted_data_entry.content).to_vec()
+}
+
+pub fn create_manifest(encrypted_data_entries: &[EncryptedDataEntry]) -> Manifest {
+    let mut manifest = Manifest::default();
+
+    for encrypted_data_entry in encrypted_data_entries {
+        record_manifest_entry(&mut manifest, encrypted_data_entry);
+    }
+
+    manifest
+}
+
+// Adds a new entry or bumps the version of an entry that was re-encrypted
+pub fn record_manifest_entry(manifest: &mut Manifest, encrypted_data_entry: &EncryptedDataEntry) {
+    if is_reserved_content_type(&encrypted_data_entry.content_type) {
+        return;
+    }
+
+    let key = encrypted_data_entry.key();
+    let digest = digest_entry(encrypted_data_entry);
+
+    match manifest.entries.get_mut(&key) {
+        Some(manifest_entry) => {
+            if manifest_entry.digest != digest {
+                manifest_entry.version += 1;
+                manifest_entry.digest = digest;
+                manifest.version += 1;
+            }
+        }
+        None => {
+            manifest
+                .entries
+                .insert(key, ManifestEntry { version: 1, digest });
+            manifest.version += 1;
+        }
+    }
+}
+
+pub fn remove_manifest_entry(manifest: &mut Manifest, key: &str) {
+    if manifest.entries.remove(key).is_some() {
+        manifest.version += 1;
+    }
+}
+
+// Drops the entries the server no longer has and records the ones it changed or added
+pub fn rebase_manifest(manifest: &mut Manifest, encrypted_data_entries: &[EncryptedDataEntry]) {
+    let keys: HashSet<String> = encrypted_data_entries
+        .iter()
+        .map(|entry| entry.key())
+        .collect();
+
+    let len = manifest.entries.len();
+    manifest.entries.retain(|key, _| keys.contains(key));
+
+    if manifest.entries.len() != len {
+        manifest.version += 1;
+    }
+
+    for encrypted_data_entry in encrypted_data_entries {
+        record_manifest_entry(manifest, encrypted_data_entry);
+    }
+}
+
+// Returns a warning for every entry the server dropped, duplicated, rolled back or made up
+pub fn verify_manifest(
+    manifest: &Manifest,
+    encrypted_data_entries: &[EncryptedDataEntry],
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for encrypted_data_entry in encrypted_data_entries {
+        if is_reserved_content_type(&encrypted_data_entry.content_type) {
+            continue;
+        }
+
+        let key = encrypted_data_entry.key();
+
+        let count = seen.entry(key.clone()).or_insert(0);
+        *count += 1;
+
+        if *count == 2 {
+            warnings.push(format!("Entry {} is duplicated", key));
+        }
+
+        match manifest.entries.get(&key) {
+            Some(manifest_entry) => {
+                if manifest_entry.digest != digest_entry(encrypted_data_entry) {
+                    warnings.push(format!(
+                        "Entry {} does not match version {} and may have been rolled back",
+                        key, manifest_entry.version
+                    ));
+                }
+            }
+            None => warnings.push(format!("Entry {} is not in the vault manifest", key)),
+        }
+    }
+
+    for key in manifest.entries.keys() {
+        if !seen.contains_key(key) {
+            warnings.push(format!("Entry {} is missing", key));
+        }
+    }
+
+    warnings
+}
+
+// The manifest is stored as a reserved entry encrypted with its own vault subkey
+pub fn encrypt_manifest(
+    manifest: &Manifest,
+    cipher: &VaultCipher,
+) -> Result<EncryptedDataEntry, Error> {
+    let serialized_data = match serde_json::to_string(manifest) {
+        Ok(data) => data,
+        Err(e) => {
+            return Err(Error::Decode(format!(
+                "Failed to serialize manifest: {}",
+                e
+            )))
+        }
+    };
+
+    let (content, nonce) = encrypt_data_entry(
+        &serialized_data,
+        MANIFEST_CONTENT_TYPE,
+        MANIFEST_CONTENT_TYPE,
+        cipher,
+    )?;
+
+    Ok(EncryptedDataEntry {
+        id: String::new(),
+        name: MANIFEST_CONTENT_TYPE.to_string(),
+        content,
+        nonce,
+        content_type: MANIFEST_CONTENT_TYPE.to_string(),
+        revision: None,
+    })
+}
+
+pub fn decrypt_manifest(
+    encrypted_manifest: &EncryptedDataEntry,
+    cipher: &VaultCipher,
+) -> Result<Manifest, Error> {
+    let data = decrypt_data_entry(encrypted_manifest, cipher)?;
+
+    match serde_json::from_str::<Manifest>(&data) {
+        Ok(manifest) => Ok(manifest),
+        Err(e) => Err(Error::Decode(format!(
+            "Failed to deserialize manifest: {}",
+            e
+        ))),
+    }
+}
+
+pub fn find_manifest_entry(
+    encrypted_data_entries: &[EncryptedDataEntry],
+) -> Option<&EncryptedDataEntry> {
+    encrypted_data_entries
+        .iter()
+        .find(|entry| entry.content_type == MANIFEST_CONTENT_TYPE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, content: &[u8]) -> EncryptedDataEntry {
+        EncryptedDataEntry {
+            id: id.to_string(),
+            name: id.to_string(),
+            content: content.to_vec(),
+            nonce: Vec::new(),
+            content_type: "password".to_string(),
+            revision: None,
+        }
+    }
+
+    #[test]
+    fn verify_accepts_matching_entries() {
+        let entries = vec![entry("a", b"one"), entry("b", b"two")];
+        let manifest = create_manifest(&entries);
+
+        assert!(verify_manifest(&manifest, &entries).is_empty());
+    }
+
+    #[test]
+    fn verify_reports_missing_changed_unknown_and_duplicated_entries() {
+        let manifest = create_manifest(&[entry("a", b"one"), entry("b", b"two")]);
+
+        let entries = vec![
+            entry("a", b"older"),
+            entry("c", b"three"),
+            entry("c", b"three"),
+        ];
+        let warnings = verify_manifest(&manifest, &entries);
+
+        assert!(warnings.iter().any(|w| w.contains("a does not match")));
+        assert!(warnings.iter().any(|w| w.contains("b is missing")));
+        assert!(warnings.iter().any(|w| w.contains("c is not in")));
+        assert!(warnings.iter().any(|w| w.contains("c is duplicated")));
+    }
+
+    #[test]
+    fn version_only_moves_on_changes() {
+        let mut manifest = create_manifest(&[entry("a", b"one")]);
+        let version = manifest.version;
+
+        record_manifest_entry(&mut manifest, &entry("a", b"one"));
+        remove_manifest_entry(&mut manifest, "missing");
+        assert_eq!(manifest.version, version);
+
+        record_manifest_entry(&mut manifest, &entry("a", b"two"));
+        assert_eq!(manifest.version, version + 1);
+
+        remove_manifest_entry(&mut manifest, "a");
+        assert_eq!(manifest.version, version + 2);
+    }
+
+    #[test]
+    fn anchor_flags_older_and_diverging_manifests() {
+        let old_manifest = create_manifest(&[entry("a", b"one")]);
+        let mut manifest = old_manifest.clone();
+        record_manifest_entry(&mut manifest, &entry("b", b"two"));
+
+        let anchor = ManifestAnchor::new("user@example.com", &manifest);
+
+        assert!(anchor.check(&manifest).is_none());
+        assert!(anchor.check(&old_manifest).is_some());
+
+        let mut newer_manifest = manifest.clone();
+        record_manifest_entry(&mut newer_manifest, &entry("c", b"three"));
+        assert!(anchor.check(&newer_manifest).is_none());
+
+        let mut diverging_manifest = old_manifest.clone();
+        record_manifest_entry(&mut diverging_manifest, &entry("d", b"four"));
+        assert_eq!(diverging_manifest.version, anchor.version);
+        assert!(anchor.check(&diverging_manifest).is_some());
+    }
+
+    #[test]
+    fn manifests_without_version_still_load() {
+        let manifest: Manifest = serde_json::from_str(r#"{"entries":{}}"#).unwrap();
+
+        assert_eq!(manifest.version, 0);
+    }
+}
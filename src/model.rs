@@ -1,31 +1,64 @@
 use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::XChaCha20Poly1305;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-use crate::encryption::generate_all_master_ciphers;
-use crate::entries::fill_data_vault_from_response;
+use crate::encryption::generate_all_vault_ciphers;
+use crate::entries::fill_data_vault_from_entries;
+use crate::error::Error;
+use crate::outbox::Outbox;
+use crate::vault_entry::VaultEntry;
 
 // Request structures
-#[derive(Debug, Serialize, Deserialize)]
+// Structures holding passwords, authentication hashes or entry contents are wiped on drop
+#[derive(Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct LoginRequest {
     pub email: String,
     pub password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct RegisterRequest {
     pub email: String,
     pub password: String,
+    #[zeroize(skip)]
+    pub kdf: KdfParams,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_hash: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct ChangePasswordRequest {
     pub old_password: String,
     pub new_password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[zeroize(skip)]
+    pub kdf: Option<KdfParams>,
+}
+
+// Resets the master password with the hash derived from the recovery code
+#[derive(Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct RecoverRequest {
+    pub email: String,
+    pub recovery_hash: String,
+    pub new_password: String,
+    #[zeroize(skip)]
+    pub kdf: KdfParams,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreloginRequest {
+    pub email: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AddEncryptedDataEntryRequest {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub id: String,
     pub name: String,
     pub content: Vec<u8>,
     pub nonce: Vec<u8>,
@@ -34,17 +67,27 @@ pub struct AddEncryptedDataEntryRequest {
 
 #[derive(Debug, Serialize)]
 pub struct UpdateEncryptedDataEntryRequest {
+    // The entry is found by id, old_name only finds entries uploaded before they had one
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub id: String,
     pub content_type: String,
     pub old_name: String,
     pub new_name: String,
     pub new_content: Vec<u8>,
     pub new_nonce: Vec<u8>,
+    // The server refuses the update when the entry has moved on from this revision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_revision: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct DeleteEncryptedDataEntryRequest {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub id: String,
     pub name: String,
     pub content_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_revision: Option<u64>,
 }
 
 // Response structures
@@ -72,6 +115,23 @@ pub struct SimpleResponse {
     pub status: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreloginResponse {
+    pub status: String,
+    pub data: PreloginData,
+}
+
+// Only an explicit flag from the server sends the client down the legacy login, a failed or
+// incomplete prelogin never does, since that login sends the master password itself
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreloginData {
+    #[serde(default)]
+    pub is_legacy_account: bool,
+    // Missing for accounts registered before the settings were stored per account
+    #[serde(flatten)]
+    pub kdf_params: Option<KdfParams>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EncryptedDataEntryResponse {
     pub status: String,
@@ -82,11 +142,45 @@ pub struct EncryptedDataEntryResponse {
 pub struct GetAllEncryptedDataEntriesResponse {
     pub status: String,
     pub data: Vec<EncryptedDataEntry>,
+    // Where the next delta sync starts, missing from servers that only return the whole vault
+    #[serde(default)]
+    pub cursor: Option<u64>,
 }
 
-// Data structures
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptedDataEntryChangesResponse {
+    pub status: String,
+    pub data: EncryptedDataEntryChanges,
+}
+
+// Everything written or deleted on the server after a cursor
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedDataEntryChanges {
+    pub changed: Vec<EncryptedDataEntry>,
+    pub deleted: Vec<DeletedDataEntry>,
+    pub cursor: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeletedDataEntry {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+}
+
+impl DeletedDataEntry {
+    pub fn key(&self) -> String {
+        entry_key(&self.id, &self.name, &self.content_type)
+    }
+}
+
+// Data structures
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct Password {
+    // Empty in entries encrypted before they had one
+    #[serde(default)]
+    pub id: String,
     pub name: String,
     pub username: String,
     pub password: String,
@@ -95,15 +189,21 @@ pub struct Password {
     pub created_at: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct Note {
+    // Empty in entries encrypted before they had one
+    #[serde(default)]
+    pub id: String,
     pub name: String,
     pub content: String,
     pub created_at: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct Card {
+    // Empty in entries encrypted before they had one
+    #[serde(default)]
+    pub id: String,
     pub name: String,
     pub cardholder_name: String,
     pub card_number: String,
@@ -112,8 +212,11 @@ pub struct Card {
     pub created_at: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct TOTPEntry {
+    // Empty in entries encrypted before they had one
+    #[serde(default)]
+    pub id: String,
     pub name: String,
     pub algorithm: String,
     pub secret: String,
@@ -123,52 +226,250 @@ pub struct TOTPEntry {
     pub created_at: String,
 }
 
+// Key derivation settings of an account, stored on the server next to the account
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub memory_cost: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: Vec<u8>,
+}
+
+// Identifies the AEAD algorithm in the envelope header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CipherSuite {
+    Aes256GcmSiv = 1,
+    XChaCha20Poly1305 = 2,
+}
+
+// Holds a key schedule for every suite so entries written in any of them can be opened
+pub struct VaultCipher {
+    pub suite: CipherSuite,
+    pub aes_256_gcm_siv: Aes256GcmSiv,
+    pub xchacha20_poly1305: XChaCha20Poly1305,
+}
+
+// Both derived from the master password; only the authentication hash ever leaves the client
+// The ciphers wipe their own key schedules on drop
+#[derive(ZeroizeOnDrop)]
+pub struct MasterKeys {
+    pub auth_hash: String,
+    #[zeroize(skip)]
+    pub vault_key_cipher: VaultCipher,
+}
+
+// One cipher per entry type, by content type
 pub struct Ciphers {
-    pub password_cipher: Aes256GcmSiv,
-    pub note_cipher: Aes256GcmSiv,
-    pub card_cipher: Aes256GcmSiv,
-    pub totp_entry_cipher: Aes256GcmSiv,
+    pub ciphers: HashMap<&'static str, VaultCipher>,
+}
+
+impl Ciphers {
+    pub fn get(&self, content_type: &str) -> Result<&VaultCipher, Error> {
+        match self.ciphers.get(content_type) {
+            Some(cipher) => Ok(cipher),
+            None => Err(Error::Decode(format!(
+                "Unknown content type: {}",
+                content_type
+            ))),
+        }
+    }
 }
 
-#[derive(Clone)]
+// Every decrypted entry, in no particular order since they are decrypted in parallel
 pub struct EntriesVault {
-    pub passwords: Vec<Password>,
-    pub notes: Vec<Note>,
-    pub cards: Vec<Card>,
-    pub totp_entries: Vec<TOTPEntry>,
+    pub entries: Vec<VaultEntry>,
+}
+
+impl EntriesVault {
+    pub fn of_type<'a>(&'a self, content_type: &'a str) -> impl Iterator<Item = &'a VaultEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.content_type() == content_type)
+    }
+
+    // Entries are shown by type, so the list only knows their position among their type
+    pub fn nth_of_type(&self, content_type: &str, index: usize) -> Option<&VaultEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.content_type() == content_type)
+            .nth(index)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&VaultEntry> {
+        self.entries.iter().find(|entry| entry.id() == id)
+    }
+
+    // Replaces the entry with the same id where it is, or adds it, returning whether it
+    // replaced one
+    pub fn store(&mut self, vault_entry: VaultEntry) -> bool {
+        match self
+            .entries
+            .iter()
+            .position(|entry| entry.id() == vault_entry.id())
+        {
+            Some(position) => {
+                self.entries[position] = vault_entry;
+                true
+            }
+            None => {
+                self.entries.push(vault_entry);
+                false
+            }
+        }
+    }
+
+    // Returns whether there was an entry to remove
+    pub fn remove(&mut self, id: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.id() != id);
+
+        self.entries.len() != len
+    }
+}
+
+// Versions and ciphertext digests of every entry, stored encrypted to detect server tampering
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    // Bumped on every change, so that an older manifest replayed by the server can be told apart
+    #[serde(default)]
+    pub version: u64,
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub version: u64,
+    pub digest: Vec<u8>,
 }
 
 pub struct DataVault {
+    pub email: String,
+    pub kdf_params: Option<KdfParams>,
+    pub vault_key: Zeroizing<Vec<u8>>,
     pub ciphers: Ciphers,
     pub entries_vault: EntriesVault,
+    pub manifest: Manifest,
+    pub integrity_warnings: Vec<String>,
+    // What the manifest becomes when the user accepts the server state despite the warnings
+    pub rebased_manifest: Option<Manifest>,
+    // Shown by an earlier refresh, so the next ones only show what is new
+    pub refresh_warnings: Vec<String>,
+    // Set when the server could not be reached and the offline copy was opened instead,
+    // to when that copy was last synced
+    pub offline_synced_at: Option<DateTime<Utc>>,
+    // Server revisions of the entries, by entry key
+    pub revisions: HashMap<String, u64>,
+    // Where the next delta sync starts, None when the server does not support it
+    pub sync_cursor: Option<u64>,
+    pub outbox: Outbox,
+}
+
+// Only the account is printed, the keys and entries never end up in a log
+impl fmt::Debug for DataVault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DataVault")
+            .field("email", &self.email)
+            .finish_non_exhaustive()
+    }
 }
 
 impl DataVault {
-    pub fn new(email: &str, password: &str) -> Result<DataVault, String> {
+    pub fn new(
+        email: &str,
+        kdf_params: Option<KdfParams>,
+        vault_key: &[u8],
+    ) -> Result<DataVault, Error> {
         Ok(DataVault {
-            ciphers: match generate_all_master_ciphers(email, password) {
-                Ok(ciphers) => ciphers,
-                Err(e) => return Err(e),
-            },
+            email: email.to_string(),
+            kdf_params,
+            vault_key: Zeroizing::new(vault_key.to_vec()),
+            ciphers: generate_all_vault_ciphers(vault_key)?,
             entries_vault: EntriesVault {
-                passwords: Vec::new(),
-                notes: Vec::new(),
-                cards: Vec::new(),
-                totp_entries: Vec::new(),
+                entries: Vec::new(),
             },
+            manifest: Manifest::default(),
+            integrity_warnings: Vec::new(),
+            rebased_manifest: None,
+            refresh_warnings: Vec::new(),
+            offline_synced_at: None,
+            revisions: HashMap::new(),
+            sync_cursor: None,
+            outbox: Outbox::new(email),
         })
     }
 
-    pub fn fill(&mut self, encrypted_data_entries_response: GetAllEncryptedDataEntriesResponse) {
-        fill_data_vault_from_response(self, encrypted_data_entries_response);
+    pub fn is_offline(&self) -> bool {
+        self.offline_synced_at.is_some()
+    }
+
+    pub fn revision(&self, key: &str) -> Option<u64> {
+        self.revisions.get(key).copied()
+    }
+
+    // Records the revision the server gave an entry, entries without one are forgotten
+    pub fn set_revision(&mut self, encrypted_data_entry: &EncryptedDataEntry) {
+        let key = encrypted_data_entry.key();
+
+        match encrypted_data_entry.revision {
+            Some(revision) => {
+                self.revisions.insert(key, revision);
+            }
+            None => {
+                self.revisions.remove(&key);
+            }
+        }
+    }
+
+    pub fn remove_revision(&mut self, key: &str) {
+        self.revisions.remove(key);
+    }
+
+    pub fn fill(
+        &mut self,
+        encrypted_data_entries: &[EncryptedDataEntry],
+    ) -> (Vec<EncryptedDataEntry>, Vec<EncryptedDataEntry>) {
+        fill_data_vault_from_entries(self, encrypted_data_entries)
     }
 }
 
 // Encrypted data structures
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct EncryptedDataEntry {
+    // Generated by the client and also stored inside the content, empty for reserved entries
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
     pub name: String,
     pub content: Vec<u8>,
     pub nonce: Vec<u8>,
     pub content_type: String,
+    // Bumped by the server on every write, missing from servers that do not track revisions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+}
+
+impl EncryptedDataEntry {
+    pub fn key(&self) -> String {
+        entry_key(&self.id, &self.name, &self.content_type)
+    }
+}
+
+// What entries are told apart by, the id or, for reserved entries and entries uploaded before
+// they had one, the content type and name
+pub fn entry_key(id: &str, name: &str, content_type: &str) -> String {
+    if id.is_empty() {
+        format!("{}/{}", content_type, name)
+    } else {
+        id.to_string()
+    }
+}
+
+pub fn generate_entry_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+// Sent with a write so that a retried one is only applied once
+pub fn generate_idempotency_key() -> String {
+    Uuid::new_v4().to_string()
 }
@@ -0,0 +1,461 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{read_state_file, remove_entry, store_entry, write_state_file};
+use crate::error::Error;
+use crate::manifest::{record_manifest_entry, remove_manifest_entry};
+use crate::model::{entry_key, generate_idempotency_key, EncryptedDataEntry, Manifest};
+
+const OUTBOX_SUFFIX: &str = ".outbox.json";
+
+// A change the server has not accepted yet, made against the revision the entry had then. The
+// idempotency key is the one of the first attempt and stays with the change across restarts, so
+// the server can tell a change it already took from a new one. It is empty in changes queued
+// before they had one
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueuedChange {
+    Add {
+        entry: EncryptedDataEntry,
+        #[serde(default)]
+        idempotency_key: String,
+    },
+    // old_name is the name the server knows the entry by
+    Update {
+        old_name: String,
+        entry: EncryptedDataEntry,
+        base_revision: Option<u64>,
+        #[serde(default)]
+        idempotency_key: String,
+    },
+    Delete {
+        // Empty in changes queued before entries had one
+        #[serde(default)]
+        id: String,
+        name: String,
+        content_type: String,
+        base_revision: Option<u64>,
+        #[serde(default)]
+        idempotency_key: String,
+    },
+}
+
+impl QueuedChange {
+    // What the entry is told apart by, which a change never modifies
+    pub fn key(&self) -> String {
+        match self {
+            QueuedChange::Add { entry, .. } => entry.key(),
+            QueuedChange::Update {
+                old_name, entry, ..
+            } => entry_key(&entry.id, old_name, &entry.content_type),
+            QueuedChange::Delete {
+                id,
+                name,
+                content_type,
+                ..
+            } => entry_key(id, name, content_type),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            QueuedChange::Add { entry, .. } => &entry.id,
+            QueuedChange::Update { entry, .. } => &entry.id,
+            QueuedChange::Delete { id, .. } => id,
+        }
+    }
+
+    // The name the server knows the entry by
+    pub fn name(&self) -> &str {
+        match self {
+            QueuedChange::Add { entry, .. } => &entry.name,
+            QueuedChange::Update { old_name, .. } => old_name,
+            QueuedChange::Delete { name, .. } => name,
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        match self {
+            QueuedChange::Add { entry, .. } => &entry.content_type,
+            QueuedChange::Update { entry, .. } => &entry.content_type,
+            QueuedChange::Delete { content_type, .. } => content_type,
+        }
+    }
+
+    // The entry as it is after the change, None once it is deleted
+    pub fn local_entry(&self) -> Option<&EncryptedDataEntry> {
+        match self {
+            QueuedChange::Add { entry, .. } => Some(entry),
+            QueuedChange::Update { entry, .. } => Some(entry),
+            QueuedChange::Delete { .. } => None,
+        }
+    }
+
+    // Changes queued before they had a key get a new one every time they are sent
+    pub fn idempotency_key(&self) -> String {
+        let idempotency_key = match self {
+            QueuedChange::Add {
+                idempotency_key, ..
+            } => idempotency_key,
+            QueuedChange::Update {
+                idempotency_key, ..
+            } => idempotency_key,
+            QueuedChange::Delete {
+                idempotency_key, ..
+            } => idempotency_key,
+        };
+
+        if idempotency_key.is_empty() {
+            generate_idempotency_key()
+        } else {
+            idempotency_key.clone()
+        }
+    }
+}
+
+// A queued change the server refused because the entry changed there in the meantime
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncConflict {
+    pub change: QueuedChange,
+    // None when the server no longer has the entry
+    pub remote: Option<EncryptedDataEntry>,
+}
+
+// Changes made while the server could not be reached, in the order they were made. The entries
+// are encrypted like on the server, only the names and content types can be read
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Outbox {
+    pub email: String,
+    pub changes: Vec<QueuedChange>,
+    // Set when the manifest on the server lists a version the user threw away
+    #[serde(default)]
+    pub is_manifest_outdated: bool,
+    // Refused changes stay queued but are not sent again until the user picks a side, also
+    // after a restart
+    #[serde(default)]
+    pub conflicts: Vec<SyncConflict>,
+}
+
+impl Outbox {
+    pub fn new(email: &str) -> Outbox {
+        Outbox {
+            email: email.to_string(),
+            changes: Vec::new(),
+            is_manifest_outdated: false,
+            conflicts: Vec::new(),
+        }
+    }
+
+    pub fn load(server_url: &str, email: &str) -> Result<Outbox, Error> {
+        match read_state_file::<Outbox>(server_url, email, OUTBOX_SUFFIX)? {
+            Some(outbox) if outbox.email == email => Ok(outbox),
+            Some(_) => Err(Error::Storage(
+                "The queued changes belong to another account".to_string(),
+            )),
+            None => Ok(Outbox::new(email)),
+        }
+    }
+
+    pub fn save(&self, server_url: &str) -> Result<(), Error> {
+        write_state_file(server_url, &self.email, OUTBOX_SUFFIX, self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // Whether a queued change still has to go out for the entry
+    pub fn has_change_for(&self, key: &str) -> bool {
+        self.changes.iter().any(|change| change.key() == key)
+    }
+
+    // The changes that can be sent, leaving out the ones waiting for the user
+    pub fn sendable_changes(&self) -> Vec<QueuedChange> {
+        self.changes
+            .iter()
+            .filter(|change| {
+                !self
+                    .conflicts
+                    .iter()
+                    .any(|conflict| &conflict.change == *change)
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Folds the change into the one already queued for the same entry, so that every entry
+    // is sent once and against the revision it had before the first change. The folded change
+    // takes the key of the newer one, the server must not answer it with what it stored for
+    // the older one
+    pub fn push(&mut self, change: QueuedChange) {
+        let key = change.key();
+        let position = self.changes.iter().position(|queued| queued.key() == key);
+
+        let queued = match position {
+            Some(position) => self.changes.remove(position),
+            None => {
+                self.changes.push(change);
+                return;
+            }
+        };
+
+        match (queued, change) {
+            (
+                QueuedChange::Add { .. },
+                QueuedChange::Update {
+                    entry,
+                    idempotency_key,
+                    ..
+                },
+            ) => {
+                self.changes.push(QueuedChange::Add {
+                    entry,
+                    idempotency_key,
+                });
+            }
+            // The server never saw the entry
+            (QueuedChange::Add { .. }, QueuedChange::Delete { .. }) => {}
+            (
+                QueuedChange::Update {
+                    old_name,
+                    base_revision,
+                    ..
+                },
+                QueuedChange::Update {
+                    entry,
+                    idempotency_key,
+                    ..
+                },
+            ) => {
+                self.changes.push(QueuedChange::Update {
+                    old_name,
+                    entry,
+                    base_revision,
+                    idempotency_key,
+                });
+            }
+            (
+                QueuedChange::Update {
+                    old_name,
+                    base_revision,
+                    ..
+                },
+                QueuedChange::Delete {
+                    id,
+                    content_type,
+                    idempotency_key,
+                    ..
+                },
+            ) => {
+                self.changes.push(QueuedChange::Delete {
+                    id,
+                    name: old_name,
+                    content_type,
+                    base_revision,
+                    idempotency_key,
+                });
+            }
+            (queued, change) => {
+                self.changes.push(queued);
+                self.changes.push(change);
+            }
+        }
+    }
+
+    // Replaces a queued change, or queues it again if it was dropped in the meantime
+    pub fn replace(&mut self, queued: &QueuedChange, change: QueuedChange) {
+        match self.changes.iter().position(|other| other == queued) {
+            Some(position) => self.changes[position] = change,
+            None => self.changes.push(change),
+        }
+    }
+
+    pub fn remove(&mut self, queued: &QueuedChange) {
+        self.changes.retain(|other| other != queued);
+    }
+
+    // Applies the queued changes on top of the entries and manifest as last seen on the server
+    pub fn apply_to(&self, entries: &mut Vec<EncryptedDataEntry>, manifest: &mut Manifest) {
+        for change in self.changes.iter() {
+            remove_entry(entries, &change.key());
+            remove_manifest_entry(manifest, &change.key());
+
+            if let Some(entry) = change.local_entry() {
+                store_entry(entries, entry);
+                record_manifest_entry(manifest, entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::verify_manifest;
+
+    fn entry(id: &str, name: &str, revision: Option<u64>) -> EncryptedDataEntry {
+        EncryptedDataEntry {
+            id: id.to_string(),
+            name: name.to_string(),
+            content: vec![1],
+            nonce: vec![2],
+            content_type: "password".to_string(),
+            revision,
+        }
+    }
+
+    fn add(entry: EncryptedDataEntry) -> QueuedChange {
+        QueuedChange::Add {
+            entry,
+            idempotency_key: "add".to_string(),
+        }
+    }
+
+    fn update(
+        old_name: &str,
+        entry: EncryptedDataEntry,
+        base_revision: Option<u64>,
+    ) -> QueuedChange {
+        QueuedChange::Update {
+            old_name: old_name.to_string(),
+            entry,
+            base_revision,
+            idempotency_key: "update".to_string(),
+        }
+    }
+
+    fn delete(id: &str, name: &str, base_revision: Option<u64>) -> QueuedChange {
+        QueuedChange::Delete {
+            id: id.to_string(),
+            name: name.to_string(),
+            content_type: "password".to_string(),
+            base_revision,
+            idempotency_key: "delete".to_string(),
+        }
+    }
+
+    #[test]
+    fn updates_fold_into_the_queued_add() {
+        let mut outbox = Outbox::new("user@example.com");
+        outbox.push(add(entry("a", "mail", None)));
+        outbox.push(update("mail", entry("a", "work mail", None), None));
+
+        // The server may have taken the add, the update must not be answered with it
+        assert_eq!(
+            outbox.changes,
+            vec![QueuedChange::Add {
+                entry: entry("a", "work mail", None),
+                idempotency_key: "update".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn deleting_an_entry_the_server_never_saw_drops_it() {
+        let mut outbox = Outbox::new("user@example.com");
+        outbox.push(add(entry("a", "mail", None)));
+        outbox.push(delete("a", "mail", None));
+
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn updates_keep_the_first_name_and_revision() {
+        let mut outbox = Outbox::new("user@example.com");
+        outbox.push(update("mail", entry("a", "work mail", None), Some(3)));
+        outbox.push(update("work mail", entry("a", "old mail", None), Some(4)));
+
+        assert_eq!(
+            outbox.changes,
+            vec![update("mail", entry("a", "old mail", None), Some(3))]
+        );
+
+        outbox.push(delete("a", "old mail", None));
+
+        assert_eq!(outbox.changes, vec![delete("a", "mail", Some(3))]);
+    }
+
+    #[test]
+    fn changes_to_other_entries_are_kept_in_order() {
+        let mut outbox = Outbox::new("user@example.com");
+        outbox.push(update("mail", entry("a", "mail", None), Some(1)));
+        outbox.push(delete("b", "bank", Some(2)));
+        outbox.push(add(entry("c", "shop", None)));
+
+        let keys: Vec<String> = outbox.changes.iter().map(|change| change.key()).collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        assert!(outbox.has_change_for("b"));
+        assert!(!outbox.has_change_for("d"));
+    }
+
+    #[test]
+    fn queued_changes_go_on_top_of_the_server_state() {
+        let mut outbox = Outbox::new("user@example.com");
+        outbox.push(update("mail", entry("a", "work mail", None), Some(1)));
+        outbox.push(delete("b", "bank", Some(1)));
+        outbox.push(add(entry("c", "shop", None)));
+
+        let mut entries = vec![entry("a", "mail", Some(1)), entry("b", "bank", Some(1))];
+        let mut manifest = Manifest::default();
+        for server_entry in entries.iter() {
+            record_manifest_entry(&mut manifest, server_entry);
+        }
+
+        outbox.apply_to(&mut entries, &mut manifest);
+
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["work mail", "shop"]);
+        assert!(verify_manifest(&manifest, &entries).is_empty());
+    }
+
+    #[test]
+    fn conflicts_are_kept_across_restarts() {
+        let change = QueuedChange::Update {
+            old_name: "mail".to_string(),
+            entry: entry("a", "mail", Some(1)),
+            base_revision: Some(1),
+            idempotency_key: "update".to_string(),
+        };
+
+        let mut outbox = Outbox::new("user@example.com");
+        outbox.push(change.clone());
+        outbox.conflicts.push(SyncConflict {
+            change: change.clone(),
+            remote: Some(entry("a", "mail", Some(2))),
+        });
+
+        let json = serde_json::to_string(&outbox).unwrap();
+        let loaded: Outbox = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.conflicts, outbox.conflicts);
+        assert!(loaded.sendable_changes().is_empty());
+    }
+
+    #[test]
+    fn queued_changes_keep_their_idempotency_key_across_restarts() {
+        let mut outbox = Outbox::new("user@example.com");
+        outbox.push(update("mail", entry("a", "mail", None), Some(1)));
+
+        let json = serde_json::to_string(&outbox).unwrap();
+        let loaded: Outbox = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.changes[0].idempotency_key(), "update");
+    }
+
+    #[test]
+    fn changes_queued_without_an_idempotency_key_get_one() {
+        let loaded: Outbox = serde_json::from_str(
+            r#"{"email":"user@example.com","changes":[{"Delete":{"id":"a","name":"mail","content_type":"password","base_revision":1}}]}"#,
+        )
+        .unwrap();
+
+        assert!(!loaded.changes[0].idempotency_key().is_empty());
+    }
+
+    #[test]
+    fn outboxes_without_conflicts_still_load() {
+        let loaded: Outbox =
+            serde_json::from_str(r#"{"email":"user@example.com","changes":[]}"#).unwrap();
+
+        assert!(loaded.conflicts.is_empty());
+        assert!(!loaded.is_manifest_outdated);
+    }
+}
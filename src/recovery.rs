@@ -0,0 +1,138 @@
+use zeroize::Zeroizing;
+
+use crate::encryption::{
+    decrypt_data_entry, encrypt_data_entry, generate_recovery_keys, generate_vault_cipher,
+    wrap_vault_key,
+};
+use crate::error::Error;
+use crate::model::EncryptedDataEntry;
+
+// Reserved entry holding the vault key wrapped by the key derived from the recovery code
+pub const RECOVERY_KEY_CONTENT_TYPE: &str = "recovery_key";
+
+// Reserved entry holding the recovery code itself under the vault key, so that rotating
+// the vault key can wrap the new one for recovery without asking for the code
+pub const RECOVERY_CODE_CONTENT_TYPE: &str = "recovery_code";
+
+pub fn create_recovery_entries(
+    recovery_code: &str,
+    vault_key: &[u8],
+) -> Result<Vec<EncryptedDataEntry>, Error> {
+    let recovery_keys = generate_recovery_keys(recovery_code)?;
+
+    let (recovery_key_content, recovery_key_nonce) = wrap_vault_key(
+        vault_key,
+        RECOVERY_KEY_CONTENT_TYPE,
+        RECOVERY_KEY_CONTENT_TYPE,
+        &recovery_keys.vault_key_cipher,
+    )?;
+
+    let (recovery_code_content, recovery_code_nonce) = encrypt_data_entry(
+        recovery_code,
+        RECOVERY_CODE_CONTENT_TYPE,
+        RECOVERY_CODE_CONTENT_TYPE,
+        &generate_vault_cipher(vault_key, RECOVERY_CODE_CONTENT_TYPE)?,
+    )?;
+
+    Ok(vec![
+        EncryptedDataEntry {
+            id: String::new(),
+            name: RECOVERY_KEY_CONTENT_TYPE.to_string(),
+            content: recovery_key_content,
+            nonce: recovery_key_nonce,
+            content_type: RECOVERY_KEY_CONTENT_TYPE.to_string(),
+            revision: None,
+        },
+        EncryptedDataEntry {
+            id: String::new(),
+            name: RECOVERY_CODE_CONTENT_TYPE.to_string(),
+            content: recovery_code_content,
+            nonce: recovery_code_nonce,
+            content_type: RECOVERY_CODE_CONTENT_TYPE.to_string(),
+            revision: None,
+        },
+    ])
+}
+
+pub fn decrypt_recovery_code_entry(
+    recovery_code_entry: &EncryptedDataEntry,
+    vault_key: &[u8],
+) -> Result<Zeroizing<String>, Error> {
+    decrypt_data_entry(
+        recovery_code_entry,
+        &generate_vault_cipher(vault_key, RECOVERY_CODE_CONTENT_TYPE)?,
+    )
+}
+
+pub fn find_recovery_key_entry(
+    encrypted_data_entries: &[EncryptedDataEntry],
+) -> Option<&EncryptedDataEntry> {
+    encrypted_data_entries
+        .iter()
+        .find(|entry| entry.content_type == RECOVERY_KEY_CONTENT_TYPE)
+}
+
+pub fn find_recovery_code_entry(
+    encrypted_data_entries: &[EncryptedDataEntry],
+) -> Option<&EncryptedDataEntry> {
+    encrypted_data_entries
+        .iter()
+        .find(|entry| entry.content_type == RECOVERY_CODE_CONTENT_TYPE)
+}
+
+// Text the user is asked to print or store somewhere safe after registering
+pub fn generate_emergency_kit(email: &str, server: &str, recovery_code: &str) -> Zeroizing<String> {
+    Zeroizing::new(format!(
+        "Password Manager emergency kit\n\n\
+         Email: {}\n\
+         Server: {}\n\
+         Recovery key: {}\n\n\
+         Anyone with this recovery key can reset the master password of the account.\n\
+         Keep it offline and somewhere safe.\n",
+        email, server, recovery_code
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{generate_recovery_code, generate_vault_key, unwrap_vault_key};
+
+    #[test]
+    fn recovery_entries_give_back_the_vault_key_and_code() {
+        let vault_key = generate_vault_key();
+        let recovery_code = generate_recovery_code();
+        let entries = create_recovery_entries(&recovery_code, &vault_key).unwrap();
+
+        // Typed back in lower case with spaces
+        let typed_code = recovery_code.to_lowercase().replace('-', " ");
+        let recovery_keys = generate_recovery_keys(&typed_code).unwrap();
+
+        let recovery_key_entry = find_recovery_key_entry(&entries).unwrap();
+        let unwrapped_vault_key =
+            unwrap_vault_key(recovery_key_entry, &recovery_keys.vault_key_cipher).unwrap();
+        assert_eq!(unwrapped_vault_key.as_slice(), vault_key.as_slice());
+
+        let recovery_code_entry = find_recovery_code_entry(&entries).unwrap();
+        assert_eq!(
+            decrypt_recovery_code_entry(recovery_code_entry, &vault_key)
+                .unwrap()
+                .as_str(),
+            recovery_code.as_str()
+        );
+    }
+
+    #[test]
+    fn another_recovery_code_does_not_unwrap_the_vault_key() {
+        let vault_key = generate_vault_key();
+        let entries = create_recovery_entries(&generate_recovery_code(), &vault_key).unwrap();
+
+        let recovery_keys = generate_recovery_keys(&generate_recovery_code()).unwrap();
+        let recovery_key_entry = find_recovery_key_entry(&entries).unwrap();
+
+        assert!(matches!(
+            unwrap_vault_key(recovery_key_entry, &recovery_keys.vault_key_cipher),
+            Err(Error::Crypto(_))
+        ));
+    }
+}
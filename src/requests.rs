@@ -1,115 +1,195 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+
 use crate::constraints;
+use crate::error::Error;
 use crate::model::{
-    AddEncryptedDataEntryRequest, DeleteEncryptedDataEntryRequest, EncryptedDataEntry,
-    EncryptedDataEntryResponse, ErrorResponse, GetAllEncryptedDataEntriesResponse, LoginRequest,
-    RegisterRequest, SimpleResponse, UpdateEncryptedDataEntryRequest, UserResponse,
+    AddEncryptedDataEntryRequest, ChangePasswordRequest, DeleteEncryptedDataEntryRequest,
+    EncryptedDataEntry, EncryptedDataEntryChangesResponse, EncryptedDataEntryResponse,
+    ErrorResponse, GetAllEncryptedDataEntriesResponse, KdfParams, LoginRequest, PreloginRequest,
+    PreloginResponse, RecoverRequest, RegisterRequest, SimpleResponse,
+    UpdateEncryptedDataEntryRequest, UserResponse,
 };
+use crate::tls::find_certificate_error;
+
+// Requests that are safe to repeat are sent up to this many times, waiting twice as long
+// after every failure
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY_MS: u64 = 500;
+const RETRY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// The server answers a repeated write with the same key like the first one instead of
+// applying it twice
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+// Shared with a running operation, which stops at the next step that is safe to abandon
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    is_cancelled: Arc<AtomicBool>,
+}
 
-pub fn login_request(
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.is_cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+}
+
+// Fetches the key derivation settings of an account, needed before the password can be hashed
+pub fn prelogin_request(
+    email: &str,
+    client: &reqwest::blocking::Client,
+    base_url: &str,
+    cancellation: &Cancellation,
+) -> Result<PreloginResponse, Error> {
+    if !constraints::is_email_valid(email) {
+        return Err(Error::Validation("Invalid email".to_string()));
+    }
+
+    let request = PreloginRequest {
+        email: email.to_string(),
+    };
+
+    let full_url = format!("{}/prelogin", base_url);
+
+    // Only reads the settings, so it can be repeated
+    send_json_request_with_retry(|| client.post(&full_url).json(&request), cancellation)
+}
+
+// Accounts registered before the authentication hash was introduced
+// still authenticate with the raw master password
+pub fn legacy_login_request(
     email: &str,
     password: &str,
     client: &reqwest::blocking::Client,
     base_url: &str,
-) -> Result<SimpleResponse, String> {
+) -> Result<Option<SimpleResponse>, Error> {
     if (!constraints::is_email_valid(email)) || (!constraints::is_password_valid(password)) {
-        return Err("Invalid email or password".to_string());
+        return Err(Error::Validation("Invalid email or password".to_string()));
+    }
+
+    login_request(email, password, client, base_url)
+}
+
+// The master password is validated by the caller before it is hashed into auth_hash
+pub fn login_request(
+    email: &str,
+    auth_hash: &str,
+    client: &reqwest::blocking::Client,
+    base_url: &str,
+) -> Result<Option<SimpleResponse>, Error> {
+    if !constraints::is_email_valid(email) {
+        return Err(Error::Validation("Invalid email".to_string()));
     }
 
     let request = LoginRequest {
         email: email.to_string(),
-        password: password.to_string(),
+        password: auth_hash.to_string(),
     };
 
     let full_url = format!("{}/login", base_url);
 
-    let response = client.post(full_url).json(&request).send();
-
-    // TODO: Rework login response parsing
-    match response {
-        Ok(response) => match response.json::<serde_json::Value>() {
-            Ok(json_response) => {
-                let json_response_copy = json_response.clone();
-
-                match serde_json::from_value::<ErrorResponse>(json_response_copy) {
-                    Ok(error_response) => Err(error_response.message),
-                    Err(_) => match serde_json::from_value::<SimpleResponse>(json_response) {
-                        Ok(login_response) => Ok(login_response),
-                        Err(_) => Err("Error parsing response".to_string()),
-                    },
-                }
-            }
-            Err(_) => Err("Error parsing response".to_string()),
-        },
-        Err(_) => Err("Error sending request".to_string()),
-    }
+    send_json_request(client.post(full_url).json(&request))
 }
 
+// The master password is validated by the caller before it is hashed into auth_hash
 pub fn register_request(
     email: &str,
-    password: &str,
-    password2: &str,
+    auth_hash: &str,
+    kdf_params: &KdfParams,
+    recovery_hash: Option<&str>,
     client: &reqwest::blocking::Client,
     base_url: &str,
-) -> Result<UserResponse, String> {
-    if password != password2 {
-        return Err("Passwords do not match".to_string());
-    }
-
-    if (!constraints::is_email_valid(email)) || (!constraints::is_password_valid(password)) {
-        return Err("Invalid email or password".to_string());
+) -> Result<UserResponse, Error> {
+    if !constraints::is_email_valid(email) {
+        return Err(Error::Validation("Invalid email".to_string()));
     }
 
     let request = RegisterRequest {
         email: email.to_string(),
-        password: password.to_string(),
+        password: auth_hash.to_string(),
+        kdf: kdf_params.clone(),
+        recovery_hash: recovery_hash.map(|recovery_hash| recovery_hash.to_string()),
     };
 
     let full_url = format!("{}/register", base_url);
 
-    let response = client.post(full_url).json(&request).send();
+    send_json_request(client.post(full_url).json(&request))
+}
 
-    match response {
-        Ok(response) => match response.json::<serde_json::Value>() {
-            Ok(json_response) => {
-                let json_response_copy = json_response.clone();
+pub fn logout_request(client: &reqwest::blocking::Client, base_url: &str) -> Result<String, Error> {
+    let full_url = format!("{}/logout", base_url);
 
-                match serde_json::from_value::<UserResponse>(json_response_copy) {
-                    Ok(register_response) => Ok(register_response),
-                    Err(_) => match serde_json::from_value::<ErrorResponse>(json_response) {
-                        Ok(error_response) => Err(error_response.message),
-                        Err(_) => Err("Error parsing response".to_string()),
-                    },
-                }
-            }
-            Err(_) => Err("Error parsing response".to_string()),
-        },
-        Err(_) => Err("Error sending request".to_string()),
-    }
+    // Not cancellable, the session has to end even when the operation that started it did not
+    send_request_with_retry(|| client.get(&full_url), &Cancellation::default())
+        .map_err(|e| e.context("Logout failed"))
 }
 
-pub fn logout_request(
+pub fn change_password_request(
+    old_password: &str,
+    new_password: &str,
+    new_kdf_params: Option<&KdfParams>,
     client: &reqwest::blocking::Client,
     base_url: &str,
-) -> Result<String, String> {
-    let full_url = format!("{}/logout", base_url);
+) -> Result<Option<SimpleResponse>, Error> {
+    let request = ChangePasswordRequest {
+        old_password: old_password.to_string(),
+        new_password: new_password.to_string(),
+        kdf: new_kdf_params.cloned(),
+    };
 
-    let response = client.get(full_url).send();
+    let full_url = format!("{}/change_password", base_url);
 
-    match response {
-        Ok(response) => match response.text() {
-            Ok(logout_response) => Ok(logout_response),
-            Err(e) => Err(format!("Logout failed: {}", e)),
-        },
-        Err(e) => Err(format!("Logout failed: {}", e)),
+    send_json_request(client.post(full_url).json(&request))
+}
+
+// Replaces the master password of an account using the hash derived from its recovery code
+// The server starts a session on success, like a login
+pub fn recover_request(
+    email: &str,
+    recovery_hash: &str,
+    new_auth_hash: &str,
+    new_kdf_params: &KdfParams,
+    client: &reqwest::blocking::Client,
+    base_url: &str,
+) -> Result<Option<SimpleResponse>, Error> {
+    if !constraints::is_email_valid(email) {
+        return Err(Error::Validation("Invalid email".to_string()));
     }
+
+    let request = RecoverRequest {
+        email: email.to_string(),
+        recovery_hash: recovery_hash.to_string(),
+        new_password: new_auth_hash.to_string(),
+        kdf: new_kdf_params.clone(),
+    };
+
+    let full_url = format!("{}/recover", base_url);
+
+    send_json_request(client.post(full_url).json(&request))
 }
 
+// The idempotency key is sent with every attempt, a change sent again later passes the same one
 pub fn add_encrypted_data_entry_request(
-    data_entry: EncryptedDataEntry,
+    data_entry: &EncryptedDataEntry,
+    idempotency_key: &str,
     client: &reqwest::blocking::Client,
     base_url: &str,
-) -> Result<EncryptedDataEntryResponse, String> {
+    cancellation: &Cancellation,
+) -> Result<EncryptedDataEntryResponse, Error> {
     let request = AddEncryptedDataEntryRequest {
+        id: data_entry.id.clone(),
         name: data_entry.name.clone(),
         content: data_entry.content.clone(),
         nonce: data_entry.nonce.clone(),
@@ -118,126 +198,508 @@ pub fn add_encrypted_data_entry_request(
 
     let full_url = format!("{}/add_encrypted_data_entry", base_url);
 
-    let response = client.post(full_url).json(&request).send();
-
-    match response {
-        Ok(response) => match response.json::<serde_json::Value>() {
-            Ok(json_response) => {
-                let json_response_copy = json_response.clone();
-
-                match serde_json::from_value::<EncryptedDataEntryResponse>(json_response_copy) {
-                    Ok(add_response) => Ok(add_response),
-                    Err(_) => match serde_json::from_value::<ErrorResponse>(json_response) {
-                        Ok(error_response) => Err(error_response.message),
-                        Err(_) => Err("Error parsing response".to_string()),
-                    },
-                }
-            }
-            Err(_) => Err("Error parsing response".to_string()),
+    send_json_request_with_retry(
+        || {
+            client
+                .post(&full_url)
+                .header(IDEMPOTENCY_KEY_HEADER, idempotency_key)
+                .json(&request)
         },
-        Err(_) => Err("Error sending request".to_string()),
-    }
+        cancellation,
+    )
 }
 
+// The entry keeps its content type, and is renamed when old_name differs from its name
 pub fn update_encrypted_data_entry_request(
     old_name: &str,
-    new_name: &str,
-    new_data_entry: EncryptedDataEntry,
-    content_type: &str,
+    new_data_entry: &EncryptedDataEntry,
+    expected_revision: Option<u64>,
+    idempotency_key: &str,
     client: &reqwest::blocking::Client,
     base_url: &str,
-) -> Result<EncryptedDataEntryResponse, String> {
+    cancellation: &Cancellation,
+) -> Result<EncryptedDataEntryResponse, Error> {
     let request = UpdateEncryptedDataEntryRequest {
+        id: new_data_entry.id.clone(),
         old_name: old_name.to_string(),
-        new_name: new_name.to_string(),
+        new_name: new_data_entry.name.clone(),
         new_content: new_data_entry.content.clone(),
         new_nonce: new_data_entry.nonce.clone(),
-        content_type: content_type.to_string(),
+        content_type: new_data_entry.content_type.clone(),
+        expected_revision,
     };
 
     let full_url = format!("{}/update_encrypted_data_entry", base_url);
 
-    let response = client.post(full_url).json(&request).send();
-
-    match response {
-        Ok(response) => match response.json::<serde_json::Value>() {
-            Ok(json_response) => {
-                let json_response_copy = json_response.clone();
-
-                match serde_json::from_value::<EncryptedDataEntryResponse>(json_response_copy) {
-                    Ok(update_response) => Ok(update_response),
-                    Err(_) => match serde_json::from_value::<ErrorResponse>(json_response) {
-                        Ok(error_response) => Err(error_response.message),
-                        Err(_) => Err("Error parsing response".to_string()),
-                    },
-                }
-            }
-            Err(_) => Err("Error parsing response".to_string()),
+    send_json_request_with_retry(
+        || {
+            client
+                .post(&full_url)
+                .header(IDEMPOTENCY_KEY_HEADER, idempotency_key)
+                .json(&request)
         },
-        Err(_) => Err("Error sending request".to_string()),
-    }
+        cancellation,
+    )
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn delete_encrypted_data_entry_request(
+    id: &str,
     name: &str,
     content_type: &str,
+    expected_revision: Option<u64>,
+    idempotency_key: &str,
     client: &reqwest::blocking::Client,
     base_url: &str,
-) -> Result<SimpleResponse, String> {
+    cancellation: &Cancellation,
+) -> Result<Option<SimpleResponse>, Error> {
     let request = DeleteEncryptedDataEntryRequest {
+        id: id.to_string(),
         name: name.to_string(),
         content_type: content_type.to_string(),
+        expected_revision,
     };
 
     let full_url = format!("{}/delete_encrypted_data_entry", base_url);
 
-    let response = client.post(full_url).json(&request).send();
-
-    // TODO: Rework delete response parsing
-    match response {
-        Ok(response) => match response.json::<serde_json::Value>() {
-            Ok(json_response) => {
-                let json_response_copy = json_response.clone();
-
-                match serde_json::from_value::<ErrorResponse>(json_response_copy) {
-                    Ok(error_response) => Err(error_response.message),
-                    Err(_) => match serde_json::from_value::<SimpleResponse>(json_response) {
-                        Ok(delete_response) => Ok(delete_response),
-                        Err(_) => Err("Error parsing response".to_string()),
-                    },
-                }
-            }
-            Err(_) => Err("Error parsing response".to_string()),
+    send_json_request_with_retry(
+        || {
+            client
+                .post(&full_url)
+                .header(IDEMPOTENCY_KEY_HEADER, idempotency_key)
+                .json(&request)
         },
-        Err(_) => Err("Error sending request".to_string()),
-    }
+        cancellation,
+    )
 }
 
 pub fn get_all_encrypted_data_entries_request(
     client: &reqwest::blocking::Client,
     base_url: &str,
-) -> Result<GetAllEncryptedDataEntriesResponse, String> {
+    cancellation: &Cancellation,
+) -> Result<GetAllEncryptedDataEntriesResponse, Error> {
     let full_url = format!("{}/get_all_encrypted_data_entries", base_url);
 
-    let response = client.get(full_url).send();
-
-    match response {
-        Ok(response) => match response.json::<serde_json::Value>() {
-            Ok(json_response) => {
-                let json_response_copy = json_response.clone();
-
-                match serde_json::from_value::<GetAllEncryptedDataEntriesResponse>(
-                    json_response_copy,
-                ) {
-                    Ok(data_response) => Ok(data_response),
-                    Err(_) => match serde_json::from_value::<ErrorResponse>(json_response) {
-                        Ok(error_response) => Err(error_response.message),
-                        Err(_) => Err("Error parsing response".to_string()),
-                    },
+    send_json_request_with_retry(|| client.get(&full_url), cancellation)
+}
+
+// Only the entries written or deleted since the cursor, servers that no longer know the cursor
+// answer 410 and the whole vault has to be downloaded again
+pub fn get_encrypted_data_entry_changes_request(
+    cursor: u64,
+    client: &reqwest::blocking::Client,
+    base_url: &str,
+    cancellation: &Cancellation,
+) -> Result<EncryptedDataEntryChangesResponse, Error> {
+    let full_url = format!("{}/get_encrypted_data_entry_changes", base_url);
+
+    send_json_request_with_retry(
+        || client.get(&full_url).query(&[("cursor", cursor)]),
+        cancellation,
+    )
+}
+
+// Sends a request and returns the body of a successful response, whatever its format
+fn send_request(request: reqwest::blocking::RequestBuilder) -> Result<String, Error> {
+    let response = match request.send() {
+        Ok(response) => response,
+        Err(e) => {
+            if let Some(message) = find_certificate_error(&e) {
+                return Err(Error::Tls(message));
+            }
+
+            return Err(Error::Network(format!("Error sending request: {}", e)));
+        }
+    };
+
+    let status = response.status();
+
+    let body = match response.text() {
+        Ok(body) => body,
+        Err(e) => return Err(Error::Network(format!("Error reading response: {}", e))),
+    };
+
+    if !status.is_success() {
+        return Err(Error::from_server(status, error_message(status, &body)));
+    }
+
+    Ok(body)
+}
+
+// Builds the request again for every attempt, only failures that may be temporary are retried
+// and a cancelled operation stops waiting instead of sending the request again
+fn send_request_with_retry(
+    build_request: impl Fn() -> reqwest::blocking::RequestBuilder,
+    cancellation: &Cancellation,
+) -> Result<String, Error> {
+    let mut attempt = 1;
+
+    loop {
+        match send_request(build_request()) {
+            Err(e) if e.is_retryable() && attempt < MAX_ATTEMPTS => {
+                let delay = Duration::from_millis(RETRY_BASE_DELAY_MS << (attempt - 1));
+                println!("{}, retrying in {} ms", e, delay.as_millis());
+
+                wait_before_retry(delay, cancellation)?;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Sleeps in short steps so that a cancel does not sit out the whole delay
+fn wait_before_retry(delay: Duration, cancellation: &Cancellation) -> Result<(), Error> {
+    let deadline = Instant::now() + delay;
+
+    loop {
+        cancellation.check()?;
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+
+        thread::sleep(RETRY_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+// The status code decides between success and failure, the body is only parsed afterwards
+fn send_json_request<T: DeserializeOwned>(
+    request: reqwest::blocking::RequestBuilder,
+) -> Result<T, Error> {
+    parse_json_body(send_request(request)?)
+}
+
+fn send_json_request_with_retry<T: DeserializeOwned>(
+    build_request: impl Fn() -> reqwest::blocking::RequestBuilder,
+    cancellation: &Cancellation,
+) -> Result<T, Error> {
+    parse_json_body(send_request_with_retry(build_request, cancellation)?)
+}
+
+// An empty body, like the one of a 204, is read as null so that it parses into () or None and
+// only fails for responses that have to carry data
+fn parse_json_body<T: DeserializeOwned>(body: String) -> Result<T, Error> {
+    if body.trim().is_empty() {
+        return match serde_json::from_value::<T>(serde_json::Value::Null) {
+            Ok(response) => Ok(response),
+            Err(_) => Err(Error::Decode("Empty response".to_string())),
+        };
+    }
+
+    match serde_json::from_str::<T>(&body) {
+        Ok(response) => Ok(response),
+        Err(e) => Err(Error::Decode(format!("Error parsing response: {}", e))),
+    }
+}
+
+// Error bodies may be the usual JSON, plain text from a proxy or nothing at all
+fn error_message(status: reqwest::StatusCode, body: &str) -> String {
+    if let Ok(error_response) = serde_json::from_str::<ErrorResponse>(body) {
+        return error_response.message;
+    }
+
+    let body = body.trim();
+
+    if body.is_empty() || body.starts_with('<') {
+        return match status.canonical_reason() {
+            Some(reason) => format!("Server returned {} {}", status.as_u16(), reason),
+            None => format!("Server returned {}", status.as_u16()),
+        };
+    }
+
+    body.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    use super::*;
+
+    const EMAIL: &str = "user@example.com";
+    const AUTH_HASH: &str = "auth-hash";
+
+    fn response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    // Answers one connection per response in order and hands back the requests it received
+    fn serve(responses: Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                requests.push(read_request(&mut stream));
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+
+            requests
+        });
+
+        (base_url, handle)
+    }
+
+    fn read_request(stream: &mut impl Read) -> String {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+
+        loop {
+            let read = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&request).to_string();
+
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text
+                    .lines()
+                    .find_map(|line| {
+                        line.to_ascii_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|value| value.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+
+                if request.len() >= header_end + 4 + content_length {
+                    return text;
                 }
             }
-            Err(_) => Err("Error parsing response".to_string()),
-        },
-        Err(_) => Err("Error sending request".to_string()),
+
+            if read == 0 {
+                return String::from_utf8_lossy(&request).to_string();
+            }
+        }
+    }
+
+    fn client() -> reqwest::blocking::Client {
+        reqwest::blocking::Client::new()
+    }
+
+    #[test]
+    fn unauthorized_status_is_unauthorized() {
+        let (base_url, server) = serve(vec![response(
+            "401 Unauthorized",
+            r#"{"status":"error","message":"Session expired"}"#,
+        )]);
+
+        let result = login_request(EMAIL, AUTH_HASH, &client(), &base_url);
+        server.join().unwrap();
+
+        assert_eq!(
+            result.unwrap_err(),
+            Error::Unauthorized("Session expired".to_string())
+        );
+    }
+
+    #[test]
+    fn server_error_keeps_status() {
+        let (base_url, server) = serve(vec![response("500 Internal Server Error", "")]);
+
+        let result = login_request(EMAIL, AUTH_HASH, &client(), &base_url);
+        server.join().unwrap();
+
+        assert!(matches!(
+            result.unwrap_err(),
+            Error::Server { status: 500, .. }
+        ));
+    }
+
+    #[test]
+    fn malformed_body_is_decode_error() {
+        let (base_url, server) = serve(vec![response("200 OK", "not json")]);
+
+        let result =
+            get_all_encrypted_data_entries_request(&client(), &base_url, &Cancellation::default());
+        server.join().unwrap();
+
+        assert!(matches!(result.unwrap_err(), Error::Decode(_)));
+    }
+
+    #[test]
+    fn empty_body_is_no_response() {
+        let (base_url, server) =
+            serve(vec![response("204 No Content", ""), response("200 OK", "")]);
+
+        let deleted = delete_encrypted_data_entry_request(
+            "id",
+            "name",
+            "password",
+            None,
+            "key",
+            &client(),
+            &base_url,
+            &Cancellation::default(),
+        );
+        let logged_in = login_request(EMAIL, AUTH_HASH, &client(), &base_url);
+        server.join().unwrap();
+
+        assert!(deleted.unwrap().is_none());
+        assert!(logged_in.unwrap().is_none());
+    }
+
+    #[test]
+    fn empty_body_fails_where_data_is_needed() {
+        let (base_url, server) = serve(vec![response("200 OK", "")]);
+
+        let result =
+            get_all_encrypted_data_entries_request(&client(), &base_url, &Cancellation::default());
+        server.join().unwrap();
+
+        assert_eq!(
+            result.unwrap_err(),
+            Error::Decode("Empty response".to_string())
+        );
+    }
+
+    #[test]
+    fn refused_connection_is_network_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let result = login_request(EMAIL, AUTH_HASH, &client(), &base_url);
+
+        assert!(matches!(result.unwrap_err(), Error::Network(_)));
+    }
+
+    fn entry() -> EncryptedDataEntry {
+        EncryptedDataEntry {
+            id: "id".to_string(),
+            name: "name".to_string(),
+            content: vec![1],
+            nonce: vec![2],
+            content_type: "password".to_string(),
+            revision: None,
+        }
+    }
+
+    fn idempotency_key(request: &str) -> Option<String> {
+        request.lines().find_map(|line| {
+            line.to_ascii_lowercase()
+                .strip_prefix("idempotency-key:")
+                .map(|value| value.trim().to_string())
+        })
+    }
+
+    #[test]
+    fn retried_write_keeps_its_idempotency_key() {
+        let stored = r#"{"status":"ok","data":{"id":"id","name":"name","content":[1],"nonce":[2],"content_type":"password","revision":4}}"#;
+        let (base_url, server) = serve(vec![
+            response("503 Service Unavailable", ""),
+            response("502 Bad Gateway", ""),
+            response("200 OK", stored),
+        ]);
+
+        let result = add_encrypted_data_entry_request(
+            &entry(),
+            "key",
+            &client(),
+            &base_url,
+            &Cancellation::default(),
+        );
+        let requests = server.join().unwrap();
+
+        assert_eq!(result.unwrap().data.revision, Some(4));
+        assert_eq!(requests.len(), 3);
+        assert!(requests
+            .iter()
+            .all(|request| idempotency_key(request) == Some("key".to_string())));
+    }
+
+    #[test]
+    fn separate_writes_send_their_own_idempotency_keys() {
+        let (base_url, server) = serve(vec![
+            response("204 No Content", ""),
+            response("204 No Content", ""),
+        ]);
+
+        for key in ["first", "second"] {
+            delete_encrypted_data_entry_request(
+                "id",
+                "name",
+                "password",
+                None,
+                key,
+                &client(),
+                &base_url,
+                &Cancellation::default(),
+            )
+            .unwrap();
+        }
+        let requests = server.join().unwrap();
+
+        assert_eq!(idempotency_key(&requests[0]), Some("first".to_string()));
+        assert_eq!(idempotency_key(&requests[1]), Some("second".to_string()));
+    }
+
+    #[test]
+    fn retries_stop_after_the_last_attempt() {
+        let (base_url, server) = serve(vec![
+            response("503 Service Unavailable", ""),
+            response("503 Service Unavailable", ""),
+            response("503 Service Unavailable", ""),
+        ]);
+
+        let result =
+            get_all_encrypted_data_entries_request(&client(), &base_url, &Cancellation::default());
+        let requests = server.join().unwrap();
+
+        assert!(matches!(
+            result.unwrap_err(),
+            Error::Server { status: 503, .. }
+        ));
+        assert_eq!(requests.len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn rejected_requests_are_not_retried() {
+        let (base_url, server) = serve(vec![response(
+            "409 Conflict",
+            r#"{"status":"error","message":"Revision mismatch"}"#,
+        )]);
+
+        let result = update_encrypted_data_entry_request(
+            "name",
+            &entry(),
+            Some(3),
+            "key",
+            &client(),
+            &base_url,
+            &Cancellation::default(),
+        );
+        let requests = server.join().unwrap();
+
+        assert!(result.unwrap_err().is_conflict());
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn cancellation_stops_the_retries() {
+        let (base_url, server) = serve(vec![response("503 Service Unavailable", "")]);
+
+        let cancellation = Cancellation::default();
+        let canceller = cancellation.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+
+        let result = get_all_encrypted_data_entries_request(&client(), &base_url, &cancellation);
+        let requests = server.join().unwrap();
+
+        assert_eq!(result.unwrap_err(), Error::Cancelled);
+        assert_eq!(requests.len(), 1);
     }
 }
@@ -0,0 +1,30 @@
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+// Only the expiry is read, checking the signature is up to the server
+#[derive(Deserialize)]
+struct SessionClaims {
+    exp: i64,
+}
+
+// The server keeps the session in a JWT cookie, so the first cookie that decodes as one is used
+pub fn find_session_expiry(cookie_header: &str) -> Option<DateTime<Utc>> {
+    cookie_header.split(';').find_map(|cookie| {
+        let (_, value) = cookie.trim().split_once('=')?;
+        decode_session_expiry(value)
+    })
+}
+
+fn decode_session_expiry(token: &str) -> Option<DateTime<Utc>> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+
+    let token_data =
+        decode::<SessionClaims>(token, &DecodingKey::from_secret(&[]), &validation).ok()?;
+
+    DateTime::from_timestamp(token_data.claims.exp, 0)
+}
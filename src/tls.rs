@@ -0,0 +1,409 @@
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::blocking::ClientBuilder;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, OtherError, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+use crate::config::Profile;
+use crate::error::Error;
+
+const PUBLIC_KEY_PIN_PREFIX: &str = "sha256/";
+
+// Either the SHA-256 of the whole certificate, written in hex like most tools print fingerprints,
+// or of its public key, written as sha256/BASE64 like curl's --pinnedpubkey
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificatePin {
+    Certificate([u8; 32]),
+    PublicKey([u8; 32]),
+}
+
+impl CertificatePin {
+    pub fn parse(pin: &str) -> Result<CertificatePin, Error> {
+        let pin = pin.trim();
+
+        if let Some(public_key_hash) = pin.strip_prefix(PUBLIC_KEY_PIN_PREFIX) {
+            let public_key_hash = match STANDARD.decode(public_key_hash.trim_start_matches('/')) {
+                Ok(public_key_hash) => public_key_hash,
+                Err(e) => return Err(Error::Validation(format!("Invalid public key pin: {}", e))),
+            };
+
+            return match public_key_hash.try_into() {
+                Ok(public_key_hash) => Ok(CertificatePin::PublicKey(public_key_hash)),
+                Err(_) => Err(Error::Validation(
+                    "Public key pin must be a SHA-256 hash".to_string(),
+                )),
+            };
+        }
+
+        let hex_digits: String = pin.chars().filter(|c| *c != ':').collect();
+
+        if hex_digits.len() != 64 || !hex_digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::Validation(
+                "Certificate pin must be a SHA-256 fingerprint or sha256/BASE64".to_string(),
+            ));
+        }
+
+        let mut certificate_hash = [0u8; 32];
+        for (index, byte) in certificate_hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex_digits[index * 2..index * 2 + 2], 16).unwrap();
+        }
+
+        Ok(CertificatePin::Certificate(certificate_hash))
+    }
+
+    fn matches(&self, certificate: &CertificateDer<'_>) -> bool {
+        match self {
+            CertificatePin::Certificate(certificate_hash) => {
+                Sha256::digest(certificate.as_ref()).as_slice() == certificate_hash
+            }
+            CertificatePin::PublicKey(public_key_hash) => {
+                match webpki::EndEntityCert::try_from(certificate) {
+                    Ok(end_entity) => {
+                        Sha256::digest(end_entity.subject_public_key_info().as_ref()).as_slice()
+                            == public_key_hash
+                    }
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+}
+
+// Carried inside the rustls error so a failed pin can be told apart from other TLS failures
+#[derive(Debug)]
+struct PinMismatch;
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The server certificate does not match the pin of this profile"
+        )
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+// The pin replaces the certificate authorities, which lets a self-signed certificate be used,
+// the handshake signatures are still checked against the pinned certificate
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    pin: CertificatePin,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pin.matches(end_entity) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+            OtherError(Arc::new(PinMismatch)),
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// Adds the CA bundle and the pin of a profile to the client
+pub fn configure_tls(builder: ClientBuilder, profile: &Profile) -> Result<ClientBuilder, Error> {
+    let mut builder = builder;
+
+    if let Some(ca_bundle) = &profile.ca_bundle {
+        let pem_bundle = match fs::read(ca_bundle) {
+            Ok(pem_bundle) => pem_bundle,
+            Err(e) => {
+                return Err(Error::Storage(format!(
+                    "Failed to read CA bundle {}: {}",
+                    ca_bundle.display(),
+                    e
+                )))
+            }
+        };
+
+        let certificates = match reqwest::Certificate::from_pem_bundle(&pem_bundle) {
+            Ok(certificates) => certificates,
+            Err(e) => {
+                return Err(Error::Validation(format!(
+                    "Invalid CA bundle {}: {}",
+                    ca_bundle.display(),
+                    e
+                )))
+            }
+        };
+
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let Some(pin) = &profile.certificate_pin {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let verifier = PinnedCertificateVerifier {
+            pin: CertificatePin::parse(pin)?,
+            provider: provider.clone(),
+        };
+
+        let tls_config = match rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+        {
+            Ok(tls_config) => tls_config
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth(),
+            Err(e) => return Err(Error::Tls(format!("Failed to configure TLS: {}", e))),
+        };
+
+        builder = builder.use_preconfigured_tls(tls_config);
+    }
+
+    Ok(builder)
+}
+
+// Plain http is only safe when the traffic never leaves the machine
+pub fn is_local_url(url: &reqwest::Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return false,
+    };
+
+    match host.parse::<IpAddr>() {
+        Ok(address) => address.is_loopback(),
+        Err(_) => host == "localhost",
+    }
+}
+
+// Finds a certificate rejected by rustls, or a handshake refused by the system TLS library used
+// for profiles without a pin, among the causes of a failed request
+pub fn find_certificate_error(error: &(dyn std::error::Error + 'static)) -> Option<String> {
+    let mut source = Some(error);
+
+    while let Some(error) = source {
+        if let Some(rustls::Error::InvalidCertificate(certificate_error)) =
+            error.downcast_ref::<rustls::Error>()
+        {
+            return Some(match certificate_error {
+                CertificateError::Other(other_error) => other_error.to_string(),
+                certificate_error => format!("{:?}", certificate_error),
+            });
+        }
+
+        // OpenSSL on Linux, its message names the reason, like an unknown issuer
+        if let Some(native_tls_error) = error.downcast_ref::<native_tls::Error>() {
+            return Some(native_tls_error.to_string());
+        }
+
+        // Errors from the TLS stream are wrapped in an io::Error, which hides them from source()
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            if let Some(inner_error) = io_error.get_ref() {
+                if let Some(message) = find_certificate_error(inner_error) {
+                    return Some(message);
+                }
+            }
+        }
+
+        source = error.source();
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use rustls::pki_types::PrivateKeyDer;
+
+    use super::*;
+    use crate::requests::{get_all_encrypted_data_entries_request, Cancellation};
+
+    // Self-signed for localhost and 127.0.0.1, generated once with openssl
+    const CERTIFICATE: &[u8] = include_bytes!("../tests/data/localhost.crt.der");
+    const PRIVATE_KEY: &[u8] = include_bytes!("../tests/data/localhost.key.der");
+
+    // Serves one HTTPS request with an empty vault, a refused handshake just ends the connection
+    fn serve_tls() -> String {
+        let tls_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(CERTIFICATE.to_vec())],
+            PrivateKeyDer::try_from(PRIVATE_KEY.to_vec()).unwrap(),
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("https://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = rustls::ServerConnection::new(Arc::new(tls_config)).unwrap();
+            let mut stream = rustls::StreamOwned::new(connection, stream);
+
+            let mut buffer = [0; 4096];
+            if stream.read(&mut buffer).is_err() {
+                return;
+            }
+
+            let body = r#"{"status":"ok","data":[]}"#;
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.flush();
+        });
+
+        base_url
+    }
+
+    fn client(certificate_pin: Option<String>) -> reqwest::blocking::Client {
+        let profile = Profile {
+            certificate_pin,
+            ..Profile::default()
+        };
+
+        configure_tls(reqwest::blocking::Client::builder(), &profile)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    fn certificate_fingerprint() -> String {
+        Sha256::digest(CERTIFICATE)
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    #[test]
+    fn parses_both_pin_formats() {
+        assert!(matches!(
+            CertificatePin::parse(&certificate_fingerprint()),
+            Ok(CertificatePin::Certificate(_))
+        ));
+        assert!(matches!(
+            CertificatePin::parse(&format!("sha256/{}", STANDARD.encode([7u8; 32]))),
+            Ok(CertificatePin::PublicKey(_))
+        ));
+        assert!(CertificatePin::parse("sha256/AAAA").is_err());
+        assert!(CertificatePin::parse("not a pin").is_err());
+    }
+
+    #[test]
+    fn matching_pin_trusts_self_signed_certificate() {
+        let base_url = serve_tls();
+
+        let response = get_all_encrypted_data_entries_request(
+            &client(Some(certificate_fingerprint())),
+            &base_url,
+            &Cancellation::default(),
+        );
+
+        assert!(response.unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn matching_public_key_pin_trusts_self_signed_certificate() {
+        let certificate = CertificateDer::from(CERTIFICATE);
+        let end_entity = webpki::EndEntityCert::try_from(&certificate).unwrap();
+        let pin = format!(
+            "sha256/{}",
+            STANDARD.encode(Sha256::digest(
+                end_entity.subject_public_key_info().as_ref()
+            ))
+        );
+
+        let base_url = serve_tls();
+
+        let response = get_all_encrypted_data_entries_request(
+            &client(Some(pin)),
+            &base_url,
+            &Cancellation::default(),
+        );
+
+        assert!(response.is_ok());
+    }
+
+    #[test]
+    fn pin_mismatch_is_tls_error() {
+        let base_url = serve_tls();
+        let other_pin = format!("sha256/{}", STANDARD.encode([7u8; 32]));
+
+        let response = get_all_encrypted_data_entries_request(
+            &client(Some(other_pin)),
+            &base_url,
+            &Cancellation::default(),
+        );
+
+        assert_eq!(response.unwrap_err(), Error::Tls(PinMismatch.to_string()));
+    }
+
+    #[test]
+    fn self_signed_certificate_without_pin_is_tls_error() {
+        let base_url = serve_tls();
+
+        let response = get_all_encrypted_data_entries_request(
+            &client(None),
+            &base_url,
+            &Cancellation::default(),
+        );
+
+        assert!(matches!(response.unwrap_err(), Error::Tls(_)));
+    }
+}
@@ -1,17 +1,18 @@
+use crate::error::Error;
 use crate::model::TOTPEntry;
 use totp_rs::{Algorithm, Secret, TOTP};
 
-pub fn generate_totp_token(totp_entry: TOTPEntry) -> Result<String, String> {
+pub fn generate_totp_token(totp_entry: &TOTPEntry) -> Result<String, Error> {
     let secret = match Secret::Encoded(totp_entry.secret.clone()).to_bytes() {
         Ok(secret) => secret,
-        Err(e) => return Err(format!("Failed to decode secret: {}", e)),
+        Err(e) => return Err(Error::Totp(format!("Failed to decode secret: {}", e))),
     };
 
     let algorithm = match totp_entry.algorithm.as_str() {
         "SHA1" => Algorithm::SHA1,
         "SHA256" => Algorithm::SHA256,
         "SHA512" => Algorithm::SHA512,
-        _ => return Err("Invalid algorithm".to_string()),
+        _ => return Err(Error::Totp("Invalid algorithm".to_string())),
     };
 
     let totp = match TOTP::new(
@@ -22,11 +23,16 @@ pub fn generate_totp_token(totp_entry: TOTPEntry) -> Result<String, String> {
         secret,
     ) {
         Ok(totp) => totp,
-        Err(e) => return Err(format!("Failed to create TOTP instance: {}", e)),
+        Err(e) => {
+            return Err(Error::Totp(format!(
+                "Failed to create TOTP instance: {}",
+                e
+            )))
+        }
     };
 
     match totp.generate_current() {
         Ok(token) => Ok(token),
-        Err(e) => Err(format!("Failed to generate TOTP token: {}", e)),
+        Err(e) => Err(Error::Totp(format!("Failed to generate TOTP token: {}", e))),
     }
 }
@@ -0,0 +1,305 @@
+use serde::{de::DeserializeOwned, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::encryption::{decrypt_data_entry, encrypt_data_entry};
+use crate::error::Error;
+use crate::model::{Card, EncryptedDataEntry, Note, Password, TOTPEntry, VaultCipher};
+
+// What every entry type has in common, a new type implements this, gets a VaultEntry variant
+// and a line in ENTRY_HANDLERS
+pub trait EntryData: Clone + Serialize + DeserializeOwned {
+    // Also the name of the vault subkey the type is encrypted with
+    const CONTENT_TYPE: &'static str;
+    // Used in messages
+    const LABEL: &'static str;
+
+    fn id(&self) -> &str;
+    fn id_mut(&mut self) -> &mut String;
+    fn name(&self) -> &str;
+
+    // Shown under the name in the entry list
+    fn subtext(&self) -> &str {
+        ""
+    }
+
+    fn describe(&self) -> Vec<EntryField>;
+
+    fn into_vault_entry(self) -> VaultEntry;
+}
+
+// A decrypted field for showing an entry outside of the main window, secrets are flagged
+// so they can be hidden
+#[derive(Debug, Zeroize, ZeroizeOnDrop)]
+pub struct EntryField {
+    #[zeroize(skip)]
+    pub label: &'static str,
+    pub value: String,
+    #[zeroize(skip)]
+    pub is_secret: bool,
+}
+
+fn entry_field(label: &'static str, value: &str, is_secret: bool) -> EntryField {
+    EntryField {
+        label,
+        value: value.to_string(),
+        is_secret,
+    }
+}
+
+impl EntryData for Password {
+    const CONTENT_TYPE: &'static str = "password";
+    const LABEL: &'static str = "password";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn id_mut(&mut self) -> &mut String {
+        &mut self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn subtext(&self) -> &str {
+        &self.username
+    }
+
+    fn describe(&self) -> Vec<EntryField> {
+        vec![
+            entry_field("Name", &self.name, false),
+            entry_field("Username", &self.username, false),
+            entry_field("Password", &self.password, true),
+            entry_field("URL", &self.url, false),
+            entry_field("Expiration Date", &self.expiration_date, false),
+        ]
+    }
+
+    fn into_vault_entry(self) -> VaultEntry {
+        VaultEntry::Password(self)
+    }
+}
+
+impl EntryData for Note {
+    const CONTENT_TYPE: &'static str = "note";
+    const LABEL: &'static str = "note";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn id_mut(&mut self) -> &mut String {
+        &mut self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn describe(&self) -> Vec<EntryField> {
+        vec![
+            entry_field("Name", &self.name, false),
+            entry_field("Content", &self.content, false),
+        ]
+    }
+
+    fn into_vault_entry(self) -> VaultEntry {
+        VaultEntry::Note(self)
+    }
+}
+
+impl EntryData for Card {
+    const CONTENT_TYPE: &'static str = "card";
+    const LABEL: &'static str = "card";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn id_mut(&mut self) -> &mut String {
+        &mut self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn describe(&self) -> Vec<EntryField> {
+        vec![
+            entry_field("Name", &self.name, false),
+            entry_field("Cardholder Name", &self.cardholder_name, false),
+            entry_field("Card Number", &self.card_number, true),
+            entry_field("Security Code", &self.security_code, true),
+            entry_field("Expiration Date", &self.expiration_date, false),
+        ]
+    }
+
+    fn into_vault_entry(self) -> VaultEntry {
+        VaultEntry::Card(self)
+    }
+}
+
+impl EntryData for TOTPEntry {
+    const CONTENT_TYPE: &'static str = "totp_entry";
+    const LABEL: &'static str = "TOTP entry";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn id_mut(&mut self) -> &mut String {
+        &mut self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn describe(&self) -> Vec<EntryField> {
+        vec![
+            entry_field("Name", &self.name, false),
+            entry_field("Secret", &self.secret, true),
+            entry_field("Algorithm", &self.algorithm, false),
+            entry_field("Digits", &self.digits.to_string(), false),
+            entry_field("Period", &self.period.to_string(), false),
+        ]
+    }
+
+    fn into_vault_entry(self) -> VaultEntry {
+        VaultEntry::TOTP(self)
+    }
+}
+
+// Any decrypted entry, the contents are wiped on drop by the entry types themselves
+#[derive(Clone, Debug)]
+pub enum VaultEntry {
+    Password(Password),
+    Note(Note),
+    Card(Card),
+    TOTP(TOTPEntry),
+}
+
+// Runs the same code on whichever entry type the variant holds
+macro_rules! with_entry_data {
+    ($vault_entry:expr, $entry:ident => $body:expr) => {
+        match $vault_entry {
+            VaultEntry::Password($entry) => $body,
+            VaultEntry::Note($entry) => $body,
+            VaultEntry::Card($entry) => $body,
+            VaultEntry::TOTP($entry) => $body,
+        }
+    };
+}
+
+impl VaultEntry {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            VaultEntry::Password(_) => Password::CONTENT_TYPE,
+            VaultEntry::Note(_) => Note::CONTENT_TYPE,
+            VaultEntry::Card(_) => Card::CONTENT_TYPE,
+            VaultEntry::TOTP(_) => TOTPEntry::CONTENT_TYPE,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        with_entry_data!(self, entry => entry.id())
+    }
+
+    pub fn id_mut(&mut self) -> &mut String {
+        with_entry_data!(self, entry => entry.id_mut())
+    }
+
+    pub fn name(&self) -> &str {
+        with_entry_data!(self, entry => entry.name())
+    }
+
+    pub fn subtext(&self) -> &str {
+        with_entry_data!(self, entry => entry.subtext())
+    }
+
+    pub fn describe(&self) -> Vec<EntryField> {
+        with_entry_data!(self, entry => entry.describe())
+    }
+
+    pub fn encrypt(&self, cipher: &VaultCipher) -> Result<EncryptedDataEntry, Error> {
+        with_entry_data!(self, entry => encrypt_entry_data(entry, cipher))
+    }
+}
+
+// How entries of a content type are read back, every type the vault holds is listed here
+pub struct EntryHandler {
+    pub content_type: &'static str,
+    pub decrypt: fn(&EncryptedDataEntry, &VaultCipher) -> Result<VaultEntry, Error>,
+}
+
+const fn entry_handler<T: EntryData>() -> EntryHandler {
+    EntryHandler {
+        content_type: T::CONTENT_TYPE,
+        decrypt: decrypt_into_vault_entry::<T>,
+    }
+}
+
+pub const ENTRY_HANDLERS: &[EntryHandler] = &[
+    entry_handler::<Password>(),
+    entry_handler::<Note>(),
+    entry_handler::<Card>(),
+    entry_handler::<TOTPEntry>(),
+];
+
+pub fn find_entry_handler(content_type: &str) -> Option<&'static EntryHandler> {
+    ENTRY_HANDLERS
+        .iter()
+        .find(|handler| handler.content_type == content_type)
+}
+
+pub fn encrypt_entry_data<T: EntryData>(
+    entry: &T,
+    cipher: &VaultCipher,
+) -> Result<EncryptedDataEntry, Error> {
+    let serialized_data = match serde_json::to_string(entry) {
+        Ok(data) => data,
+        Err(e) => {
+            return Err(Error::Decode(format!(
+                "Failed to serialize {}: {}",
+                T::LABEL,
+                e
+            )))
+        }
+    };
+
+    let (content, nonce) =
+        encrypt_data_entry(&serialized_data, entry.id(), T::CONTENT_TYPE, cipher)?;
+
+    Ok(EncryptedDataEntry {
+        id: entry.id().to_string(),
+        name: entry.name().to_string(),
+        content,
+        nonce,
+        content_type: T::CONTENT_TYPE.to_string(),
+        revision: None,
+    })
+}
+
+pub fn decrypt_entry_data<T: EntryData>(
+    encrypted_data_entry: &EncryptedDataEntry,
+    cipher: &VaultCipher,
+) -> Result<T, Error> {
+    let data = decrypt_data_entry(encrypted_data_entry, cipher)?;
+
+    match serde_json::from_str::<T>(&data) {
+        Ok(entry) => Ok(entry),
+        Err(e) => Err(Error::Decode(format!(
+            "Failed to deserialize {}: {}",
+            T::LABEL,
+            e
+        ))),
+    }
+}
+
+fn decrypt_into_vault_entry<T: EntryData>(
+    encrypted_data_entry: &EncryptedDataEntry,
+    cipher: &VaultCipher,
+) -> Result<VaultEntry, Error> {
+    decrypt_entry_data::<T>(encrypted_data_entry, cipher).map(EntryData::into_vault_entry)
+}